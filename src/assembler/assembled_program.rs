@@ -0,0 +1,341 @@
+use crate::assembler::assembler_errors::{AssemblerError, AssemblerErrorKind, AssemblerWarning};
+use crate::assembler::listing::Listing;
+use crate::assembler::optimizer::OptimizationReport;
+use crate::assembler::preprocessor::SourceLine;
+use crate::assembler::{Assembler, SourceLocation, Symbol, SymbolTable, SymbolType};
+use crate::symbol_map::{SymbolEntry, SymbolMap};
+use std::path::Path;
+
+/// Maps a range of the program back to the source line it was assembled from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineEntry {
+    pub offset: u32,
+    pub len: u32,
+    pub location: SourceLocation,
+}
+
+/// Everything produced by assembling a program.
+///
+/// Code is placed at `code_offset` and data right after it, at the next
+/// multiple of 4. All offsets and symbol values are absolute addresses in
+/// the [`image`](AssembledProgram::image).
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembledProgram {
+    pub code: Vec<u8>,
+    pub code_offset: u32,
+    pub data: Vec<u8>,
+    pub data_offset: u32,
+    /// Where execution starts: the `.entry` operand, or the start of the code.
+    pub entry: u32,
+    pub symbols: SymbolTable,
+    /// One entry per instruction or directive that emitted bytes, in address
+    /// order.
+    pub lines: Vec<LineEntry>,
+    pub warnings: Vec<AssemblerWarning>,
+    /// What the optimizer changed, if the assembler was asked to optimize.
+    pub optimizations: Option<OptimizationReport>,
+    /// Set if the assembler was asked to generate a listing.
+    pub listing: Option<Listing>,
+    /// Symbols used before they were defined. Only an [`AssemblySession`]
+    /// leaves symbols unresolved; the bytes using them are placeholders until
+    /// a later piece defines them.
+    pub unresolved: Vec<String>,
+}
+
+impl AssembledProgram {
+    /// The code followed by the data, padded so the data starts at
+    /// `data_offset`. The image itself starts at `code_offset`.
+    pub fn image(&self) -> Vec<u8> {
+        let mut image = self.code.clone();
+        if !self.data.is_empty() {
+            image.resize((self.data_offset - self.code_offset) as usize, 0);
+            image.extend_from_slice(&self.data);
+        }
+        image
+    }
+
+    /// Exports the symbols so they outlive the program.
+    pub fn symbol_map(&self) -> SymbolMap {
+        let mut map = SymbolMap::new();
+        for symbol in self.symbols.iter() {
+            map.push(SymbolEntry {
+                name: symbol.name().to_string(),
+                value: symbol.value(),
+                section: symbol.section(),
+                kind: *symbol.symbol_type(),
+            });
+        }
+        map
+    }
+
+    /// The source line that produced the byte at `address`.
+    pub fn location_of(&self, address: u32) -> Option<&SourceLocation> {
+        self.lines
+            .iter()
+            .find(|line| line.offset <= address && address < line.offset + line.len)
+            .map(|line| &line.location)
+    }
+}
+
+/// Assembles a program piece by piece, as typed into the REPL. Each piece is
+/// placed after the previous ones and may use the labels and constants they
+/// defined. Pieces added with [`AssemblySession::add`] may also use labels
+/// defined by later pieces; they are assembled again in place once those
+/// labels are known.
+#[derive(Debug, Clone, Default)]
+pub struct AssemblySession {
+    assembler: Assembler,
+    image: Vec<u8>,
+    symbols: SymbolTable,
+    pending: Vec<PendingPiece>,
+}
+
+/// A piece assembled with placeholders for the symbols in `unresolved`.
+#[derive(Debug, Clone)]
+struct PendingPiece {
+    lines: Vec<SourceLine>,
+    base: u32,
+    len: usize,
+    unresolved: Vec<String>,
+    /// Symbols the piece defines itself, left out when it is assembled again.
+    defined: Vec<String>,
+}
+
+impl AssemblySession {
+    pub fn new(assembler: Assembler) -> AssemblySession {
+        AssemblySession {
+            assembler,
+            image: vec![],
+            symbols: SymbolTable::new(),
+            pending: vec![],
+        }
+    }
+
+    /// Continues an already assembled program.
+    pub fn from_program(assembler: Assembler, program: &AssembledProgram) -> AssemblySession {
+        let mut image = vec![0; program.code_offset as usize];
+        image.append(&mut program.image());
+        AssemblySession::from_image(assembler, image, program.symbols.clone())
+    }
+
+    /// Continues a program image, such as one loaded from a
+    /// [`ProgramFile`](crate::program_file::ProgramFile).
+    pub fn from_image(assembler: Assembler, image: Vec<u8>, symbols: SymbolTable) -> AssemblySession {
+        AssemblySession {
+            assembler,
+            image,
+            symbols,
+            pending: vec![],
+        }
+    }
+
+    pub fn assembler(&self) -> &Assembler {
+        &self.assembler
+    }
+
+    /// The whole program assembled so far.
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Every symbol used but not defined yet.
+    pub fn unresolved(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = vec![];
+        for name in self.pending.iter().flat_map(|piece| &piece.unresolved) {
+            if !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Symbols still waiting to be defined for the piece containing
+    /// `address`, if that piece was assembled with placeholders.
+    pub fn unresolved_at(&self, address: usize) -> Option<&[String]> {
+        self.pending
+            .iter()
+            .find(|piece| (piece.base as usize..piece.base as usize + piece.len).contains(&address))
+            .map(|piece| piece.unresolved.as_slice())
+    }
+
+    /// Assembles `source` at the end of the program. On success the new piece
+    /// is appended and returned; on failure the session is left unchanged.
+    ///
+    /// Labels that are not defined yet get a placeholder value, and are listed
+    /// in [`AssembledProgram::unresolved`]. The piece is assembled again, and
+    /// its bytes in the image replaced, by the first `add` or `add_file` after
+    /// which all of them are defined.
+    pub fn add(&mut self, source: &str) -> Result<AssembledProgram, Vec<AssemblerError>> {
+        let lines = self.assembler.preprocessor().process(source)?;
+        self.add_lines(&lines, true)
+    }
+
+    /// Assembles a file at the end of the program. Unlike [`add`](Self::add),
+    /// every symbol the file uses must be defined by the time it ends.
+    pub fn add_file(&mut self, path: &Path) -> Result<AssembledProgram, Vec<AssemblerError>> {
+        let lines = self.assembler.preprocessor().process_file(path)?;
+        self.add_lines(&lines, false)
+    }
+
+    fn add_lines(&mut self, lines: &[SourceLine], forward_references: bool) -> Result<AssembledProgram, Vec<AssemblerError>> {
+        let base = self.image.len() as u32;
+        let program = match self.assembler.assemble_lines(lines, base, self.symbols.clone()) {
+            Ok(program) => program,
+            Err(errors) if forward_references => self.assemble_with_placeholders(lines, base, errors)?,
+            Err(errors) => return Err(errors),
+        };
+        let mut image = self.image.clone();
+        image.append(&mut program.image());
+        let mut pending = vec![];
+        for piece in &self.pending {
+            if piece.unresolved.iter().all(|name| program.symbols.symbol(name).is_some()) {
+                let bytes = self.reassemble(piece, &program.symbols)?;
+                image[piece.base as usize..piece.base as usize + piece.len].copy_from_slice(&bytes);
+            } else {
+                pending.push(piece.clone());
+            }
+        }
+        if !program.unresolved.is_empty() {
+            pending.push(PendingPiece {
+                lines: lines.to_vec(),
+                base,
+                len: image.len() - base as usize,
+                unresolved: program.unresolved.clone(),
+                defined: program
+                    .symbols
+                    .iter()
+                    .filter(|symbol| self.symbols.symbol(symbol.name()).is_none())
+                    .map(|symbol| symbol.name().to_string())
+                    .collect(),
+            });
+        }
+        self.image = image;
+        self.symbols = program.symbols.clone();
+        self.pending = pending;
+        Ok(program)
+    }
+
+    /// Assembles lines that failed only because of undefined symbols, with
+    /// each of those defined as a label at 0. Lines that define constants
+    /// are not, since the constants could depend on the placeholders.
+    fn assemble_with_placeholders(
+        &self,
+        lines: &[SourceLine],
+        base: u32,
+        errors: Vec<AssemblerError>,
+    ) -> Result<AssembledProgram, Vec<AssemblerError>> {
+        let mut unresolved: Vec<String> = vec![];
+        for error in &errors {
+            match &error.kind {
+                AssemblerErrorKind::UndefinedSymbol { name } if !unresolved.contains(name) => unresolved.push(name.clone()),
+                AssemblerErrorKind::UndefinedSymbol { .. } => {}
+                _ => return Err(errors),
+            }
+        }
+        let mut symbols = self.symbols.clone();
+        for name in &unresolved {
+            symbols.add_symbol(Symbol::with_value(name.clone(), SymbolType::Label, 0));
+        }
+        let mut program = match self.assembler.assemble_lines(lines, base, symbols) {
+            Ok(program) => program,
+            Err(_) => return Err(errors),
+        };
+        let defines_constants = program.symbols.iter().any(|symbol| {
+            *symbol.symbol_type() != SymbolType::Label
+                && self.symbols.symbol(symbol.name()).is_none()
+                && !self.assembler.defines.iter().any(|(name, _)| name == symbol.name())
+        });
+        if defines_constants {
+            return Err(errors);
+        }
+        for name in &unresolved {
+            program.symbols.remove_symbol(name);
+        }
+        program.unresolved = unresolved;
+        Ok(program)
+    }
+
+    /// The bytes of a pending piece now that `symbols` defines everything it
+    /// uses.
+    fn reassemble(&self, piece: &PendingPiece, symbols: &SymbolTable) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let mut symbols = symbols.clone();
+        for name in &piece.defined {
+            symbols.remove_symbol(name);
+        }
+        let bytes = self.assembler.assemble_lines(&piece.lines, piece.base, symbols)?.image();
+        if bytes.len() != piece.len {
+            let location = piece.lines.first().map(|line| line.location.clone()).unwrap_or_default();
+            let name = piece.unresolved[0].clone();
+            return Err(vec![AssemblerError::new(location, AssemblerErrorKind::ForwardReferenceChangesSize { name })]);
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assembler_errors::AssemblerErrorKind;
+
+    #[test]
+    fn test_assembled_program() {
+        let source = ".data\nmessage: .byte 'h', 'i'\n.code\nload $1 #message\nstart: hlt\n.entry @start";
+        let program = Assembler::new().assemble(source).unwrap();
+        assert_eq!(program.code, vec![1, 1, 0, 8, 0, 0, 0, 0]);
+        assert_eq!((program.data_offset, program.data.as_slice()), (8, &b"hi"[..]));
+        assert_eq!(program.entry, 4);
+        assert_eq!(program.image(), vec![1, 1, 0, 8, 0, 0, 0, 0, b'h', b'i']);
+        assert_eq!(program.location_of(5).map(|location| location.line), Some(5));
+        assert_eq!(program.location_of(9).map(|location| location.line), Some(2));
+        assert_eq!(program.location_of(10), None);
+        let map = program.symbol_map();
+        assert_eq!(map.describe_address(9), "message+1");
+        assert_eq!(program.warnings, vec![]);
+    }
+
+    #[test]
+    fn test_warns_about_scratch_register() {
+        let program = Assembler::new().assemble("nop\nload $31 #1").unwrap();
+        assert_eq!(program.warnings.len(), 1);
+        assert_eq!(
+            program.warnings[0].to_string(),
+            "line 2, column 1: warning: $31 is used here but overwritten by pseudo-instructions"
+        );
+        let errors = Assembler::new().assemble(".entry #0\n.entry #4").unwrap_err();
+        assert_eq!(errors[0].kind, AssemblerErrorKind::DuplicateEntry);
+    }
+
+    #[test]
+    fn test_session_resolves_earlier_labels() {
+        let mut session = AssemblySession::default();
+        session.add("start: load $1 #1\n.equ STEP 2").unwrap();
+        let piece = session.add("loop: load $2 #STEP\ndjeq @start").unwrap();
+        assert_eq!(piece.code_offset, 4);
+        assert_eq!(piece.code, vec![1, 2, 0, 2, 19, 0, 0, 0]);
+        assert_eq!(session.symbols().symbol_value("loop"), Some(4));
+        assert_eq!(session.image().len(), 12);
+        let errors = session.add("start: hlt").unwrap_err();
+        assert_eq!(errors[0].to_string(), "line 1, column 1: symbol `start` is already defined");
+        assert_eq!(session.image().len(), 12);
+    }
+
+    #[test]
+    fn test_session_fixes_up_forward_references() {
+        let mut session = AssemblySession::default();
+        let piece = session.add("start: eq $1 $1\ndjeq @done").unwrap();
+        assert_eq!(piece.unresolved, ["done"]);
+        assert_eq!(session.unresolved_at(4), Some(&["done".to_string()][..]));
+        session.add("inc $1").unwrap();
+        assert_eq!(session.image()[4..8], [19, 0, 0, 0]);
+        let piece = session.add("done: hlt").unwrap();
+        assert!(piece.unresolved.is_empty());
+        assert_eq!(session.image()[4..8], [19, 0, 12, 0]);
+        assert_eq!(session.unresolved_at(4), None);
+        assert!(session.add(".equ LATER @never + 1").is_err());
+        assert!(session.add_file(Path::new("no/such/file")).is_err());
+    }
+}
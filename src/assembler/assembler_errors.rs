@@ -0,0 +1,201 @@
+use crate::assembler::pseudo_instructions::SCRATCH_REGISTER;
+use crate::assembler::SourceLocation;
+use std::fmt;
+
+/// Codes carried by `nom::ErrorKind::Custom` when a parser recognised a token
+/// but rejected its contents.
+pub const INVALID_REGISTER: u32 = 1;
+pub const INVALID_NUMBER: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssemblerErrorKind {
+    UnexpectedInput { found: String },
+    InvalidRegister { register: String },
+    InvalidNumber { literal: String },
+    ValueOutOfRange { value: i64, min: i64, max: i64 },
+    UndefinedSymbol { name: String },
+    SymbolAlreadyDefined { name: String },
+    DivisionByZero,
+    ArithmeticOverflow,
+    UnknownDirective { name: String },
+    UnknownMnemonic { name: String },
+    UnterminatedMacro { name: String },
+    UnexpectedEndm,
+    NestedMacroDefinition,
+    InvalidMacroDefinition { definition: String },
+    MacroArgumentCount { name: String, expected: usize, found: usize },
+    MacroRecursionLimit { name: String },
+    MacroExpansionLimit { name: String },
+    UndefinedMacroParameter { name: String },
+    InvalidInclude { argument: String },
+    IncludeNotFound { path: String },
+    IncludeCycle { path: String },
+    IncludeNotAllowed { path: String },
+    UnreadableFile { path: String, reason: String },
+    UnexpectedEndNamespace,
+    UnexpectedConditional { directive: String },
+    ConditionalAfterElse { directive: String },
+    UnterminatedConditional,
+    InvalidCondition { condition: String },
+    InvalidPseudoOperands { name: String, usage: &'static str },
+    ScratchRegisterOperand { name: String },
+    InvalidOperands { name: String, usage: &'static str },
+    InvalidDirectiveOperands { name: String, usage: &'static str },
+    InvalidAlignment { value: i64 },
+    DuplicateEntry,
+    ForwardReferenceChangesSize { name: String },
+    UnterminatedNamespace { name: String },
+    NonOpcodeInOpcodeField,
+    NonOperandInOperandField,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblerError {
+    pub location: SourceLocation,
+    pub kind: AssemblerErrorKind,
+}
+
+impl AssemblerError {
+    pub fn new(location: SourceLocation, kind: AssemblerErrorKind) -> AssemblerError {
+        AssemblerError { location, kind }
+    }
+}
+
+impl fmt::Display for AssemblerErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerErrorKind::UnexpectedInput { found } => write!(f, "unexpected input `{found}`"),
+            AssemblerErrorKind::InvalidRegister { register } => {
+                write!(f, "invalid register `${register}`, expected $0 to $31")
+            }
+            AssemblerErrorKind::InvalidNumber { literal } => {
+                write!(f, "invalid number or expression `{literal}`")
+            }
+            AssemblerErrorKind::ValueOutOfRange { value, min, max } => {
+                write!(f, "value {value} does not fit in operand (expected {min} to {max})")
+            }
+            AssemblerErrorKind::UndefinedSymbol { name } => write!(f, "undefined symbol `{name}`"),
+            AssemblerErrorKind::SymbolAlreadyDefined { name } => write!(f, "symbol `{name}` is already defined"),
+            AssemblerErrorKind::DivisionByZero => write!(f, "division by zero in expression"),
+            AssemblerErrorKind::ArithmeticOverflow => write!(f, "arithmetic overflow in expression"),
+            AssemblerErrorKind::UnknownDirective { name } => write!(f, "unknown directive `.{name}`"),
+            AssemblerErrorKind::UnknownMnemonic { name } => write!(f, "unknown instruction `{name}`"),
+            AssemblerErrorKind::UnterminatedMacro { name } => write!(f, "macro `{name}` is missing `.endm`"),
+            AssemblerErrorKind::UnexpectedEndm => write!(f, "`.endm` without a matching `.macro`"),
+            AssemblerErrorKind::NestedMacroDefinition => write!(f, "macros cannot be defined inside a macro"),
+            AssemblerErrorKind::InvalidMacroDefinition { definition } => {
+                write!(f, "invalid macro definition `{definition}`")
+            }
+            AssemblerErrorKind::MacroArgumentCount { name, expected, found } => {
+                write!(f, "macro `{name}` takes {expected} argument(s) but {found} were given")
+            }
+            AssemblerErrorKind::MacroRecursionLimit { name } => {
+                write!(f, "macro `{name}` expands recursively too deep")
+            }
+            AssemblerErrorKind::MacroExpansionLimit { name } => {
+                write!(f, "too many macro expansions, gave up at `{name}`")
+            }
+            AssemblerErrorKind::UndefinedMacroParameter { name } => {
+                write!(f, "unknown macro parameter `\\{name}`")
+            }
+            AssemblerErrorKind::InvalidInclude { argument } => {
+                write!(f, "expected a quoted path after `.include`, found `{argument}`")
+            }
+            AssemblerErrorKind::IncludeNotFound { path } => write!(f, "cannot find included file `{path}`"),
+            AssemblerErrorKind::IncludeCycle { path } => write!(f, "`{path}` includes itself"),
+            AssemblerErrorKind::IncludeNotAllowed { path } => write!(f, "cannot include `{path}`, files are not allowed here"),
+            AssemblerErrorKind::UnreadableFile { path, reason } => write!(f, "cannot read `{path}`: {reason}"),
+            AssemblerErrorKind::InvalidPseudoOperands { name, usage } => {
+                write!(f, "invalid operands for `{name}`, expected `{usage}`")
+            }
+            AssemblerErrorKind::ScratchRegisterOperand { name } => {
+                write!(f, "`{name}` cannot read ${SCRATCH_REGISTER}, it overwrites it first")
+            }
+            AssemblerErrorKind::InvalidOperands { name, usage } => {
+                write!(f, "invalid operands for `{name}`, expected `{usage}`")
+            }
+            AssemblerErrorKind::InvalidDirectiveOperands { name, usage } => {
+                write!(f, "invalid operands for `.{name}`, expected `{usage}`")
+            }
+            AssemblerErrorKind::InvalidAlignment { value } => {
+                write!(f, "alignment {value} is not a power of two between 1 and 65536")
+            }
+            AssemblerErrorKind::DuplicateEntry => write!(f, "`.entry` is given more than once"),
+            AssemblerErrorKind::ForwardReferenceChangesSize { name } => {
+                write!(f, "`{name}` is used before it is defined, and its value changes the size of the code")
+            }
+            AssemblerErrorKind::UnexpectedConditional { directive } => {
+                write!(f, "`.{directive}` without a matching `.if`")
+            }
+            AssemblerErrorKind::ConditionalAfterElse { directive } => {
+                write!(f, "`.{directive}` after `.else`")
+            }
+            AssemblerErrorKind::UnterminatedConditional => write!(f, "`.if` is missing `.endif`"),
+            AssemblerErrorKind::InvalidCondition { condition } => write!(f, "invalid condition `{condition}`"),
+            AssemblerErrorKind::UnexpectedEndNamespace => {
+                write!(f, "`.endnamespace` without a matching `.namespace`")
+            }
+            AssemblerErrorKind::UnterminatedNamespace { name } => {
+                write!(f, "namespace `{name}` is missing `.endnamespace`")
+            }
+            AssemblerErrorKind::NonOpcodeInOpcodeField => write!(f, "non-opcode found in opcode field"),
+            AssemblerErrorKind::NonOperandInOperandField => write!(f, "non-operand found in operand field"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssemblerWarningKind {
+    /// The program uses the register pseudo-instructions overwrite.
+    ScratchRegisterUsed,
+}
+
+/// Something suspicious that does not stop the program from assembling.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssemblerWarning {
+    pub location: SourceLocation,
+    pub kind: AssemblerWarningKind,
+}
+
+impl AssemblerWarning {
+    pub fn new(location: SourceLocation, kind: AssemblerWarningKind) -> AssemblerWarning {
+        AssemblerWarning { location, kind }
+    }
+}
+
+impl fmt::Display for AssemblerWarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerWarningKind::ScratchRegisterUsed => write!(
+                f,
+                "${SCRATCH_REGISTER} is used here but overwritten by pseudo-instructions"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for AssemblerWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: warning: {}", self.location, self.kind)
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_error() {
+        let error = AssemblerError::new(
+            SourceLocation::new(3, 9),
+            AssemblerErrorKind::InvalidRegister { register: "300".to_string() },
+        );
+        assert_eq!(error.to_string(), "line 3, column 9: invalid register `$300`, expected $0 to $31");
+    }
+}
@@ -1,49 +1,36 @@
-use crate::assembler::instruction_parser::AssemblerInstruction;
-use crate::assembler::operand_parser::operand;
-use crate::assembler::label_parser::label_declaration;
+use crate::assembler::operand_parser::{integer_value, label_usage};
 use crate::assembler::Token;
-use nom::alpha1;
 use nom::types::CompleteStr;
+use nom::space0;
 
-named!(directive_declaration<CompleteStr, Token>,
-    do_parse!(
-        tag!(".") >>
-        name: alpha1 >>
-        (
-            Token::Directive {name: name.to_string()}
-        )
-    )
-);
+/// Directives that emit bytes into the program.
+pub const DATA_DIRECTIVES: [&str; 5] = ["byte", "half", "word", "space", "align"];
 
-named!(directive_combined<CompleteStr, AssemblerInstruction>,
-    ws!(
-        do_parse!(
-            label: opt!(label_declaration) >>
-            name: directive_declaration >>
-            operand1: opt!(operand) >>
-            operand2: opt!(operand) >>
-            operand3: opt!(operand) >>
-            (
-                AssemblerInstruction {
-                    opcode: None,
-                    directive: Some(name),
-                    label,
-                    operand1,
-                    operand2,
-                    operand3
-                }
-            )
-        )
-    )
-);
+/// Every directive handled after preprocessing, including the data directives.
+pub const DIRECTIVES: [&str; 12] = [
+    "equ", "set", "namespace", "endnamespace", "code", "data", "entry", "byte", "half", "word", "space", "align",
+];
 
-named!(pub directive<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        ins: alt!(
-            directive_combined
-        ) >>
-        (
-            ins
-        )
+/// Directives the preprocessor handles. All but `.include` open or close a
+/// block of lines.
+pub const PREPROCESSOR_DIRECTIVES: [&str; 9] = ["include", "macro", "endm", "if", "ifdef", "ifndef", "elif", "else", "endif"];
+
+named!(pub(crate) data_value<CompleteStr, Token>,
+    alt!(
+        label_usage |
+        preceded!(opt!(terminated!(tag!("#"), space0)), integer_value)
     )
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_data_value() {
+        let label = Token::LabelUsage { name: "start".to_string() };
+        assert_eq!(data_value(CompleteStr("@start")), Ok((CompleteStr(""), label)));
+        assert_eq!(data_value(CompleteStr("#2")), Ok((CompleteStr(""), Token::Number { value: 2 })));
+        assert_eq!(data_value(CompleteStr("'a' + 1")), Ok((CompleteStr(""), Token::Number { value: 98 })));
+    }
+}
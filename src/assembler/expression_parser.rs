@@ -0,0 +1,365 @@
+use crate::assembler::assembler_errors::AssemblerErrorKind;
+use crate::assembler::SymbolTable;
+use nom::types::CompleteStr;
+use nom::{anychar, space0, ErrorKind, IResult};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+/// A compile-time expression, evaluated against the symbol table once every
+/// symbol it refers to is known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(i64),
+    Symbol(String),
+    Unary(UnaryOperator, Box<Expression>),
+    Binary(Box<Expression>, BinaryOperator, Box<Expression>),
+}
+
+impl Expression {
+    /// Calls `f` with every symbol name the expression refers to, allowing
+    /// names to be rewritten in place.
+    pub fn for_each_symbol_mut(&mut self, f: &mut dyn FnMut(&mut String)) {
+        match self {
+            Expression::Number(_) => {}
+            Expression::Symbol(name) => f(name),
+            Expression::Unary(_, operand) => operand.for_each_symbol_mut(f),
+            Expression::Binary(lhs, _, rhs) => {
+                lhs.for_each_symbol_mut(f);
+                rhs.for_each_symbol_mut(f);
+            }
+        }
+    }
+
+    pub fn evaluate(&self, symbols: &SymbolTable) -> Result<i64, AssemblerErrorKind> {
+        match self {
+            Expression::Number(value) => Ok(*value),
+            Expression::Symbol(name) => symbols
+                .symbol_value(name)
+                .ok_or_else(|| AssemblerErrorKind::UndefinedSymbol { name: name.clone() }),
+            Expression::Unary(operator, operand) => {
+                let value = operand.evaluate(symbols)?;
+                match operator {
+                    UnaryOperator::Negate => value.checked_neg().ok_or(AssemblerErrorKind::ArithmeticOverflow),
+                    UnaryOperator::Not => Ok(!value),
+                }
+            }
+            Expression::Binary(lhs, operator, rhs) => {
+                let lhs = lhs.evaluate(symbols)?;
+                let rhs = rhs.evaluate(symbols)?;
+                let result = match operator {
+                    BinaryOperator::Add => lhs.checked_add(rhs),
+                    BinaryOperator::Sub => lhs.checked_sub(rhs),
+                    BinaryOperator::Mul => lhs.checked_mul(rhs),
+                    BinaryOperator::Div | BinaryOperator::Rem if rhs == 0 => {
+                        return Err(AssemblerErrorKind::DivisionByZero)
+                    }
+                    BinaryOperator::Div => lhs.checked_div(rhs),
+                    BinaryOperator::Rem => lhs.checked_rem(rhs),
+                    BinaryOperator::And => Some(lhs & rhs),
+                    BinaryOperator::Or => Some(lhs | rhs),
+                    BinaryOperator::Xor => Some(lhs ^ rhs),
+                    BinaryOperator::Shl => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)),
+                    BinaryOperator::Shr => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)),
+                };
+                result.ok_or(AssemblerErrorKind::ArithmeticOverflow)
+            }
+        }
+    }
+
+    /// Returns the value of the expression if it does not refer to any symbol.
+    pub fn constant_value(&self) -> Option<i64> {
+        self.evaluate(&SymbolTable::new()).ok()
+    }
+}
+
+impl BinaryOperator {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Sub => "-",
+            BinaryOperator::Mul => "*",
+            BinaryOperator::Div => "/",
+            BinaryOperator::Rem => "%",
+            BinaryOperator::And => "&",
+            BinaryOperator::Or => "|",
+            BinaryOperator::Xor => "^",
+            BinaryOperator::Shl => "<<",
+            BinaryOperator::Shr => ">>",
+        }
+    }
+}
+
+/// Writes the expression back as source. Nested operations are always
+/// parenthesised, so the output parses to the same tree.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn operand(f: &mut fmt::Formatter, expression: &Expression) -> fmt::Result {
+            match expression {
+                Expression::Binary(..) => write!(f, "({expression})"),
+                _ => write!(f, "{expression}"),
+            }
+        }
+        match self {
+            Expression::Number(value) => write!(f, "{value}"),
+            Expression::Symbol(name) => write!(f, "{name}"),
+            Expression::Unary(operator, expression) => {
+                write!(f, "{}", if *operator == UnaryOperator::Negate { "-" } else { "~" })?;
+                operand(f, expression)
+            }
+            Expression::Binary(lhs, operator, rhs) => {
+                operand(f, lhs)?;
+                write!(f, " {} ", operator.symbol())?;
+                operand(f, rhs)
+            }
+        }
+    }
+}
+
+/// Parses the digits of a number in the given radix. Underscores may be used
+/// to separate digits, but not at the start or end of the number.
+fn parse_digits(digits: CompleteStr, radix: u32) -> Result<i64, ()> {
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return Err(());
+    }
+    i64::from_str_radix(&digits.replace('_', ""), radix).map_err(|_| ())
+}
+
+fn digits(input: CompleteStr, radix: u32) -> IResult<CompleteStr, i64> {
+    map_res!(
+        input,
+        take_while1!(|c: char| c.is_digit(radix) || c == '_'),
+        |digits| parse_digits(digits, radix)
+    )
+}
+
+fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '\'' | '"' => Some(c),
+        _ => None,
+    }
+}
+
+named!(number_literal<CompleteStr, i64>,
+    alt!(
+        preceded!(tag_no_case!("0x"), call!(digits, 16)) |
+        preceded!(tag_no_case!("0b"), call!(digits, 2)) |
+        preceded!(tag_no_case!("0o"), call!(digits, 8)) |
+        call!(digits, 10)
+    )
+);
+
+named!(character_literal<CompleteStr, i64>,
+    delimited!(
+        char!('\''),
+        map!(
+            alt!(
+                preceded!(char!('\\'), map_opt!(anychar, unescape)) |
+                none_of!("\\'")
+            ),
+            |c| c as i64
+        ),
+        char!('\'')
+    )
+);
+
+/// A symbol name: a letter, underscore or dot followed by letters, digits,
+/// underscores or dots. A leading dot names a label local to the enclosing
+/// global label, inner dots separate namespaces and scopes.
+pub fn identifier(input: CompleteStr) -> IResult<CompleteStr, CompleteStr> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+    match input.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+            let end = input.find(|c: char| !is_name_char(c)).unwrap_or(input.len());
+            if &input[..end] == "." {
+                return Err(nom::Err::Error(error_position!(input, ErrorKind::AlphaNumeric)));
+            }
+            Ok((CompleteStr(&input[end..]), CompleteStr(&input[..end])))
+        }
+        _ => Err(nom::Err::Error(error_position!(input, ErrorKind::AlphaNumeric))),
+    }
+}
+
+named!(primary<CompleteStr, Expression>,
+    alt!(
+        map!(number_literal, Expression::Number) |
+        map!(character_literal, Expression::Number) |
+        map!(identifier, |name| Expression::Symbol(name.to_string())) |
+        delimited!(
+            terminated!(char!('('), space0),
+            expression,
+            preceded!(space0, char!(')'))
+        )
+    )
+);
+
+named!(unary<CompleteStr, Expression>,
+    alt!(
+        map!(
+            pair!(
+                terminated!(
+                    alt!(
+                        value!(UnaryOperator::Negate, char!('-')) |
+                        value!(UnaryOperator::Not, char!('~'))
+                    ),
+                    space0
+                ),
+                unary
+            ),
+            |(operator, operand)| match (operator, operand) {
+                (UnaryOperator::Negate, Expression::Number(value)) => Expression::Number(-value),
+                (operator, operand) => Expression::Unary(operator, Box::new(operand)),
+            }
+        ) |
+        primary
+    )
+);
+
+/// Parses a left-associative chain of `operand`s joined by `operators`.
+/// Spaces and tabs are allowed around operators, but never line breaks. An
+/// operator that is not followed by an operand ends the chain before it.
+fn binary_chain<'a>(
+    input: CompleteStr<'a>,
+    operand: fn(CompleteStr<'a>) -> IResult<CompleteStr<'a>, Expression>,
+    operators: &[(&str, BinaryOperator)],
+) -> IResult<CompleteStr<'a>, Expression> {
+    let (mut rest, mut result) = operand(input)?;
+    'chain: loop {
+        let (after_space, _) = space0(rest)?;
+        for (symbol, operator) in operators {
+            if let Some(after_operator) = after_space.strip_prefix(symbol) {
+                let (after_operator, _) = space0(CompleteStr(after_operator))?;
+                if let Ok((remaining, rhs)) = operand(after_operator) {
+                    result = Expression::Binary(Box::new(result), *operator, Box::new(rhs));
+                    rest = remaining;
+                    continue 'chain;
+                }
+                break 'chain;
+            }
+        }
+        break;
+    }
+    Ok((rest, result))
+}
+
+fn multiplicative(input: CompleteStr) -> IResult<CompleteStr, Expression> {
+    binary_chain(
+        input,
+        unary,
+        &[("*", BinaryOperator::Mul), ("/", BinaryOperator::Div), ("%", BinaryOperator::Rem)],
+    )
+}
+
+fn additive(input: CompleteStr) -> IResult<CompleteStr, Expression> {
+    binary_chain(input, multiplicative, &[("+", BinaryOperator::Add), ("-", BinaryOperator::Sub)])
+}
+
+fn shift(input: CompleteStr) -> IResult<CompleteStr, Expression> {
+    binary_chain(input, additive, &[("<<", BinaryOperator::Shl), (">>", BinaryOperator::Shr)])
+}
+
+fn bitwise_and(input: CompleteStr) -> IResult<CompleteStr, Expression> {
+    binary_chain(input, shift, &[("&", BinaryOperator::And)])
+}
+
+fn bitwise_xor(input: CompleteStr) -> IResult<CompleteStr, Expression> {
+    binary_chain(input, bitwise_and, &[("^", BinaryOperator::Xor)])
+}
+
+/// Parses an expression. Operators follow C precedence, from loosest to
+/// tightest: `|`, `^`, `&`, `<<` `>>`, `+` `-`, `*` `/` `%`, unary `-` `~`.
+pub fn expression(input: CompleteStr) -> IResult<CompleteStr, Expression> {
+    binary_chain(input, bitwise_xor, &[("|", BinaryOperator::Or)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Symbol, SymbolType};
+
+    fn parse(input: &str) -> Expression {
+        let (rest, expression) = expression(CompleteStr(input)).unwrap();
+        assert_eq!(rest, CompleteStr(""), "{input}");
+        expression
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let expression = parse("1 + 2 * 3");
+        assert_eq!(
+            expression,
+            Expression::Binary(
+                Box::new(Expression::Number(1)),
+                BinaryOperator::Add,
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Number(2)),
+                    BinaryOperator::Mul,
+                    Box::new(Expression::Number(3))
+                ))
+            )
+        );
+        assert_eq!(parse("(1 + 2) * 3").constant_value(), Some(9));
+        assert_eq!(parse("10 - 4 - 3").constant_value(), Some(3));
+        assert_eq!(parse("1 << 4 | 0x3 & ~1").constant_value(), Some(18));
+        assert_eq!(parse("-(2 + 3) % 3").constant_value(), Some(-2));
+        assert_eq!(parse("0b1010 ^ 'A'").constant_value(), Some(75));
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        for source in ["1 + (2 * 3)", "(1 + 2) * 3", "-(a - b) << 2", "~FLAGS & 0xff"] {
+            let expression = parse(source);
+            assert_eq!(parse(&expression.to_string()), expression);
+        }
+        assert_eq!(parse("1+2*3").to_string(), "1 + (2 * 3)");
+    }
+
+    #[test]
+    fn test_parse_stops_at_line_end() {
+        let result = expression(CompleteStr("BUF_SIZE + 1\nload"));
+        let (rest, _) = result.unwrap();
+        assert_eq!(rest, CompleteStr("\nload"));
+        let result = expression(CompleteStr("4 $1"));
+        let (rest, _) = result.unwrap();
+        assert_eq!(rest, CompleteStr(" $1"));
+    }
+
+    #[test]
+    fn test_evaluate_symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("table".to_string(), SymbolType::Label, 12));
+        symbols.add_symbol(Symbol::with_value("BUF_SIZE".to_string(), SymbolType::Constant, 16));
+        assert_eq!(parse("BUF_SIZE * 4 + 1").evaluate(&symbols), Ok(65));
+        assert_eq!(parse("table+8").evaluate(&symbols), Ok(20));
+        assert_eq!(
+            parse("missing - 1").evaluate(&symbols),
+            Err(AssemblerErrorKind::UndefinedSymbol {
+                name: "missing".to_string()
+            })
+        );
+        assert_eq!(parse("1 / (BUF_SIZE - 16)").evaluate(&symbols), Err(AssemblerErrorKind::DivisionByZero));
+        assert_eq!(parse("1 << 64").evaluate(&symbols), Err(AssemblerErrorKind::ArithmeticOverflow));
+    }
+}
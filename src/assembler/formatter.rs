@@ -0,0 +1,193 @@
+use crate::assembler::instruction_parser::AssemblerInstruction;
+use crate::assembler::pseudo_instructions::is_pseudo_instruction;
+use crate::assembler::syntax::{Span, StatementNode, SyntaxKind, SyntaxLine, SyntaxTree};
+use crate::assembler::SourceLocation;
+use crate::instruction::Opcode;
+use nom::types::CompleteStr;
+
+/// Column instructions start at when no label is wider.
+const MIN_INSTRUCTION_COLUMN: usize = 4;
+
+/// A source line split into the parts the formatter lays out.
+struct Line<'a> {
+    label: Option<&'a str>,
+    body: String,
+    comment: Option<&'a str>,
+    indented: bool,
+}
+
+/// Rewrites source in the canonical layout:
+///
+/// * labels start the line and instructions start at the same column
+///   throughout the file, just past the widest label sharing a line with an
+///   instruction;
+/// * mnemonics and directive names are lowercase;
+/// * operands are separated by one space, and list items by `, `;
+/// * `;` comments are kept, trailing ones two spaces after the code and
+///   comment-only lines at column 0 or, if indented, at the instruction column;
+/// * runs of blank lines are collapsed and the file ends with one newline.
+///
+/// Only whitespace and letter case change. Lines that parse as instructions
+/// are reformatted only if they still parse to the same instructions; lines
+/// the parser does not know, such as macro calls and preprocessor
+/// directives, get their spacing normalized the same way.
+pub fn format_source(source: &str) -> String {
+    let tree = SyntaxTree::parse(source);
+    let lines: Vec<Line> = tree.lines().iter().map(|line| split_line(&tree, line)).collect();
+    let column = lines
+        .iter()
+        .filter(|line| !line.body.is_empty())
+        .filter_map(|line| line.label.map(|label| label.len() + 2))
+        .fold(MIN_INSTRUCTION_COLUMN, usize::max);
+    let mut output = String::new();
+    let mut blank = false;
+    for line in &lines {
+        let mut text = match (line.label, line.body.is_empty()) {
+            (Some(label), true) => format!("{label}:"),
+            (Some(label), false) => format!("{:<column$}{}", format!("{label}:"), line.body),
+            (None, false) => format!("{:column$}{}", "", line.body),
+            (None, true) if line.comment.is_some() && line.indented => " ".repeat(column),
+            (None, true) => String::new(),
+        };
+        if let Some(comment) = line.comment {
+            if !text.trim().is_empty() {
+                text.push_str("  ");
+            }
+            text.push_str(comment.trim_end());
+        }
+        if text.is_empty() {
+            blank = !output.is_empty();
+            continue;
+        }
+        if blank {
+            output.push('\n');
+            blank = false;
+        }
+        output.push_str(&text);
+        output.push('\n');
+    }
+    output
+}
+
+/// Whether `source` is already in the layout [`format_source`] produces.
+pub fn is_formatted(source: &str) -> bool {
+    format_source(source) == source
+}
+
+fn split_line<'a>(tree: &'a SyntaxTree, line: &SyntaxLine) -> Line<'a> {
+    let tokens = &tree.tokens()[line.tokens.clone()];
+    let comment = tokens
+        .iter()
+        .find(|token| token.kind == SyntaxKind::Comment)
+        .map(|token| tree.text(token.span));
+    let indented = tokens.first().is_some_and(|token| token.kind == SyntaxKind::Whitespace);
+    let label = line.label.as_ref().map(|label| tree.text(label.name));
+    let body = match &line.statement {
+        Some(statement) => {
+            let body = normalize(tree, statement);
+            let formatted = match label {
+                Some(label) => format!("{label}: {body}"),
+                None => body.clone(),
+            };
+            let original = line.code().and_then(|code| parsed(tree.text(code)));
+            match original.is_some() && parsed(&formatted) != original {
+                true => tree.text(statement.span).to_string(),
+                false => body,
+            }
+        }
+        None => String::new(),
+    };
+    Line {
+        label,
+        body,
+        comment,
+        indented,
+    }
+}
+
+/// The instructions `code` parses to, without their locations.
+fn parsed(code: &str) -> Option<Vec<AssemblerInstruction>> {
+    let program = SyntaxTree::parse(code).to_program(&SourceLocation::default()).ok()?;
+    Some(
+        program
+            .instructions
+            .into_iter()
+            .map(|instruction| AssemblerInstruction {
+                location: SourceLocation::default(),
+                ..instruction
+            })
+            .collect(),
+    )
+}
+
+/// Lowercases the mnemonic or directive name and normalizes the spacing of
+/// the operands. Text inside string and character literals is left alone.
+fn normalize(tree: &SyntaxTree, statement: &StatementNode) -> String {
+    let name = tree.text(statement.name);
+    let is_mnemonic = Opcode::from(CompleteStr(name)) != Opcode::ILLEGAL || is_pseudo_instruction(name);
+    let mut result = match name.starts_with('.') || is_mnemonic {
+        true => name.to_lowercase(),
+        false => name.to_string(),
+    };
+    let mut previous: Option<Span> = None;
+    for &operand in &statement.operands {
+        match previous {
+            Some(previous) if tree.text(Span::new(previous.end, operand.start)).contains(',') => result.push_str(", "),
+            _ => result.push(' '),
+        }
+        for token in tree.tokens().iter().filter(|token| operand.contains(token.span.start)) {
+            match token.kind {
+                SyntaxKind::Whitespace => result.push(' '),
+                _ => result.push_str(tree.text(token.span)),
+            }
+        }
+        previous = Some(operand);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_source() {
+        let source = "; counts down\n\
+                      .EQU  COUNT 3\n\
+                      start:  LOAD $1   #COUNT   ; the counter\n\
+                      \n\
+                      \n\
+                      \t; loop body\n\
+                      countdown: dec $1\n\
+                      \tjmp @countdown\n\
+                      table: .byte 1 ,2,';'\n\
+                      LOAD  $1 #'\\\\' ; hi\n\
+                      done:\n\
+                      \n";
+        let formatted = format_source(source);
+        assert_eq!(
+            formatted,
+            "; counts down\n\
+             \x20          .equ COUNT 3\n\
+             start:     load $1 #COUNT  ; the counter\n\
+             \n\
+             \x20          ; loop body\n\
+             countdown: dec $1\n\
+             \x20          jmp @countdown\n\
+             table:     .byte 1, 2, ';'\n\
+             \x20          load $1 #'\\\\'  ; hi\n\
+             done:\n"
+        );
+        assert!(is_formatted(&formatted));
+        assert!(!is_formatted(source));
+    }
+
+    #[test]
+    fn test_format_keeps_unparsed_lines() {
+        let source = ".macro  twice reg ,value\nLOAD \\reg  \\value\n.endm\ntwice $1,  2\n";
+        assert_eq!(
+            format_source(source),
+            "    .macro twice reg, value\n    load \\reg \\value\n    .endm\n    twice $1, 2\n"
+        );
+    }
+}
@@ -1,11 +1,11 @@
-use crate::assembler::label_parser::label_declaration;
-use crate::assembler::opcode_parser::opcode;
-use crate::assembler::operand_parser::operand;
-use crate::assembler::{SymbolTable, Token};
-use byteorder::{LittleEndian, WriteBytesExt};
-use nom::types::CompleteStr;
-
-#[derive(Debug, PartialEq)]
+use crate::assembler::assembler_errors::{AssemblerError, AssemblerErrorKind};
+use crate::assembler::directive_parser::DATA_DIRECTIVES;
+use crate::assembler::pseudo_instructions;
+use crate::assembler::{SourceLocation, SymbolTable, SymbolType, Token};
+use crate::instruction::Opcode;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct AssemblerInstruction {
     pub opcode: Option<Token>,
     pub label: Option<Token>,
@@ -13,10 +13,79 @@ pub struct AssemblerInstruction {
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
+    pub location: SourceLocation,
+}
+
+/// What an instruction expects in an operand slot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OperandKind {
+    Register,
+    /// A 16-bit number, label or expression.
+    Value,
+}
+
+/// The operands an opcode takes and how to write them.
+fn signature(code: Opcode) -> (&'static [OperandKind], &'static str) {
+    use OperandKind::{Register, Value};
+    match code {
+        Opcode::HLT => (&[], "hlt"),
+        Opcode::ILLEGAL => (&[], "illegal"),
+        Opcode::LOAD => (&[Register, Value], "load $r #value"),
+        Opcode::ADD => (&[Register, Register, Register], "add $a $b $result"),
+        Opcode::SUB => (&[Register, Register, Register], "sub $a $b $result"),
+        Opcode::MUL => (&[Register, Register, Register], "mul $a $b $result"),
+        Opcode::DIV => (&[Register, Register, Register], "div $a $b $result"),
+        Opcode::EQ => (&[Register, Register], "eq $a $b"),
+        Opcode::NEQ => (&[Register, Register], "neq $a $b"),
+        Opcode::GT => (&[Register, Register], "gt $a $b"),
+        Opcode::LT => (&[Register, Register], "lt $a $b"),
+        Opcode::GE => (&[Register, Register], "ge $a $b"),
+        Opcode::LE => (&[Register, Register], "le $a $b"),
+        Opcode::JMP => (&[Register], "jmp $r"),
+        Opcode::JMPF => (&[Register], "jmpf $r"),
+        Opcode::JMPB => (&[Register], "jmpb $r"),
+        Opcode::JEQ => (&[Register], "jeq $r"),
+        Opcode::ALLOC => (&[Register], "alloc $r"),
+        Opcode::INC => (&[Register], "inc $r"),
+        Opcode::DEC => (&[Register], "dec $r"),
+        Opcode::DJEQ => (&[Value], "djeq @label"),
+    }
+}
+
+/// Bytes emitted per value by `.byte`, `.half` and `.word`.
+fn data_width(directive: &str) -> u32 {
+    match directive {
+        "byte" => 1,
+        "half" => 2,
+        _ => 4,
+    }
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    /// Encodes the instruction or directive, which starts `offset` bytes into
+    /// the program.
+    pub fn to_bytes(&self, offset: u32, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+        if let Some(Token::Directive { name }) = &self.directive {
+            return match name.as_str() {
+                "equ" | "set" | "namespace" | "endnamespace" | "code" | "data" | "entry" => Ok(vec![]),
+                name if DATA_DIRECTIVES.contains(&name) => {
+                    self.data_bytes(name, offset, symbols).map_err(|kind| self.error(kind))
+                }
+                _ => Err(self.error(AssemblerErrorKind::UnknownDirective { name: name.clone() })),
+            };
+        }
+        if self.opcode.is_none() {
+            return Ok(vec![]);
+        }
+        if let Some(expansion) = self.expansion() {
+            let mut results = vec![];
+            for instruction in expansion.map_err(|kind| self.error(kind))? {
+                let offset = offset + results.len() as u32;
+                results.append(&mut instruction.to_bytes(offset, symbols)?);
+            }
+            return Ok(results);
+        }
+        self.check_operands().map_err(|(_, kind)| self.error(kind))?;
         let mut results: Vec<u8> = vec![];
         if let Some(ref token) = self.opcode {
             match token {
@@ -25,44 +94,168 @@ impl AssemblerInstruction {
                     results.push(b);
                 },
                 _ => {
-                    println!("Non-opcode found in opcode field");
+                    return Err(self.error(AssemblerErrorKind::NonOpcodeInOpcodeField));
                 }
             }
         }
         for operand in [&self.operand1, &self.operand2, &self.operand3].iter().copied().flatten() {
-                AssemblerInstruction::extract_operand(operand, &mut results, symbols);
+            self.extract_operand(operand, &mut results, symbols)
+                .map_err(|kind| self.error(kind))?;
         }
         while results.len() < 4 {
             results.push(0);
         }
-        results
+        Ok(results)
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
+    /// Checks that a real instruction has the number and kind of operands its
+    /// opcode takes. The error comes with the index of the first operand that
+    /// is wrong, or of the first missing one.
+    pub fn check_operands(&self) -> Result<(), (usize, AssemblerErrorKind)> {
+        let code = match &self.opcode {
+            Some(Token::Op { code }) => *code,
+            _ => return Ok(()),
+        };
+        let (kinds, usage) = signature(code);
+        let operands: Vec<&Token> = [&self.operand1, &self.operand2, &self.operand3].into_iter().flatten().collect();
+        let wrong = (0..kinds.len().max(operands.len())).find(|&index| {
+            let kind = match operands.get(index) {
+                Some(Token::Register { .. }) => Some(OperandKind::Register),
+                Some(Token::Number { .. } | Token::LabelUsage { .. } | Token::Expression { .. }) => Some(OperandKind::Value),
+                _ => None,
+            };
+            kind.is_none() || kind != kinds.get(index).copied()
+        });
+        match wrong {
+            Some(index) => Err((index, AssemblerErrorKind::InvalidOperands { name: code.to_string(), usage })),
+            None => Ok(()),
+        }
+    }
+
+    fn extract_operand(&self, t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) -> Result<(), AssemblerErrorKind> {
+        if let Token::Register { register_number } = t {
+            results.push(*register_number);
+            return Ok(());
+        }
+        let value = AssemblerInstruction::operand_value(t, symbols)?;
+        // The VM zero-extends 16-bit operands, so negative values would
+        // silently load as large positive ones.
+        let (min, max) = (0, u16::MAX as i64);
+        if value < min || value > max {
+            return Err(AssemblerErrorKind::ValueOutOfRange { value, min, max });
+        }
+        results.extend_from_slice(&(value as u16).to_be_bytes());
+        Ok(())
+    }
+
+    /// Resolves a number, label or expression operand to its value.
+    pub fn operand_value(t: &Token, symbols: &SymbolTable) -> Result<i64, AssemblerErrorKind> {
         match t {
-            Token::Register { register_number } => results.push(*register_number),
-            Token::Number { value } => {
-                let converted = *value as u16;
-                let byte1 = converted;
-                let byte2 = converted >> 8;
-                results.push(byte2 as u8);
-                results.push(byte1 as u8);
+            Token::Number { value } => Ok(*value),
+            Token::LabelUsage { name } => symbols
+                .symbol_value(name)
+                .ok_or_else(|| AssemblerErrorKind::UndefinedSymbol { name: name.clone() }),
+            Token::Expression { expression } => expression.evaluate(symbols),
+            _ => Err(AssemblerErrorKind::NonOperandInOperandField),
+        }
+    }
+
+    /// Returns the name, kind and value token of a `.equ` or `.set` directive.
+    pub fn constant_definition(&self) -> Option<(&str, SymbolType, &Token)> {
+        let symbol_type = match &self.directive {
+            Some(Token::Directive { name }) if name == "equ" => SymbolType::Constant,
+            Some(Token::Directive { name }) if name == "set" => SymbolType::Variable,
+            _ => return None,
+        };
+        match (&self.operand1, &self.operand2) {
+            (Some(Token::Identifier { name }), Some(value)) => Some((name, symbol_type, value)),
+            _ => None,
+        }
+    }
+
+    /// The real instructions a pseudo-instruction stands for, or `None` if
+    /// this is not a pseudo-instruction.
+    pub fn expansion(&self) -> Option<Result<Vec<AssemblerInstruction>, AssemblerErrorKind>> {
+        pseudo_instructions::expand(self)
+    }
+
+    /// Number of bytes this instruction occupies when it starts `offset`
+    /// bytes into the program. The size of `.space` and `.align` must be
+    /// computable from the symbols defined above them.
+    pub fn encoded_len(&self, offset: u32, symbols: &SymbolTable) -> Result<u32, AssemblerErrorKind> {
+        match (&self.directive, &self.operand1) {
+            (Some(Token::Directive { name }), Some(Token::Values { values })) if matches!(name.as_str(), "byte" | "half" | "word") => {
+                return Ok(values.len() as u32 * data_width(name));
+            }
+            (Some(Token::Directive { name }), _) if DATA_DIRECTIVES.contains(&name.as_str()) => {
+                return self.data_bytes(name, offset, symbols).map(|bytes| bytes.len() as u32);
             }
-            Token::LabelUsage {name} => {
-                if let Some(value) = symbols.symbol_value(name) {
-                    let mut wtr = vec![];
-                    wtr.write_u32::<LittleEndian>(value).unwrap();
-                    results.push(wtr[1]);
-                    results.push(wtr[0]);
+            _ => {}
+        }
+        Ok(match self.expansion() {
+            Some(Ok(expansion)) => 4 * expansion.len() as u32,
+            _ if self.opcode.is_some() => 4,
+            _ => 0,
+        })
+    }
+
+    /// Encodes a data directive:
+    ///
+    /// * `.byte`, `.half` and `.word` emit each value as 1, 2 or 4 big-endian
+    ///   bytes; values may be signed or unsigned.
+    /// * `.space count[, fill]` emits `count` copies of `fill`, 0 by default.
+    /// * `.align n` pads with zeros up to the next multiple of `n`.
+    fn data_bytes(&self, name: &str, offset: u32, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerErrorKind> {
+        let values = match &self.operand1 {
+            Some(Token::Values { values }) if self.operand2.is_none() => values.as_slice(),
+            _ => &[],
+        };
+        let value = |token: &Token, min: i64, max: i64| {
+            let value = AssemblerInstruction::operand_value(token, symbols)?;
+            if value < min || value > max {
+                return Err(AssemblerErrorKind::ValueOutOfRange { value, min, max });
+            }
+            Ok(value)
+        };
+        let invalid = |usage| AssemblerErrorKind::InvalidDirectiveOperands { name: name.to_string(), usage };
+        match (name, values) {
+            ("byte" | "half" | "word", [_, ..]) => {
+                let width = data_width(name);
+                let bits = 8 * width;
+                let (min, max) = (-(1i64 << (bits - 1)), (1i64 << bits) - 1);
+                let mut bytes = vec![];
+                for token in values {
+                    let encoded = (value(token, min, max)? as u32).to_be_bytes();
+                    bytes.extend_from_slice(&encoded[4 - width as usize..]);
                 }
+                Ok(bytes)
+            }
+            ("space", [count]) | ("space", [count, _]) => {
+                let count = value(count, 0, u16::MAX as i64)?;
+                let fill = match values.get(1) {
+                    Some(fill) => value(fill, i8::MIN as i64, u8::MAX as i64)?,
+                    None => 0,
+                };
+                Ok(vec![fill as u8; count as usize])
             }
-            _ => {
-                println!("Opcode found in operand field");
-                std::process::exit(1);
+            ("align", [alignment]) => {
+                let alignment = AssemblerInstruction::operand_value(alignment, symbols)?;
+                if !(1..=1 << 16).contains(&alignment) || alignment.count_ones() != 1 {
+                    return Err(AssemblerErrorKind::InvalidAlignment { value: alignment });
+                }
+                let padding = (alignment - offset as i64 % alignment) % alignment;
+                Ok(vec![0; padding as usize])
             }
+            ("space", _) => Err(invalid(".space count[, fill]")),
+            ("align", _) => Err(invalid(".align n")),
+            _ => Err(invalid(".{name} value[, value]...")),
         }
     }
 
+    fn error(&self, kind: AssemblerErrorKind) -> AssemblerError {
+        AssemblerError::new(self.location.clone(), kind)
+    }
+
     pub fn is_label(&self) -> bool
     {
         self.label.is_some()
@@ -70,67 +263,76 @@ impl AssemblerInstruction {
 
     pub fn label_name(&self) -> Option<String> {
         match &self.label {
-            Some(label) => match label {
-                Token::LabelDeclaration {name} => Some(name.clone()),
-                _ => None
-            }
-            None => None,
+            Some(Token::LabelDeclaration {name}) => Some(name.clone()),
+            _ => None,
         }
     }
 }
 
-named!(instruction_combined<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        label: opt!(label_declaration) >>
-        opcode: opcode >>
-        operand1: opt!(operand) >>
-        operand2: opt!(operand) >>
-        operand3: opt!(operand) >>
-        (
-            AssemblerInstruction {
-                opcode: Some(opcode),
-                label,
-                directive: None,
-                operand1,
-                operand2,
-                operand3,
+/// Writes the instruction back as a single line of source.
+impl fmt::Display for AssemblerInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tokens = [&self.label, &self.opcode, &self.directive, &self.operand1, &self.operand2, &self.operand3];
+        for (i, token) in tokens.iter().copied().flatten().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
             }
-        )
-    )
-);
-
-named!(pub instruction<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        instruction: alt!(
-            instruction_combined
-        ) >>
-        (
-            instruction
-        )
-    )
-);
+            write!(f, "{token}")?;
+        }
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
+    use crate::assembler::syntax::SyntaxTree;
+
+    fn parsed(source: &str) -> AssemblerInstruction {
+        let program = SyntaxTree::parse(source).to_program(&SourceLocation::new(1, 1)).unwrap();
+        program.instructions.into_iter().next().unwrap()
+    }
 
     #[test]
     fn test_parse_instruction_form_one() {
-        let result = instruction(CompleteStr("load $0 #100\n"));
         assert_eq!(
-            result,
-            Ok((
-                CompleteStr(""),
-                AssemblerInstruction {
-                    opcode: Option::from(Token::Op { code: Opcode::LOAD }),
-                    directive: None,
-                    label: None,
-                    operand1: Some(Token::Register { register_number: 0 }),
-                    operand2: Some(Token::Number { value: 100 }),
-                    operand3: None
-                }
-            ))
+            parsed("load $0 #100\n"),
+            AssemblerInstruction {
+                opcode: Option::from(Token::Op { code: Opcode::LOAD }),
+                directive: None,
+                label: None,
+                operand1: Some(Token::Register { register_number: 0 }),
+                operand2: Some(Token::Number { value: 100 }),
+                operand3: None,
+                location: SourceLocation::new(1, 1),
+            }
         );
     }
+
+    #[test]
+    fn test_to_bytes_checks_operands() {
+        let register = |register_number| Some(Token::Register { register_number });
+        let mut add = AssemblerInstruction {
+            opcode: Some(Token::Op { code: Opcode::ADD }),
+            directive: None,
+            label: None,
+            operand1: register(1),
+            operand2: Some(Token::Number { value: 5 }),
+            operand3: register(2),
+            location: SourceLocation::default(),
+        };
+        assert_eq!(add.check_operands().map_err(|(index, _)| index), Err(1));
+        assert!(add.to_bytes(0, &SymbolTable::new()).is_err());
+        add.operand2 = register(5);
+        assert_eq!(add.to_bytes(0, &SymbolTable::new()), Ok(vec![2, 1, 5, 2]));
+    }
+
+    #[test]
+    fn test_parse_label_only() {
+        let instruction = parsed("end:");
+        assert_eq!(instruction.label_name(), Some("end".to_string()));
+        assert_eq!(instruction.opcode, None);
+        assert_eq!(instruction.encoded_len(0, &SymbolTable::new()), Ok(0));
+        assert_eq!(instruction.to_bytes(0, &SymbolTable::new()), Ok(vec![]));
+    }
 }
@@ -0,0 +1,126 @@
+use crate::assembler::preprocessor::SourceLine;
+use crate::assembler::syntax::Program;
+use crate::assembler::{Symbol, SymbolTable};
+use std::fmt;
+
+/// Bytes shown on one row of the listing. Longer encodings continue on the
+/// following rows.
+const BYTES_PER_ROW: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingRow {
+    pub offset: u32,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+/// A human-readable account of how each source line was encoded: the byte
+/// offset, the bytes and the line itself, followed by the symbol table.
+/// Pseudo-instructions are followed by the real instructions they expand to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub rows: Vec<ListingRow>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl Listing {
+    /// Builds the listing from the preprocessed `lines`, the `program` parsed
+    /// from them and the offset and bytes encoded for each of its instructions.
+    pub fn new(lines: &[SourceLine], program: &Program, encoded: &[(u32, Vec<u8>)], symbols: &SymbolTable) -> Listing {
+        let mut rows = vec![];
+        let mut offset = encoded.first().map_or(0, |(offset, _)| *offset);
+        let mut instructions = program.instructions.iter().zip(encoded).peekable();
+        for line in lines {
+            let mut text = line.text.trim_end().to_string();
+            while let Some((instruction, (start, bytes))) =
+                instructions.next_if(|(i, _)| i.location == line.location.with_column(i.location.column))
+            {
+                offset = *start;
+                match instruction.expansion() {
+                    Some(Ok(expansion)) => {
+                        rows.push(ListingRow { offset, bytes: vec![], text: std::mem::take(&mut text) });
+                        for (real, bytes) in expansion.iter().zip(bytes.chunks(BYTES_PER_ROW)) {
+                            push_rows(&mut rows, offset, bytes, format!("    {real}"));
+                            offset += bytes.len() as u32;
+                        }
+                    }
+                    _ => {
+                        // Instructions lowered from one line by the optimizer
+                        // are shown like expansions.
+                        let text = match text.is_empty() {
+                            true => format!("    {instruction}"),
+                            false => std::mem::take(&mut text),
+                        };
+                        push_rows(&mut rows, offset, bytes, text);
+                        offset += bytes.len() as u32;
+                    }
+                }
+            }
+            if !text.is_empty() || line.text.trim().is_empty() {
+                rows.push(ListingRow { offset, bytes: vec![], text });
+            }
+        }
+        Listing {
+            rows,
+            symbols: symbols.iter().cloned().collect(),
+        }
+    }
+}
+
+fn push_rows(rows: &mut Vec<ListingRow>, offset: u32, bytes: &[u8], text: String) {
+    let mut chunks = bytes.chunks(BYTES_PER_ROW);
+    let first = chunks.next().unwrap_or_default().to_vec();
+    rows.push(ListingRow { offset, bytes: first, text });
+    let mut offset = offset + BYTES_PER_ROW as u32;
+    for chunk in chunks {
+        rows.push(ListingRow { offset, bytes: chunk.to_vec(), text: String::new() });
+        offset += chunk.len() as u32;
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in &self.rows {
+            let bytes: Vec<String> = row.bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+            let line = format!("{:04x}  {:<11}  {}", row.offset, bytes.join(" "), row.text);
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Symbols:")?;
+        let width = self.symbols.iter().map(|symbol| symbol.name().len()).max().unwrap_or(0);
+        for symbol in &self.symbols {
+            let value = symbol.value();
+            let hex = if value >= 0 { format!("  0x{value:04x}") } else { String::new() };
+            writeln!(f, "{:<width$}  {:<8}  {value:>6}{hex}", symbol.name(), symbol.symbol_type())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_listing() {
+        let mut asm = Assembler::new();
+        asm.generate_listing = true;
+        let program = asm.assemble(".equ VALUE 65534\nstart: load $1 #VALUE\n\njmp @start\nend:\nhlt").unwrap();
+        assert_eq!(
+            program.listing.unwrap().to_string(),
+            "0000               .equ VALUE 65534\n\
+             0000  01 01 ff fe  start: load $1 #VALUE\n\
+             0004\n\
+             0004               jmp @start\n\
+             0004  01 1f 00 00      load $31 @start\n\
+             0008  06 1f 00 00      jmp $31\n\
+             000c               end:\n\
+             000c  00 00 00 00  hlt\n\
+             \n\
+             Symbols:\n\
+             VALUE  constant   65534  0xfffe\n\
+             start  label          0  0x0000\n\
+             end    label         12  0x000c\n"
+        );
+    }
+}
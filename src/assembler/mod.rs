@@ -1,23 +1,148 @@
-use crate::assembler::program_parser::{program, Program};
+use crate::assembler::assembled_program::{AssembledProgram, LineEntry};
+use crate::assembler::assembler_errors::{
+    AssemblerError, AssemblerErrorKind, AssemblerWarning, AssemblerWarningKind,
+};
+use crate::assembler::expression_parser::Expression;
+use crate::assembler::instruction_parser::AssemblerInstruction;
+use crate::assembler::listing::Listing;
+use crate::assembler::syntax::Program;
+use crate::assembler::pseudo_instructions::SCRATCH_REGISTER;
+use crate::assembler::scope_resolver::resolve_scopes;
+use crate::assembler::syntax::SyntaxTree;
 use crate::instruction::Opcode;
+use crate::symbol_map::{Section, SymbolMap};
 use nom::types::CompleteStr;
+use crate::assembler::preprocessor::{Preprocessor, SourceLine};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::str::FromStr;
 
+pub mod assembled_program;
+pub mod assembler_errors;
 pub mod directive_parser;
+pub mod expression_parser;
+pub mod formatter;
 pub mod instruction_parser;
-pub mod label_parser;
+pub mod listing;
 pub mod opcode_parser;
+pub mod optimizer;
 pub mod operand_parser;
-pub mod program_parser;
+pub mod preprocessor;
+pub mod pseudo_instructions;
 pub mod register_parser;
+pub mod scope_resolver;
+pub mod syntax;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Op { code: Opcode },
+    PseudoOp { name: String },
     Register { register_number: u8 },
-    Number { value: i32 },
+    Number { value: i64 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
+    Identifier { name: String },
+    Expression { expression: Expression },
+    /// The comma-separated operands of a data directive.
+    Values { values: Vec<Token> },
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Op { code } => write!(f, "{}", format!("{code:?}").to_lowercase()),
+            Token::PseudoOp { name } => write!(f, "{name}"),
+            Token::Register { register_number } => write!(f, "${register_number}"),
+            Token::Number { value } => write!(f, "#{value}"),
+            Token::LabelDeclaration { name } => write!(f, "{name}:"),
+            Token::LabelUsage { name } => write!(f, "@{name}"),
+            Token::Directive { name } => write!(f, ".{name}"),
+            Token::Identifier { name } => write!(f, "{name}"),
+            Token::Expression { expression } => write!(f, "#{expression}"),
+            Token::Values { values } => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Position of an instruction or error in the assembled source, both 1-based.
+/// Lines produced by a macro point into the macro body and remember the call.
+/// `file` is `None` for source that was passed in as a string.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceLocation {
+    pub file: Option<Arc<SourceFile>>,
+    pub line: usize,
+    pub column: usize,
+    pub expansion: Option<Arc<MacroExpansion>>,
+}
+
+/// A file read by the assembler and the `.include` that pulled it in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub included_from: Option<SourceLocation>,
+}
+
+/// The macro call a line was expanded from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroExpansion {
+    pub name: String,
+    pub call_site: SourceLocation,
+}
+
+impl SourceLocation {
+    pub fn new(line: usize, column: usize) -> SourceLocation {
+        SourceLocation {
+            file: None,
+            line,
+            column,
+            expansion: None,
+        }
+    }
+
+    /// Computes the location of `rest`, which must be a suffix of `source`.
+    pub fn of_suffix(source: &str, rest: &str) -> SourceLocation {
+        let consumed = &source[..source.len() - rest.len()];
+        let line_start = consumed.rfind('\n').map_or(0, |i| i + 1);
+        SourceLocation::new(
+            consumed.matches('\n').count() + 1,
+            consumed[line_start..].chars().count() + 1,
+        )
+    }
+
+    /// Returns this location moved to another column of the same line.
+    pub fn with_column(&self, column: usize) -> SourceLocation {
+        SourceLocation {
+            column,
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) if self.line == 0 => write!(f, "{}", file.path.display())?,
+            Some(file) => write!(f, "{}, line {}, column {}", file.path.display(), self.line, self.column)?,
+            None => write!(f, "line {}, column {}", self.line, self.column)?,
+        }
+        if let Some(expansion) = &self.expansion {
+            write!(f, " (in macro `{}` called at {})", expansion.name, expansion.call_site)?;
+        }
+        if let Some(included_from) = self.file.as_ref().and_then(|file| file.included_from.as_ref()) {
+            write!(f, " (included from {included_from})")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -26,30 +151,74 @@ pub enum AssemblerPhase {
     Second,
 }
 
-#[derive(Debug)]
+/// Assembles source into an [`AssembledProgram`]. The assembler only holds
+/// options, so it can be reused for any number of programs.
+#[derive(Debug, Clone)]
 pub struct Assembler {
-    pub phase: AssemblerPhase,
-    pub symbols: SymbolTable,
+    /// Directories searched by `.include` after the including file's own.
+    pub include_dirs: Vec<PathBuf>,
+    /// Constants defined before the source is read, visible to `.if` and
+    /// `.ifdef` and usable like `.equ` constants.
+    pub defines: Vec<(String, i64)>,
+    /// Whether assembled programs come with a [`Listing`].
+    pub generate_listing: bool,
+    /// Whether to run the peephole optimizer before encoding.
+    pub optimize: bool,
+    /// Whether `.include` may read files. Off for REPL sessions over the
+    /// network.
+    pub allow_include: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Symbol {
     name: String,
-    offset: u32,
+    value: i64,
     symbol_type: SymbolType,
+    section: Section,
 }
 
 impl Symbol {
     pub fn new(name: String, symbol_type: SymbolType, offset: u32) -> Symbol {
+        Symbol::with_value(name, symbol_type, offset as i64)
+    }
+
+    /// Labels are placed in the code section and constants are absolute;
+    /// use [`Symbol::in_section`] for data labels.
+    pub fn with_value(name: String, symbol_type: SymbolType, value: i64) -> Symbol {
+        let section = match symbol_type {
+            SymbolType::Label => Section::Code,
+            SymbolType::Constant | SymbolType::Variable => Section::Absolute,
+        };
         Symbol {
             name,
             symbol_type,
-            offset,
+            value,
+            section,
         }
     }
+
+    pub fn in_section(self, section: Section) -> Symbol {
+        Symbol { section, ..self }
+    }
+
+    pub fn section(&self) -> Section {
+        self.section
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct SymbolTable {
     symbols: Vec<Symbol>,
 }
@@ -59,77 +228,492 @@ impl SymbolTable {
         SymbolTable { symbols: vec![] }
     }
 
+    /// Rebuilds a table from symbols exported with
+    /// [`AssembledProgram::symbol_map`].
+    pub fn from_symbol_map(map: &SymbolMap) -> SymbolTable {
+        let symbols = map
+            .entries()
+            .iter()
+            .map(|entry| Symbol::with_value(entry.name.clone(), entry.kind, entry.value).in_section(entry.section))
+            .collect();
+        SymbolTable { symbols }
+    }
+
     pub fn add_symbol(&mut self, symbol: Symbol) {
         self.symbols.push(symbol);
     }
 
-    pub fn symbol_value(&self, symbol_name: &str) -> Option<u32> {
-        for symbol in &self.symbols {
+    /// Iterates over the symbols in the order they were defined.
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn symbol(&self, symbol_name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == symbol_name)
+    }
+
+    pub fn symbol_value(&self, symbol_name: &str) -> Option<i64> {
+        self.symbol(symbol_name).map(|symbol| symbol.value)
+    }
+
+    pub fn remove_symbol(&mut self, symbol_name: &str) -> Option<Symbol> {
+        let index = self.symbols.iter().position(|symbol| symbol.name == symbol_name)?;
+        Some(self.symbols.remove(index))
+    }
+
+    pub fn set_symbol_value(&mut self, symbol_name: &str, value: i64) -> bool {
+        for symbol in &mut self.symbols {
             if symbol.name == symbol_name {
-                return Option::from(symbol.offset);
+                symbol.value = value;
+                return true;
             }
         }
-        None
+        false
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SymbolType {
     Label,
+    /// Defined once with `.equ`.
+    Constant,
+    /// Defined with `.set`, may be redefined further down.
+    Variable,
+}
+
+impl fmt::Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            SymbolType::Label => "label",
+            SymbolType::Constant => "constant",
+            SymbolType::Variable => "variable",
+        })
+    }
+}
+
+impl FromStr for SymbolType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "label" => Ok(SymbolType::Label),
+            "constant" => Ok(SymbolType::Constant),
+            "variable" => Ok(SymbolType::Variable),
+            _ => Err(format!("unknown symbol kind `{s}`")),
+        }
+    }
+}
+
+/// Parses a `NAME` or `NAME=VALUE` define as given on the command line.
+/// `NAME` alone defines it as 1.
+pub fn parse_define(definition: &str) -> Result<(String, i64), String> {
+    let (name, value) = definition.split_once('=').unwrap_or((definition, "1"));
+    match expression_parser::identifier(CompleteStr(name)) {
+        Ok((rest, _)) if rest.is_empty() => {}
+        _ => return Err(format!("invalid define name `{name}`")),
+    }
+    match expression_parser::expression(CompleteStr(value.trim())) {
+        Ok((rest, expression)) if rest.is_empty() => expression
+            .constant_value()
+            .map(|value| (name.to_string(), value))
+            .ok_or_else(|| format!("define `{name}` must be a constant, found `{value}`")),
+        _ => Err(format!("invalid value `{value}` for define `{name}`")),
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
     pub fn new() -> Assembler {
         Assembler {
-            phase: AssemblerPhase::First,
-            symbols: SymbolTable::new(),
+            include_dirs: vec![],
+            defines: vec![],
+            generate_listing: false,
+            optimize: false,
+            allow_include: true,
+        }
+    }
+
+    /// Defines a constant for the programs assembled from now on, like
+    /// `-D NAME=VALUE` on the command line.
+    pub fn define(&mut self, name: &str, value: i64) {
+        self.defines.push((name.to_string(), value));
+    }
+
+    pub fn assemble(&self, raw: &str) -> Result<AssembledProgram, Vec<AssemblerError>> {
+        let lines = self.preprocessor().process(raw)?;
+        self.assemble_lines(&lines, 0, SymbolTable::new())
+    }
+
+    /// Assembles a file, resolving its `.include`s relative to it.
+    pub fn assemble_file(&self, path: &Path) -> Result<AssembledProgram, Vec<AssemblerError>> {
+        let lines = self.preprocessor().process_file(path)?;
+        self.assemble_lines(&lines, 0, SymbolTable::new())
+    }
+
+    pub(crate) fn preprocessor(&self) -> Preprocessor {
+        let mut preprocessor = Preprocessor::with_include_dirs(self.include_dirs.clone());
+        if !self.allow_include {
+            preprocessor.forbid_include();
+        }
+        for (name, value) in &self.defines {
+            preprocessor.define(name, *value);
         }
+        preprocessor
     }
 
-    pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
-        match program(CompleteStr(raw)) {
-            Ok((_remainder, program)) => {
-                self.process_first_phase(&program);
-                Some(self.process_second_phase(&program))
+    /// Assembles preprocessed lines into a program whose code starts at
+    /// `base`, on top of the already defined `symbols`.
+    pub(crate) fn assemble_lines(
+        &self,
+        lines: &[SourceLine],
+        base: u32,
+        mut symbols: SymbolTable,
+    ) -> Result<AssembledProgram, Vec<AssemblerError>> {
+        for (name, value) in &self.defines {
+            if symbols.symbol(name).is_none() {
+                symbols.add_symbol(Symbol::with_value(name.clone(), SymbolType::Constant, *value));
             }
-            Err(e) => {
-                println!("Error while assembling: {e:?}");
-                None
+        }
+        let mut program = Assembler::parse(lines)?;
+        let mut errors = resolve_scopes(&mut program);
+        let warnings = Assembler::warnings(&program);
+        let optimizations = self.optimize.then(|| optimizer::optimize(&mut program));
+        // The data section follows the code, so the code is laid out once to
+        // find where it ends before any data label gets its address.
+        let mut sizing = Assembly::new(symbols.clone(), base, base);
+        sizing.extract_symbols(&program);
+        let data_base = sizing.code_end.next_multiple_of(4);
+        let mut assembly = Assembly::new(symbols, base, data_base);
+        errors.append(&mut assembly.process_first_phase(&program));
+        match assembly.process_second_phase(&program) {
+            Ok(encoded) if errors.is_empty() => {
+                let mut assembled = self.finish(lines, &program, assembly, encoded);
+                assembled.warnings = warnings;
+                assembled.optimizations = optimizations;
+                Ok(assembled)
+            }
+            Ok(_) => Err(errors),
+            Err(mut second_phase_errors) => {
+                errors.append(&mut second_phase_errors);
+                Err(errors)
             }
         }
     }
 
-    fn process_first_phase(&mut self, p: &Program) {
-        self.extract_labels(p);
+    fn finish(&self, lines: &[SourceLine], program: &Program, assembly: Assembly, encoded: Vec<Encoded>) -> AssembledProgram {
+        let mut code = vec![];
+        let mut data = vec![];
+        let mut code_lines = vec![];
+        let mut data_lines = vec![];
+        for (instruction, encoded) in program.instructions.iter().zip(&encoded) {
+            let (section, section_lines) = match encoded.section {
+                Section::Data => (&mut data, &mut data_lines),
+                _ => (&mut code, &mut code_lines),
+            };
+            section.extend_from_slice(&encoded.bytes);
+            if !encoded.bytes.is_empty() {
+                section_lines.push(LineEntry {
+                    offset: encoded.offset,
+                    len: encoded.bytes.len() as u32,
+                    location: instruction.location.clone(),
+                });
+            }
+        }
+        code_lines.append(&mut data_lines);
+        let listing = self.generate_listing.then(|| {
+            let encoded: Vec<(u32, Vec<u8>)> = encoded.into_iter().map(|e| (e.offset, e.bytes)).collect();
+            Listing::new(lines, program, &encoded, &assembly.symbols)
+        });
+        AssembledProgram {
+            code,
+            code_offset: assembly.code_base,
+            data,
+            data_offset: assembly.data_base,
+            entry: assembly.entry.unwrap_or(assembly.code_base),
+            symbols: assembly.symbols,
+            lines: code_lines,
+            warnings: vec![],
+            optimizations: None,
+            listing,
+            unresolved: vec![],
+        }
+    }
+
+    /// Parses preprocessed lines through their syntax trees, giving each
+    /// instruction the location of the line it was written on.
+    fn parse(lines: &[SourceLine]) -> Result<Program, Vec<AssemblerError>> {
+        let mut instructions = vec![];
+        let mut errors = vec![];
+        for line in lines {
+            match SyntaxTree::parse(&line.text).to_program(&line.location) {
+                Ok(mut parsed) => instructions.append(&mut parsed.instructions),
+                Err(mut line_errors) => errors.append(&mut line_errors),
+            }
+        }
+        if errors.is_empty() {
+            Ok(Program { instructions })
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Warns when a program that uses pseudo-instructions also uses their
+    /// scratch register itself.
+    fn warnings(program: &Program) -> Vec<AssemblerWarning> {
+        if !program.instructions.iter().any(|i| i.expansion().is_some()) {
+            return vec![];
+        }
+        let scratch = Token::Register { register_number: SCRATCH_REGISTER };
+        program
+            .instructions
+            .iter()
+            .filter(|i| i.expansion().is_none())
+            .find(|i| [&i.operand1, &i.operand2, &i.operand3].contains(&&Some(scratch.clone())))
+            .map(|i| AssemblerWarning::new(i.location.clone(), AssemblerWarningKind::ScratchRegisterUsed))
+            .into_iter()
+            .collect()
+    }
+}
+
+/// The bytes encoded for one instruction or directive and where they go.
+struct Encoded {
+    section: Section,
+    offset: u32,
+    bytes: Vec<u8>,
+}
+
+/// The state of a single assembly run.
+struct Assembly {
+    phase: AssemblerPhase,
+    symbols: SymbolTable,
+    code_base: u32,
+    data_base: u32,
+    /// End of the code section, known after the first phase.
+    code_end: u32,
+    entry: Option<u32>,
+    /// Instructions whose size could not be worked out in the first phase,
+    /// which has already reported why.
+    unsized_instructions: Vec<usize>,
+}
+
+impl Assembly {
+    fn new(symbols: SymbolTable, code_base: u32, data_base: u32) -> Assembly {
+        Assembly {
+            phase: AssemblerPhase::First,
+            symbols,
+            code_base,
+            data_base,
+            code_end: code_base,
+            entry: None,
+            unsized_instructions: vec![],
+        }
+    }
+
+    fn process_first_phase(&mut self, p: &Program) -> Vec<AssemblerError> {
+        let errors = self.extract_symbols(p);
         self.phase = AssemblerPhase::Second;
+        errors
     }
 
-    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
+    /// Encodes every instruction, returning the bytes of each one separately.
+    fn process_second_phase(&mut self, p: &Program) -> Result<Vec<Encoded>, Vec<AssemblerError>> {
         let mut program = vec![];
-        for i in &p.instructions {
-            let mut bytes = i.to_bytes(&self.symbols);
-            program.append(&mut bytes);
+        let mut errors = vec![];
+        let mut failed = false;
+        let mut sections = Sections::new(self.code_base, self.data_base);
+        for (index, i) in p.instructions.iter().enumerate() {
+            sections.switch(i);
+            let offset = sections.offset();
+            let result = match i.constant_definition() {
+                Some(_) => self.define_constant(i).map(|_| vec![]),
+                None if is_directive(i, "entry") => self.define_entry(i).map(|_| vec![]),
+                None => i.to_bytes(offset, &self.symbols),
+            };
+            match result {
+                Ok(bytes) => {
+                    sections.advance(bytes.len() as u32);
+                    program.push(Encoded {
+                        section: sections.current,
+                        offset,
+                        bytes,
+                    });
+                }
+                Err(_) if self.unsized_instructions.contains(&index) => failed = true,
+                Err(error) => errors.push(error),
+            }
+        }
+        if errors.is_empty() && !failed {
+            Ok(program)
+        } else {
+            Err(errors)
         }
-        program
     }
 
-    fn extract_labels(&mut self, program: &Program) {
-        let mut c = 0;
-        for i in &program.instructions {
-            if i.is_label() {
-                if let Some(name) = i.label_name() {
-                    let symbol = Symbol::new(name, SymbolType::Label, c);
+    /// Records labels at their offsets and evaluates constants in source order.
+    /// Constants referring to labels further down are retried once every label
+    /// is known; whatever is still unresolved gets reported by the second phase.
+    fn extract_symbols(&mut self, program: &Program) -> Vec<AssemblerError> {
+        let mut errors = vec![];
+        let mut unresolved = vec![];
+        let mut sections = Sections::new(self.code_base, self.data_base);
+        for (index, i) in program.instructions.iter().enumerate() {
+            sections.switch(i);
+            let c = sections.offset();
+            let len = match i.encoded_len(c, &self.symbols) {
+                Ok(len) => len,
+                Err(kind) => {
+                    errors.push(AssemblerError::new(i.location.clone(), kind));
+                    self.unsized_instructions.push(index);
+                    0
+                }
+            };
+            if let Some(name) = i.label_name() {
+                // A label on `.align` names the aligned offset, not the padding.
+                let offset = if is_directive(i, "align") { c + len } else { c };
+                if self.symbols.symbol(&name).is_some() {
+                    errors.push(AssemblerError::new(
+                        i.location.clone(),
+                        AssemblerErrorKind::SymbolAlreadyDefined { name },
+                    ));
+                } else {
+                    let symbol = Symbol::new(name, SymbolType::Label, offset).in_section(sections.current);
                     self.symbols.add_symbol(symbol);
-                };
+                }
+            }
+            if i.constant_definition().is_some() {
+                match self.define_constant(i) {
+                    Ok(()) => {}
+                    Err(error @ AssemblerError {
+                        kind: AssemblerErrorKind::SymbolAlreadyDefined { .. },
+                        ..
+                    }) => errors.push(error),
+                    Err(_) => unresolved.push(i),
+                }
+            }
+            sections.advance(len);
+        }
+        self.code_end = sections.code;
+        while !unresolved.is_empty() {
+            let count = unresolved.len();
+            unresolved.retain(|i| self.define_constant(i).is_err());
+            if unresolved.len() == count {
+                break;
+            }
+        }
+        errors
+    }
+
+    /// Evaluates a `.equ` or `.set` directive and stores its value. Only `.set`
+    /// symbols may be redefined; the second phase updates values in place so
+    /// each instruction sees the value assigned above it.
+    fn define_constant(&mut self, i: &AssemblerInstruction) -> Result<(), AssemblerError> {
+        let (name, symbol_type, value) = match i.constant_definition() {
+            Some(definition) => definition,
+            None => return Ok(()),
+        };
+        let value = AssemblerInstruction::operand_value(value, &self.symbols)
+            .map_err(|kind| AssemblerError::new(i.location.clone(), kind))?;
+        match self.symbols.symbol(name) {
+            None => self
+                .symbols
+                .add_symbol(Symbol::with_value(name.to_string(), symbol_type, value)),
+            Some(existing)
+                if matches!(self.phase, AssemblerPhase::First)
+                    && (symbol_type != SymbolType::Variable
+                        || *existing.symbol_type() != SymbolType::Variable) =>
+            {
+                return Err(AssemblerError::new(
+                    i.location.clone(),
+                    AssemblerErrorKind::SymbolAlreadyDefined {
+                        name: name.to_string(),
+                    },
+                ));
+            }
+            Some(_) => {
+                self.symbols.set_symbol_value(name, value);
             }
-            c += 4;
         }
+        Ok(())
     }
+
+    /// Evaluates `.entry @label`, which may appear once per program.
+    fn define_entry(&mut self, i: &AssemblerInstruction) -> Result<(), AssemblerError> {
+        let error = |kind| AssemblerError::new(i.location.clone(), kind);
+        let value = match (&i.operand1, &i.operand2) {
+            (Some(value), None) => AssemblerInstruction::operand_value(value, &self.symbols).map_err(error)?,
+            _ => {
+                return Err(error(AssemblerErrorKind::InvalidDirectiveOperands {
+                    name: "entry".to_string(),
+                    usage: ".entry @label",
+                }))
+            }
+        };
+        if self.entry.is_some() {
+            return Err(error(AssemblerErrorKind::DuplicateEntry));
+        }
+        let (min, max) = (0, u16::MAX as i64);
+        if value < min || value > max {
+            return Err(error(AssemblerErrorKind::ValueOutOfRange { value, min, max }));
+        }
+        self.entry = Some(value as u32);
+        Ok(())
+    }
+}
+
+/// Tracks the section selected by `.code` and `.data` and the next free
+/// offset in each.
+struct Sections {
+    current: Section,
+    code: u32,
+    data: u32,
+}
+
+impl Sections {
+    fn new(code: u32, data: u32) -> Sections {
+        Sections {
+            current: Section::Code,
+            code,
+            data,
+        }
+    }
+
+    fn switch(&mut self, i: &AssemblerInstruction) {
+        if is_directive(i, "code") {
+            self.current = Section::Code;
+        } else if is_directive(i, "data") {
+            self.current = Section::Data;
+        }
+    }
+
+    fn offset(&self) -> u32 {
+        match self.current {
+            Section::Data => self.data,
+            _ => self.code,
+        }
+    }
+
+    fn advance(&mut self, len: u32) {
+        match self.current {
+            Section::Data => self.data += len,
+            _ => self.code += len,
+        }
+    }
+}
+
+fn is_directive(i: &AssemblerInstruction, directive: &str) -> bool {
+    matches!(&i.directive, Some(Token::Directive { name }) if name == directive)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::symbol_map::SymbolMap;
+    use crate::vm::VM;
 
     #[test]
     fn test_symbol_table() {
@@ -147,20 +731,376 @@ mod tests {
 
     #[test]
     fn test_assemble_program() {
-        let mut asm = Assembler::new();
-        let test_string = "load $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njeq @test\nhlt";
+        let asm = Assembler::new();
+        let test_string = "load $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\ndjeq @test\nhlt";
         /*1 0 0 100
           1 1 0 1
           1 2 0 0
           17 0 0 0
           10 0 2 0
-          15 0 0 0
+          19 0 12 0
           0
          */
-       // let program = asm.assemble(test_string).unwrap();
-       //  let mut vm = VM::new();
-       //  assert_eq!(program.len(), 28);
-       //  vm.add_bytes(program);
-       //  assert_eq!(vm.program.len(), 28);
+        let program = asm.assemble(test_string).unwrap().code;
+        let mut vm = VM::new();
+        assert_eq!(program.len(), 28);
+        vm.add_bytes(program);
+        assert_eq!(vm.program.len(), 28);
+    }
+
+    #[test]
+    fn test_assemble_reports_invalid_operands() {
+        let errors = Assembler::new()
+            .assemble("add $1 #5 $2\nload $1\nhlt $1 $2 $3\njmp $1 $2\nend: hlt")
+            .unwrap_err();
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "line 1, column 8: invalid operands for `add`, expected `add $a $b $result`",
+                "line 2, column 1: invalid operands for `load`, expected `load $r #value`",
+                "line 3, column 5: invalid operands for `hlt`, expected `hlt`",
+                "line 4, column 8: invalid operands for `jmp`, expected `jmp $r`",
+            ]
+        );
+        let program = Assembler::new().assemble("load $1 #5\nadd $1 $1 $2\nend: djeq @end").unwrap();
+        assert_eq!(program.code.len(), 12);
+        assert_eq!(program.symbols.symbol_value("end"), Some(8));
+    }
+
+    #[test]
+    fn test_assemble_prefixed_numbers() {
+        let asm = Assembler::new();
+        let program = asm.assemble("load $0 #0xFF_FE\nload $1 #0x7F_FF\nload $2 #'A'").unwrap().code;
+        assert_eq!(program, vec![1, 0, 255, 254, 1, 1, 127, 255, 1, 2, 0, 65]);
+        let mut vm = VM::new();
+        vm.add_bytes(asm.assemble("load $0 #0b101\nload $1 #65535").unwrap().code);
+        vm.run();
+        assert_eq!(&vm.registers[0..2], &[5, 65535]);
+    }
+
+    #[test]
+    fn test_assemble_rejects_negative_loads() {
+        let errors = Assembler::new().assemble("load $0 #-2").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::new(
+                SourceLocation::new(1, 1),
+                AssemblerErrorKind::ValueOutOfRange { value: -2, min: 0, max: 65535 }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_load_labels_above_32767() {
+        let program = Assembler::new()
+            .assemble("jmp @far\n.space 40000\nfar: load $1 @far\nhlt")
+            .unwrap();
+        assert_eq!(program.symbols.symbol_value("far"), Some(40008));
+        let mut vm = VM::new();
+        vm.add_bytes(program.code);
+        vm.run();
+        assert_eq!(vm.registers[1], 40008);
+    }
+
+    #[test]
+    fn test_assemble_reports_invalid_register() {
+        let asm = Assembler::new();
+        let errors = asm.assemble("load $0 #1\nload $300 #1").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![AssemblerError::new(
+                SourceLocation::new(2, 7),
+                AssemblerErrorKind::InvalidRegister {
+                    register: "300".to_string()
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_assemble_reports_out_of_range_values() {
+        let asm = Assembler::new();
+        let errors = asm.assemble("load $0 #65536\nload $1 #-1\nload $2 #65535").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].location.line, 1);
+        assert_eq!(
+            errors[0].kind,
+            AssemblerErrorKind::ValueOutOfRange {
+                value: 65536,
+                min: 0,
+                max: 65535
+            }
+        );
+        assert_eq!(errors[1].location.line, 2);
+    }
+
+    #[test]
+    fn test_assemble_constants() {
+        let asm = Assembler::new();
+        let source = ".equ BUF_SIZE 16\n\
+                      .equ LAST end - 4\n\
+                      .set step #2\n\
+                      load $0 #(BUF_SIZE * 4 + 1)\n\
+                      load $1 #step\n\
+                      .set step step << 3\n\
+                      load $2 #step\n\
+                      end: load $3 #LAST\n\
+                      djeq @end+4";
+        let program = asm.assemble(source).unwrap().code;
+        assert_eq!(
+            program,
+            vec![1, 0, 0, 65, 1, 1, 0, 2, 1, 2, 0, 16, 1, 3, 0, 8, 19, 0, 16, 0]
+        );
+    }
+
+    #[test]
+    fn test_assemble_reports_constant_errors() {
+        let asm = Assembler::new();
+        let errors = asm
+            .assemble(".equ SIZE 4\n.equ SIZE 8\n.set SIZE 2\nload $0 #(SIZE / 0)\nload $1 #missing")
+            .unwrap_err();
+        let kinds: Vec<AssemblerErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AssemblerErrorKind::SymbolAlreadyDefined {
+                    name: "SIZE".to_string()
+                },
+                AssemblerErrorKind::SymbolAlreadyDefined {
+                    name: "SIZE".to_string()
+                },
+                AssemblerErrorKind::DivisionByZero,
+                AssemblerErrorKind::UndefinedSymbol {
+                    name: "missing".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_twice_does_not_keep_symbols() {
+        let asm = Assembler::new();
+        assert!(asm.assemble(".equ SIZE 4\nload $0 #SIZE").is_ok());
+        assert!(asm.assemble(".equ SIZE 4\nload $0 #SIZE").is_ok());
+    }
+
+    #[test]
+    fn test_assemble_macros() {
+        let asm = Assembler::new();
+        let source = ".macro countdown reg, from\n\
+                      load \\reg #\\from\n\
+                      load $30 #0\n\
+                      again: dec \\reg\n\
+                      neq \\reg $30\n\
+                      djeq @again\n\
+                      .endm\n\
+                      countdown $1, 3\n\
+                      done:\n\
+                      countdown $2, (1 + 1)";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.code.len(), 40);
+        assert_eq!(program.symbols.symbol_value(".__again_1"), Some(8));
+        assert_eq!(program.symbols.symbol_value("done"), Some(20));
+        assert_eq!(program.symbols.symbol_value("done.__again_2"), Some(28));
+        assert_eq!(&program.code[16..20], &[19, 0, 8, 0]);
+        assert_eq!(&program.code[36..40], &[19, 0, 28, 0]);
+    }
+
+    #[test]
+    fn test_assemble_data_directives() {
+        let asm = Assembler::new();
+        let source = ".equ COUNT 3\n\
+                      start: djeq @table\n\
+                      bytes: .byte 1, -1, 'A'\n\
+                      table: .align 4\n\
+                      .half 0x1234, -2\n\
+                      .word @start, @end, -1\n\
+                      .space COUNT, 0xaa\n\
+                      .align 2\n\
+                      end: hlt";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.symbols.symbol_value("bytes"), Some(4));
+        assert_eq!(program.symbols.symbol_value("table"), Some(8));
+        assert_eq!(program.symbols.symbol_value("end"), Some(28));
+        assert_eq!(
+            program.code,
+            vec![
+                19, 0, 8, 0, 1, 255, 65, 0, 0x12, 0x34, 255, 254, 0, 0, 0, 0, 0, 0, 0, 28, 255, 255, 255, 255,
+                0xaa, 0xaa, 0xaa, 0, 0, 0, 0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_directive_errors() {
+        let asm = Assembler::new();
+        let errors = asm
+            .assemble(".byte 256\n.align 3\n.space\n.space LATER\n.equ LATER 2")
+            .unwrap_err();
+        let kinds: Vec<AssemblerErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AssemblerErrorKind::InvalidAlignment { value: 3 },
+                AssemblerErrorKind::InvalidDirectiveOperands {
+                    name: "space".to_string(),
+                    usage: ".space count[, fill]"
+                },
+                AssemblerErrorKind::UndefinedSymbol { name: "LATER".to_string() },
+                AssemblerErrorKind::ValueOutOfRange { value: 256, min: -128, max: 255 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_with_defines() {
+        let source = ".ifdef DEBUG\nload $1 #DEBUG\n.else\nload $1 #0\n.endif\nstart:";
+        let mut asm = Assembler::new();
+        assert_eq!(asm.assemble(source).unwrap().code, vec![1, 1, 0, 0]);
+        asm.define("DEBUG", 7);
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.code, vec![1, 1, 0, 7]);
+        assert_eq!(program.symbols.symbol_value("start"), Some(4));
+    }
+
+    #[test]
+    fn test_parse_define() {
+        assert_eq!(parse_define("DEBUG"), Ok(("DEBUG".to_string(), 1)));
+        assert_eq!(parse_define("LEVEL=0x10 + 1"), Ok(("LEVEL".to_string(), 17)));
+        assert!(parse_define("1x=2").is_err());
+        assert!(parse_define("LEVEL=OTHER").is_err());
+    }
+
+    #[test]
+    fn test_export_symbol_map() {
+        let asm = Assembler::new();
+        let program = asm.assemble(".equ COUNT 3\nstart: load $1 #COUNT\nloop: dec $1\njmp @loop").unwrap();
+        let map = program.symbol_map();
+        assert_eq!(map.get("COUNT").map(|entry| entry.section), Some(Section::Absolute));
+        assert_eq!(map.get("loop").map(|entry| entry.value), Some(4));
+        assert_eq!(map.describe_address(10), "loop+6");
+        assert_eq!(SymbolMap::parse(&map.to_machine_readable()), Ok(map));
+    }
+
+    #[test]
+    fn test_assemble_pseudo_instructions() {
+        let asm = Assembler::new();
+        let source = "load $1 #5\n\
+                      mov $2 $1\n\
+                      not $2\n\
+                      jmp @skip\n\
+                      load $3 #99\n\
+                      skip: beq $1 $1 @end\n\
+                      load $4 #1\n\
+                      end: hlt";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.symbols.symbol_value("skip"), Some(36));
+        assert_eq!(program.symbols.symbol_value("end"), Some(52));
+        assert_eq!(program.code.len(), 56);
+        let mut vm = VM::new();
+        vm.add_bytes(program.code);
+        vm.run();
+        assert_eq!(&vm.registers[1..5], &[5, -6, 0, 0]);
+    }
+
+    #[test]
+    fn test_assemble_rejects_pseudo_instructions_reading_the_scratch_register() {
+        let errors = Assembler::new().assemble("load $31 #5\nnot $31\nmov $1 $31").unwrap_err();
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "line 2, column 1: `not` cannot read $31, it overwrites it first",
+                "line 3, column 1: `mov` cannot read $31, it overwrites it first",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_optimized_program_behaves_the_same() {
+        let source = "load $1 #5\n\
+                      load $2 #2\n\
+                      load $3 #3\n\
+                      add $2 $3 $2\n\
+                      load $3 #0\n\
+                      jmp @skip\n\
+                      skip: beq $1 $2 @end\n\
+                      load $4 #1\n\
+                      end: hlt";
+        let plain = Assembler::new().assemble(source).unwrap();
+        let mut asm = Assembler::new();
+        asm.optimize = true;
+        let optimized = asm.assemble(source).unwrap();
+        let report = optimized.optimizations.as_ref().unwrap();
+        assert_eq!(report.optimizations.len(), 3);
+        assert_eq!(report.bytes_saved(), 20);
+        assert_eq!(optimized.code.len() + 20, plain.code.len());
+        assert_eq!(optimized.symbols.symbol_value("end"), Some(24));
+        let mut registers = vec![];
+        for program in [plain, optimized] {
+            let mut vm = VM::new();
+            vm.add_bytes(program.code);
+            vm.run();
+            registers.push(vm.registers[..SCRATCH_REGISTER as usize].to_vec());
+        }
+        assert_eq!(registers[0], registers[1]);
+    }
+
+    #[test]
+    fn test_assemble_scoped_labels() {
+        let asm = Assembler::new();
+        let source = ".namespace math\n\
+                      double: add $1 $1 $1\n\
+                      .loop: djeq @.loop\n\
+                      .endnamespace\n\
+                      main: djeq @math.double\n\
+                      1: djeq @1f\n\
+                      .loop: djeq @1b\n\
+                      1: djeq @.loop";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(program.symbols.symbol_value("math.double"), Some(0));
+        assert_eq!(program.symbols.symbol_value("math.double.loop"), Some(4));
+        assert_eq!(program.symbols.symbol_value("main.loop"), Some(16));
+        assert_eq!(&program.code[4..8], &[19, 0, 4, 0]);
+        assert_eq!(&program.code[12..16], &[19, 0, 20, 0]);
+        assert_eq!(&program.code[16..20], &[19, 0, 12, 0]);
+        assert_eq!(&program.code[20..24], &[19, 0, 16, 0]);
+    }
+
+    #[test]
+    fn test_assemble_reports_errors_inside_macros() {
+        let asm = Assembler::new();
+        let errors = asm
+            .assemble(".macro set reg value\nload \\reg \\value\n.endm\n\nset $1 #1\nset $2 #70000")
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "line 2, column 1 (in macro `set` called at line 6, column 1): \
+             value 70000 does not fit in operand (expected 0 to 65535)"
+        );
+    }
+
+    #[test]
+    fn test_assemble_ignores_comments() {
+        let asm = Assembler::new();
+        let program = asm.assemble("; setup\nload $1 #';' ; semicolon\n  ; done\nhlt").unwrap();
+        assert_eq!(program.code, vec![1, 1, 0, 59, 0, 0, 0, 0]);
+        let program = asm.assemble("load $1 #'\\\\' ; backslash\nload $2 #'\\'' ; quote").unwrap();
+        assert_eq!(program.code, vec![1, 1, 0, 92, 1, 2, 0, 39]);
+    }
+
+    #[test]
+    fn test_assemble_reports_trailing_garbage() {
+        let asm = Assembler::new();
+        let errors = asm.assemble("load $0 #1\nload $1 #12 %").unwrap_err();
+        assert_eq!(errors[0].location, SourceLocation::new(2, 13));
+        assert_eq!(
+            errors[0].kind,
+            AssemblerErrorKind::UnexpectedInput {
+                found: "%".to_string()
+            }
+        );
     }
 }
@@ -1,3 +1,4 @@
+use crate::assembler::pseudo_instructions::is_pseudo_instruction;
 use crate::assembler::Token;
 use crate::instruction::Opcode;
 use nom::alpha1;
@@ -8,7 +9,12 @@ named!(pub opcode<CompleteStr, Token>,
         opcode: alpha1 >>
         (
             {
-                Token::Op{code: Opcode::from(opcode)}
+                match Opcode::from(opcode) {
+                    Opcode::ILLEGAL if is_pseudo_instruction(&opcode) => {
+                        Token::PseudoOp { name: opcode.to_lowercase() }
+                    }
+                    code => Token::Op { code },
+                }
             }
         )
     )
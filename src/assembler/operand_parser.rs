@@ -1,16 +1,64 @@
 use crate::assembler;
-use crate::assembler::label_parser::label_usage;
+use crate::assembler::assembler_errors::INVALID_NUMBER;
+use crate::assembler::expression_parser::{expression, identifier, Expression};
 use assembler::register_parser::register;
 use assembler::Token;
-use nom::digit;
 use nom::types::CompleteStr;
+use nom::{anychar, digit, ErrorKind};
+
+// A number or expression, folded to `Token::Number` when it does not refer
+// to any symbol.
+named!(pub(crate) integer_value<CompleteStr, Token>,
+    return_error!(
+        ErrorKind::Custom(INVALID_NUMBER),
+        map!(expression, |expression| match expression.constant_value() {
+            Some(value) => Token::Number { value },
+            None => Token::Expression { expression },
+        })
+    )
+);
 
 named!(pub integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
-            tag!("#") >> register_number: digit >>
+            tag!("#") >>
+            value: integer_value >>
             (
-                Token::Number{value: register_number.parse::<i32>().unwrap()}
+                value
+            )
+        )
+    )
+);
+
+// A reference to the closest numeric label before (`1b`) or after (`1f`).
+named!(numeric_label_reference<CompleteStr, CompleteStr>,
+    recognize!(
+        terminated!(
+            pair!(digit, one_of!("bf")),
+            not!(peek!(verify!(anychar, |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.')))
+        )
+    )
+);
+
+named!(pub label_usage<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("@") >>
+            token: alt!(
+                map!(numeric_label_reference, |name| Token::LabelUsage { name: name.to_string() }) |
+                do_parse!(
+                    peek!(identifier) >>
+                    value: expression >>
+                    (
+                        match value {
+                            Expression::Symbol(name) => Token::LabelUsage { name },
+                            expression => Token::Expression { expression },
+                        }
+                    )
+                )
+            ) >>
+            (
+                token
             )
         )
     )
@@ -27,6 +75,7 @@ named!(pub operand<CompleteStr, Token>,
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::{Symbol, SymbolTable, SymbolType};
 
     #[test]
     fn test_parse_integer_operand() {
@@ -39,4 +88,86 @@ mod tests {
         let result = integer_operand(CompleteStr("10"));
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_number_literals() {
+        let cases = [
+            ("#-10", -10),
+            ("#0x1F", 31),
+            ("#0XfF", 255),
+            ("#-0x10", -16),
+            ("#0b1010", 10),
+            ("#0o17", 15),
+            ("#1_000", 1000),
+            ("#0xFF_FF", 65535),
+            ("#'A'", 65),
+            ("#'\\n'", 10),
+            ("#'\\''", 39),
+        ];
+        for (input, expected) in cases {
+            let result = integer_operand(CompleteStr(input));
+            assert_eq!(result, Ok((CompleteStr(""), Token::Number { value: expected })), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_number_literals() {
+        for input in ["#1__0", "#1_", "#0x_", "#99999999999999999999", "#''", "#'\\q'", "#)"] {
+            let result = integer_operand(CompleteStr(input));
+            let ok = matches!(result, Ok((rest, _)) if rest.is_empty());
+            assert!(!ok, "{input} should not parse");
+        }
+        let result = integer_operand(CompleteStr("#%"));
+        assert!(matches!(result, Err(nom::Err::Failure(_))));
+    }
+
+    #[test]
+    fn test_parse_expression_operand() {
+        let result = integer_operand(CompleteStr("#(2 + 3) * 4"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Number { value: 20 })));
+        let (rest, token) = integer_operand(CompleteStr("#(BUF_SIZE * 4 + 1) $1")).unwrap();
+        assert_eq!(rest, CompleteStr("$1"));
+        assert!(matches!(token, Token::Expression { .. }));
+    }
+
+    #[test]
+    fn test_parse_label_usage() {
+        let result = label_usage(CompleteStr("@test"));
+        assert!(result.is_ok());
+        let (_, token) = result.unwrap();
+        assert_eq!(token, Token::LabelUsage { name: "test".to_string() });
+        let result = label_usage(CompleteStr("test"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_label_usage_with_offset() {
+        let result = label_usage(CompleteStr("@table+8"));
+        let (rest, token) = result.unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        match token {
+            Token::Expression { expression } => {
+                let mut symbols = SymbolTable::new();
+                symbols.add_symbol(Symbol::new("table".to_string(), SymbolType::Label, 4));
+                assert_eq!(expression.evaluate(&symbols), Ok(12));
+            }
+            token => panic!("expected an expression, got {token:?}"),
+        }
+        let result = label_usage(CompleteStr("@8"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_scoped_label_usage() {
+        for name in ["main.loop", ".loop", "_start", "math.sqrt.done"] {
+            let usage = format!("@{name}");
+            let result = label_usage(CompleteStr(&usage));
+            assert_eq!(result, Ok((CompleteStr(""), Token::LabelUsage { name: name.to_string() })));
+        }
+        let result = label_usage(CompleteStr("@1b"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::LabelUsage { name: "1b".to_string() })));
+        let result = label_usage(CompleteStr("@12f\n"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::LabelUsage { name: "12f".to_string() })));
+        assert!(label_usage(CompleteStr("@1bad")).is_err());
+    }
 }
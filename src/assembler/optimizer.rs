@@ -0,0 +1,510 @@
+use crate::assembler::instruction_parser::AssemblerInstruction;
+use crate::assembler::syntax::Program;
+use crate::assembler::pseudo_instructions::SCRATCH_REGISTER;
+use crate::assembler::{SourceLocation, Token};
+use crate::instruction::Opcode;
+use std::fmt;
+
+/// Bytes taken by every real instruction.
+const INSTRUCTION_LEN: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptimizationKind {
+    /// A load whose value is overwritten by the next instruction.
+    RedundantLoad,
+    /// Arithmetic on registers just loaded with constants.
+    ConstantFold,
+    /// A jump whose target is the instruction right after it.
+    JumpToNext,
+    /// A comparison followed by a jump through the scratch register, or by a
+    /// branch that can never be taken.
+    CompareAndBranch,
+}
+
+impl fmt::Display for OptimizationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            OptimizationKind::RedundantLoad => "removed redundant load",
+            OptimizationKind::ConstantFold => "folded constant arithmetic",
+            OptimizationKind::JumpToNext => "removed jump to the next instruction",
+            OptimizationKind::CompareAndBranch => "simplified compare and branch",
+        })
+    }
+}
+
+/// One rewrite, with the instructions it replaced and the ones it produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Optimization {
+    pub location: SourceLocation,
+    pub kind: OptimizationKind,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+impl Optimization {
+    pub fn bytes_saved(&self) -> u32 {
+        (self.before.len() - self.after.len()) as u32 * INSTRUCTION_LEN
+    }
+}
+
+impl fmt::Display for Optimization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let after = match self.after.is_empty() {
+            true => "(nothing)".to_string(),
+            false => self.after.join("; "),
+        };
+        write!(f, "{}: {}: {} => {after}", self.location, self.kind, self.before.join("; "))
+    }
+}
+
+/// Everything the optimizer changed, in the order it was changed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OptimizationReport {
+    pub optimizations: Vec<Optimization>,
+}
+
+impl OptimizationReport {
+    pub fn bytes_saved(&self) -> u32 {
+        self.optimizations.iter().map(Optimization::bytes_saved).sum()
+    }
+}
+
+impl fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for optimization in &self.optimizations {
+            writeln!(f, "{optimization}")?;
+        }
+        writeln!(f, "{} optimizations, {} bytes saved", self.optimizations.len(), self.bytes_saved())
+    }
+}
+
+/// Replaces `len` instructions with `replacement`. The replacement takes over
+/// the label of the first replaced instruction.
+struct Rewrite {
+    kind: OptimizationKind,
+    len: usize,
+    replacement: Vec<AssemblerInstruction>,
+}
+
+type Rule = fn(&[AssemblerInstruction], usize) -> Option<Rewrite>;
+
+const RULES: [Rule; 4] = [redundant_load, fold_constants, jump_to_next, compare_and_branch];
+
+/// Runs the peephole optimizer over a program whose scopes are resolved.
+///
+/// Pseudo-instructions are lowered first so their expansions can be optimized
+/// too. Rules only look at straight-line code: no instruction but the first of
+/// a rewritten window may carry a label, so nothing can jump into the middle
+/// of it. Labels are kept on instructions rather than offsets, so they stay
+/// correct once the program is laid out again. Jumps by an offset or to a
+/// number would not, so programs with such jumps are left alone. Registers
+/// keep the values they would have had, except for the scratch register,
+/// which pseudo-instructions clobber anyway.
+pub fn optimize(program: &mut Program) -> OptimizationReport {
+    let mut report = OptimizationReport::default();
+    if has_numeric_jumps(&program.instructions) {
+        return report;
+    }
+    let mut instructions = lower_pseudo_instructions(std::mem::take(&mut program.instructions));
+    let mut index = 0;
+    while index < instructions.len() {
+        match RULES.iter().find_map(|rule| rule(&instructions, index)) {
+            Some(rewrite) => {
+                report.optimizations.push(apply(&mut instructions, index, rewrite));
+                // The rewritten code may complete a window starting a little earlier.
+                index = index.saturating_sub(2);
+            }
+            None => index += 1,
+        }
+    }
+    program.instructions = instructions;
+    report
+}
+
+/// Whether a jump's target is not simply a label: `jmpf` and `jmpb`, `djeq`
+/// to a number, or `jmp` and `jeq` through a register something other than
+/// `load $r @label` writes.
+fn has_numeric_jumps(instructions: &[AssemblerInstruction]) -> bool {
+    let lowered = lower_pseudo_instructions(instructions.to_vec());
+    let loads_label = |instruction: &AssemblerInstruction| {
+        opcode(instruction) == Some(Opcode::LOAD) && label_usage(&instruction.operand2).is_some()
+    };
+    let only_labels_in = |target: u8| {
+        lowered
+            .iter()
+            .filter(|instruction| register_use(instruction).1 == Some(target))
+            .all(loads_label)
+    };
+    lowered.iter().any(|instruction| match opcode(instruction) {
+        Some(Opcode::JMPF | Opcode::JMPB) => true,
+        Some(Opcode::DJEQ) => label_usage(&instruction.operand1).is_none(),
+        Some(Opcode::JMP | Opcode::JEQ) => !register(&instruction.operand1).is_some_and(only_labels_in),
+        _ => false,
+    })
+}
+
+fn lower_pseudo_instructions(instructions: Vec<AssemblerInstruction>) -> Vec<AssemblerInstruction> {
+    let mut lowered = vec![];
+    for instruction in instructions {
+        match instruction.expansion() {
+            Some(Ok(mut expansion)) if !expansion.is_empty() => {
+                expansion[0].label = instruction.label.clone();
+                lowered.append(&mut expansion);
+            }
+            // Invalid pseudo-instructions are left for encoding to report.
+            _ => lowered.push(instruction),
+        }
+    }
+    lowered
+}
+
+fn apply(instructions: &mut Vec<AssemblerInstruction>, index: usize, mut rewrite: Rewrite) -> Optimization {
+    let replaced: Vec<AssemblerInstruction> = instructions
+        .splice(index..index + rewrite.len, [])
+        .collect();
+    if let (Some(first), Some(label)) = (rewrite.replacement.first_mut(), &replaced[0].label) {
+        first.label = Some(label.clone());
+    }
+    let optimization = Optimization {
+        location: replaced[0].location.clone(),
+        kind: rewrite.kind,
+        before: replaced.iter().map(|i| i.to_string()).collect(),
+        after: rewrite.replacement.iter().map(|i| i.to_string()).collect(),
+    };
+    instructions.splice(index..index, rewrite.replacement);
+    optimization
+}
+
+/// `load $r X` followed by `load $r Y` only needs the second load.
+fn redundant_load(instructions: &[AssemblerInstruction], index: usize) -> Option<Rewrite> {
+    let [first, second] = instructions.get(index..index + 2)? else {
+        return None;
+    };
+    let target = register(&first.operand1)?;
+    let is_redundant = opcode(first) == Some(Opcode::LOAD)
+        && opcode(second) == Some(Opcode::LOAD)
+        && register(&second.operand1) == Some(target)
+        && (first.label.is_none() || second.label.is_none());
+    is_redundant.then(|| Rewrite {
+        kind: OptimizationKind::RedundantLoad,
+        len: 2,
+        replacement: vec![second.clone()],
+    })
+}
+
+/// `load $a #x`, `load $b #y`, `add $a $b $d` becomes `load $d #(x + y)` when
+/// `$a` and `$b` are overwritten before being read again. `sub` and `mul` are
+/// folded the same way, as is `load $a #x`, `add $a $a $d`.
+fn fold_constants(instructions: &[AssemblerInstruction], index: usize) -> Option<Rewrite> {
+    [3, 2].into_iter().find_map(|len| {
+        let window = instructions.get(index..index + len)?;
+        let (loads, arithmetic) = window.split_at(len - 1);
+        let arithmetic = &arithmetic[0];
+        let code = opcode(arithmetic).filter(|code| matches!(code, Opcode::ADD | Opcode::SUB | Opcode::MUL))?;
+        let lhs = register(&arithmetic.operand1)?;
+        let rhs = register(&arithmetic.operand2)?;
+        let dest = register(&arithmetic.operand3)?;
+        let known = loads.iter().map(constant_load).collect::<Option<Vec<(u8, i64)>>>()?;
+        let value_of = |r: u8| known.iter().find(|(loaded, _)| *loaded == r).map(|(_, value)| *value);
+        let every_load_used = known.iter().all(|(r, _)| *r == lhs || *r == rhs);
+        let distinct = known.first().map(|(r, _)| r) != known.get(1).map(|(r, _)| r);
+        if !every_load_used || !distinct || !unlabeled(&window[1..]) {
+            return None;
+        }
+        let (x, y) = (value_of(lhs)?, value_of(rhs)?);
+        // Literals are only range checked when encoded, so they can be huge.
+        let value = match code {
+            Opcode::ADD => x.checked_add(y),
+            Opcode::SUB => x.checked_sub(y),
+            _ => x.checked_mul(y),
+        }?;
+        let fits = (0..=u16::MAX as i64).contains(&value);
+        let end = index + len - 1;
+        let dead = known.iter().all(|(r, _)| *r == dest || is_dead_after(instructions, end, *r));
+        (fits && dead).then(|| Rewrite {
+            kind: OptimizationKind::ConstantFold,
+            len,
+            replacement: vec![real(
+                Opcode::LOAD,
+                vec![register_token(dest), Token::Number { value }],
+                &window[0].location,
+            )],
+        })
+    })
+}
+
+/// Removes `djeq @next`, and `jmp $r` or `jeq $r` right after `load $r @next`.
+/// The load goes too when it only fed the scratch register.
+fn jump_to_next(instructions: &[AssemblerInstruction], index: usize) -> Option<Rewrite> {
+    let first = instructions.get(index)?;
+    if opcode(first) == Some(Opcode::DJEQ) && first.label.is_none() {
+        let target = label_usage(&first.operand1)?;
+        return labels_next(instructions, index + 1, target).then(|| Rewrite {
+            kind: OptimizationKind::JumpToNext,
+            len: 1,
+            replacement: vec![],
+        });
+    }
+    let jump = instructions.get(index + 1)?;
+    let target = label_usage(&first.operand2)?;
+    let through = register(&first.operand1)?;
+    let is_jump_to_next = opcode(first) == Some(Opcode::LOAD)
+        && matches!(opcode(jump), Some(Opcode::JMP | Opcode::JEQ))
+        && register(&jump.operand1) == Some(through)
+        && jump.label.is_none()
+        && labels_next(instructions, index + 2, target);
+    if !is_jump_to_next {
+        return None;
+    }
+    let replacement = match through == SCRATCH_REGISTER && first.label.is_none() {
+        true => vec![],
+        false => vec![first.clone()],
+    };
+    Some(Rewrite {
+        kind: OptimizationKind::JumpToNext,
+        len: 2,
+        replacement,
+    })
+}
+
+/// `load $31 @l`, `eq $a $b`, `jeq $31` (as `beq` expands) becomes
+/// `eq $a $b`, `djeq @l`. A `djeq` after a comparison that is always false,
+/// such as `neq $a $a`, is removed.
+fn compare_and_branch(instructions: &[AssemblerInstruction], index: usize) -> Option<Rewrite> {
+    let first = instructions.get(index)?;
+    if let (Some(code), Some(branch)) = (comparison(first), instructions.get(index + 1)) {
+        let never_taken = matches!(code, Opcode::NEQ | Opcode::GT | Opcode::LT)
+            && register(&first.operand1).is_some()
+            && register(&first.operand1) == register(&first.operand2);
+        if never_taken && opcode(branch) == Some(Opcode::DJEQ) && branch.label.is_none() {
+            return Some(Rewrite {
+                kind: OptimizationKind::CompareAndBranch,
+                len: 2,
+                replacement: vec![first.clone()],
+            });
+        }
+    }
+    let [load, compare, jump] = instructions.get(index..index + 3)? else {
+        return None;
+    };
+    let target = label_usage(&load.operand2)?;
+    let scratch = Some(SCRATCH_REGISTER);
+    let is_branch = opcode(load) == Some(Opcode::LOAD)
+        && register(&load.operand1) == scratch
+        && comparison(compare).is_some()
+        && register(&compare.operand1) != scratch
+        && register(&compare.operand2) != scratch
+        && opcode(jump) == Some(Opcode::JEQ)
+        && register(&jump.operand1) == scratch
+        && compare.label.is_none()
+        && jump.label.is_none();
+    is_branch.then(|| {
+        let branch = real(
+            Opcode::DJEQ,
+            vec![Token::LabelUsage { name: target.to_string() }],
+            &jump.location,
+        );
+        Rewrite {
+            kind: OptimizationKind::CompareAndBranch,
+            len: 3,
+            replacement: vec![compare.clone(), branch],
+        }
+    })
+}
+
+fn real(code: Opcode, operands: Vec<Token>, location: &SourceLocation) -> AssemblerInstruction {
+    let mut operands = operands.into_iter();
+    AssemblerInstruction {
+        opcode: Some(Token::Op { code }),
+        label: None,
+        directive: None,
+        operand1: operands.next(),
+        operand2: operands.next(),
+        operand3: operands.next(),
+        location: location.clone(),
+    }
+}
+
+fn opcode(instruction: &AssemblerInstruction) -> Option<Opcode> {
+    match (&instruction.opcode, &instruction.directive) {
+        (Some(Token::Op { code }), None) => Some(*code),
+        _ => None,
+    }
+}
+
+fn comparison(instruction: &AssemblerInstruction) -> Option<Opcode> {
+    opcode(instruction).filter(|code| {
+        matches!(
+            code,
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GE | Opcode::LE
+        )
+    })
+}
+
+fn register(token: &Option<Token>) -> Option<u8> {
+    match token {
+        Some(Token::Register { register_number }) => Some(*register_number),
+        _ => None,
+    }
+}
+
+fn register_token(register_number: u8) -> Token {
+    Token::Register { register_number }
+}
+
+fn label_usage(token: &Option<Token>) -> Option<&str> {
+    match token {
+        Some(Token::LabelUsage { name }) => Some(name),
+        _ => None,
+    }
+}
+
+/// The register and value of `load $r #value`.
+fn constant_load(instruction: &AssemblerInstruction) -> Option<(u8, i64)> {
+    match (opcode(instruction)?, register(&instruction.operand1)?, &instruction.operand2) {
+        (Opcode::LOAD, r, Some(Token::Number { value })) if (0..=u16::MAX as i64).contains(value) => Some((r, *value)),
+        _ => None,
+    }
+}
+
+fn unlabeled(instructions: &[AssemblerInstruction]) -> bool {
+    instructions.iter().all(|i| i.label.is_none())
+}
+
+fn is_label_only(instruction: &AssemblerInstruction) -> bool {
+    instruction.opcode.is_none() && instruction.directive.is_none()
+}
+
+/// Whether `name` labels the instruction at `index`, possibly through label-only
+/// lines in between.
+fn labels_next(instructions: &[AssemblerInstruction], index: usize, name: &str) -> bool {
+    for instruction in &instructions[index.min(instructions.len())..] {
+        if instruction.label_name().as_deref() == Some(name) {
+            return true;
+        }
+        if !is_label_only(instruction) {
+            return false;
+        }
+    }
+    false
+}
+
+/// Registers an instruction reads and the one it writes.
+fn register_use(instruction: &AssemblerInstruction) -> (Vec<u8>, Option<u8>) {
+    let operands = [&instruction.operand1, &instruction.operand2, &instruction.operand3].map(register);
+    match opcode(instruction) {
+        Some(Opcode::LOAD) => (vec![], operands[0]),
+        Some(Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV) => {
+            (operands[..2].iter().flatten().copied().collect(), operands[2])
+        }
+        Some(Opcode::INC | Opcode::DEC) => (operands[..1].iter().flatten().copied().collect(), operands[0]),
+        _ => (operands.iter().flatten().copied().collect(), None),
+    }
+}
+
+/// Whether the value `register` holds after the instruction at `index` is
+/// overwritten before anything can read it. Control flow, data and the end of
+/// the program all count as reads, since registers stay visible to the VM.
+fn is_dead_after(instructions: &[AssemblerInstruction], index: usize, register: u8) -> bool {
+    for instruction in &instructions[index + 1..] {
+        if is_label_only(instruction) {
+            continue;
+        }
+        let straight_line = !matches!(
+            opcode(instruction),
+            None | Some(
+                Opcode::HLT
+                    | Opcode::JMP
+                    | Opcode::JMPF
+                    | Opcode::JMPB
+                    | Opcode::JEQ
+                    | Opcode::DJEQ
+                    | Opcode::ILLEGAL
+            )
+        );
+        let (reads, writes) = register_use(instruction);
+        if !straight_line || reads.contains(&register) {
+            return false;
+        }
+        if writes == Some(register) {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::syntax::SyntaxTree;
+
+    fn optimized(source: &str) -> (Vec<String>, Vec<OptimizationKind>) {
+        let mut program = SyntaxTree::parse(source).to_program(&SourceLocation::new(1, 1)).unwrap();
+        let report = optimize(&mut program);
+        let instructions = program.instructions.iter().map(|i| i.to_string()).collect();
+        (instructions, report.optimizations.iter().map(|o| o.kind).collect())
+    }
+
+    #[test]
+    fn test_removes_redundant_loads() {
+        let (instructions, kinds) = optimized("start: load $1 #1\nload $1 #2\nload $2 #3\nadd $1 $2 $3\nhlt");
+        assert_eq!(instructions, ["start: load $1 #2", "load $2 #3", "add $1 $2 $3", "hlt"]);
+        assert_eq!(kinds, [OptimizationKind::RedundantLoad]);
+    }
+
+    #[test]
+    fn test_folds_constants_into_dead_registers() {
+        let (instructions, kinds) = optimized("load $1 #2\nload $2 #3\nadd $1 $2 $1\nload $2 #0\nhlt");
+        assert_eq!(instructions, ["load $1 #5", "load $2 #0", "hlt"]);
+        assert_eq!(kinds, [OptimizationKind::ConstantFold]);
+        let (instructions, kinds) = optimized("load $1 #2\nload $2 #3\nmul $1 $2 $3\nhlt");
+        assert_eq!(instructions.len(), 4);
+        assert_eq!(kinds, []);
+        let (instructions, kinds) = optimized("load $1 #4000000000\nload $2 #4000000000\nmul $1 $2 $3\nload $1 #0\nload $2 #0");
+        assert_eq!(instructions.len(), 5);
+        assert_eq!(kinds, []);
+        let (instructions, _) = optimized("load $1 #65535\nload $2 #0\nadd $1 $2 $1\nload $2 #0");
+        assert_eq!(instructions, ["load $1 #65535", "load $2 #0"]);
+        let (instructions, kinds) = optimized("load $1 #65535\nload $2 #1\nadd $1 $2 $1\nload $2 #0");
+        assert_eq!(instructions.len(), 4);
+        assert_eq!(kinds, []);
+    }
+
+    #[test]
+    fn test_removes_jumps_to_the_next_instruction() {
+        let (instructions, kinds) = optimized("jmp @next\nnext:\ndjeq @last\nlast: load $1 @end\njmp $1\nend: hlt");
+        assert_eq!(instructions, ["next:", "last: load $1 @end", "end: hlt"]);
+        assert_eq!(kinds, [OptimizationKind::JumpToNext; 3]);
+    }
+
+    #[test]
+    fn test_simplifies_compare_and_branch() {
+        let (instructions, kinds) = optimized("loop: beq $1 $2 @loop\nneq $3 $3\ndjeq @loop\nhlt");
+        assert_eq!(instructions, ["loop: eq $1 $2", "djeq @loop", "neq $3 $3", "hlt"]);
+        assert_eq!(kinds, [OptimizationKind::CompareAndBranch; 2]);
+    }
+
+    #[test]
+    fn test_leaves_numeric_jumps_alone() {
+        for source in [
+            "load $1 #1\nload $1 #2\nload $2 #8\njmpf $2\nhlt",
+            "load $1 #1\nload $1 #2\nload $2 #16\njmp $2\nhlt",
+            "load $1 #1\nload $1 #2\nload $2 @end\ninc $2\njeq $2\nend: hlt",
+            "load $1 #1\nload $1 #2\ndjeq #12\nhlt",
+        ] {
+            let (instructions, kinds) = optimized(source);
+            assert_eq!(instructions.len(), source.lines().count(), "{source}");
+            assert_eq!(kinds, [], "{source}");
+        }
+        let (_, kinds) = optimized("load $1 #1\nload $1 #2\nload $2 @end\njmp $2\nend: hlt");
+        assert_eq!(kinds, [OptimizationKind::RedundantLoad, OptimizationKind::JumpToNext]);
+    }
+
+    #[test]
+    fn test_keeps_labelled_instructions() {
+        let source = "load $1 #1\ntarget: load $1 #2\nload $2 #3\nagain: add $1 $2 $3\nhlt";
+        let (instructions, kinds) = optimized(source);
+        assert_eq!(instructions, ["target: load $1 #2", "load $2 #3", "again: add $1 $2 $3", "hlt"]);
+        assert_eq!(kinds, [OptimizationKind::RedundantLoad]);
+    }
+}
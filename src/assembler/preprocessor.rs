@@ -0,0 +1,899 @@
+use crate::assembler::assembler_errors::{AssemblerError, AssemblerErrorKind};
+use crate::assembler::expression_parser::expression;
+use crate::assembler::instruction_parser::AssemblerInstruction;
+use crate::assembler::{MacroExpansion, SourceFile, SourceLocation, Symbol, SymbolTable, SymbolType};
+use crate::assembler::pseudo_instructions::is_pseudo_instruction;
+use crate::assembler::syntax::SyntaxTree;
+use crate::instruction::Opcode;
+use nom::types::CompleteStr;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Deepest chain of macro calls expanded before a macro is considered to
+/// recurse forever.
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// Most macro expansions done for one program, so that macros calling each
+/// other several times cannot blow up exponentially.
+pub const MAX_EXPANSIONS: usize = 100_000;
+
+/// One line of source after preprocessing, along with where it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub text: String,
+    pub location: SourceLocation,
+}
+
+impl SourceLine {
+    pub fn new(text: String, location: SourceLocation) -> SourceLine {
+        SourceLine { text, location }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    name: String,
+    parameters: Vec<String>,
+    body: Vec<SourceLine>,
+    labels: Vec<String>,
+}
+
+/// An `.if` block being preprocessed.
+#[derive(Debug)]
+struct Conditional {
+    location: SourceLocation,
+    /// Whether the lines around the block are assembled at all.
+    enclosing_active: bool,
+    /// Whether one of the branches has already been assembled.
+    taken: bool,
+    active: bool,
+    seen_else: bool,
+}
+
+/// Runs over the source before it is parsed, splicing in `.include "file"`,
+/// expanding `.macro NAME arg1, arg2 ... .endm` definitions and dropping the
+/// branches of `.if`/`.ifdef`/`.ifndef` ... `.elif` ... `.else` ... `.endif`
+/// blocks whose condition does not hold.
+///
+/// Included files are looked up next to the including file (or the working
+/// directory for source passed as a string), then in each include directory.
+/// Macro parameters are referenced as `\arg` in the body and `\@` gives a
+/// number unique to each expansion. Labels declared inside a macro body are
+/// turned into local labels unique to each expansion, so a macro can be called
+/// more than once without opening a new label scope.
+///
+/// Conditions are evaluated before any label is known, so they can only use
+/// defines and `.equ`/`.set` constants assigned further up. Each macro body
+/// and included file must close the blocks it opens.
+#[derive(Debug, Default)]
+pub struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// Set once macros expanded too deep or too often. Further calls are
+    /// dropped so the error is reported only once.
+    gave_up: bool,
+    include_dirs: Vec<PathBuf>,
+    include_stack: Vec<PathBuf>,
+    /// Set when `.include` must not read files.
+    include_forbidden: bool,
+    symbols: SymbolTable,
+    conditionals: Vec<Conditional>,
+    /// Number of enclosing blocks the current macro body or file cannot close.
+    conditional_floor: usize,
+    errors: Vec<AssemblerError>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Preprocessor {
+        Preprocessor::default()
+    }
+
+    pub fn with_include_dirs(include_dirs: Vec<PathBuf>) -> Preprocessor {
+        Preprocessor {
+            include_dirs,
+            ..Preprocessor::default()
+        }
+    }
+
+    /// Reports `.include` as an error instead of reading the file.
+    pub fn forbid_include(&mut self) {
+        self.include_forbidden = true;
+    }
+
+    /// Defines a symbol visible to `.if` and `.ifdef`, as if by `.equ`.
+    pub fn define(&mut self, name: &str, value: i64) {
+        self.symbols.add_symbol(Symbol::with_value(name.to_string(), SymbolType::Constant, value));
+    }
+
+    pub fn process(&mut self, source: &str) -> Result<Vec<SourceLine>, Vec<AssemblerError>> {
+        let output = self.process_lines(source_lines(source, None));
+        self.close_conditionals(0);
+        self.finish(output)
+    }
+
+    pub fn process_file(&mut self, path: &Path) -> Result<Vec<SourceLine>, Vec<AssemblerError>> {
+        let output = self.include(path, None);
+        self.finish(output)
+    }
+
+    fn finish(&mut self, output: Vec<SourceLine>) -> Result<Vec<SourceLine>, Vec<AssemblerError>> {
+        if self.errors.is_empty() {
+            Ok(output)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn process_lines(&mut self, lines: Vec<SourceLine>) -> Vec<SourceLine> {
+        let mut output = vec![];
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            if self.conditional(&line) {
+                continue;
+            }
+            match directive_name(&line.text).as_deref() {
+                Some("macro") => self.define_macro(line, &mut lines),
+                Some("endm") => self.error(&line.location, AssemblerErrorKind::UnexpectedEndm),
+                Some("include") => output.append(&mut self.include_directive(&line)),
+                _ => self.expand_line(line, 0, &mut output),
+            }
+        }
+        output
+    }
+
+    fn include_directive(&mut self, line: &SourceLine) -> Vec<SourceLine> {
+        let argument = line.text.trim()[".include".len()..].trim();
+        let path = match argument.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+            Some(path) if !path.is_empty() => path,
+            _ => {
+                let argument = argument.to_string();
+                self.error(&line.location, AssemblerErrorKind::InvalidInclude { argument });
+                return vec![];
+            }
+        };
+        if self.include_forbidden {
+            let path = path.to_string();
+            self.error(&line.location, AssemblerErrorKind::IncludeNotAllowed { path });
+            return vec![];
+        }
+        match self.resolve(Path::new(path), &line.location) {
+            Some(resolved) => self.include(&resolved, Some(&line.location)),
+            None => {
+                let path = path.to_string();
+                self.error(&line.location, AssemblerErrorKind::IncludeNotFound { path });
+                vec![]
+            }
+        }
+    }
+
+    /// Finds an included file next to the file including it, then in the
+    /// include directories.
+    fn resolve(&self, path: &Path, from: &SourceLocation) -> Option<PathBuf> {
+        if path.is_absolute() {
+            return Some(path.to_path_buf()).filter(|path| path.is_file());
+        }
+        let base = match &from.file {
+            Some(file) => file.path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            None => PathBuf::new(),
+        };
+        std::iter::once(base)
+            .chain(self.include_dirs.iter().cloned())
+            .map(|dir| dir.join(path))
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Reads and preprocesses a file, refusing to include a file that is
+    /// already being processed further up the include chain.
+    fn include(&mut self, path: &Path, from: Option<&SourceLocation>) -> Vec<SourceLine> {
+        let file = Arc::new(SourceFile {
+            path: path.to_path_buf(),
+            included_from: from.cloned(),
+        });
+        let file_location = SourceLocation {
+            file: Some(file.clone()),
+            ..SourceLocation::default()
+        };
+        let location = from.unwrap_or(&file_location);
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if self.include_stack.contains(&canonical) {
+            let path = path.display().to_string();
+            self.error(location, AssemblerErrorKind::IncludeCycle { path });
+            return vec![];
+        }
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                let kind = AssemblerErrorKind::UnreadableFile {
+                    path: path.display().to_string(),
+                    reason: e.to_string(),
+                };
+                self.error(location, kind);
+                return vec![];
+            }
+        };
+        self.include_stack.push(canonical);
+        let floor = std::mem::replace(&mut self.conditional_floor, self.conditionals.len());
+        let output = self.process_lines(source_lines(&source, Some(file)));
+        self.close_conditionals(floor);
+        self.include_stack.pop();
+        output
+    }
+
+    fn define_macro(&mut self, header: SourceLine, lines: &mut impl Iterator<Item = SourceLine>) {
+        let mut body = vec![];
+        let mut terminated = false;
+        for line in lines.by_ref() {
+            match directive_name(&line.text).as_deref() {
+                Some("endm") => {
+                    terminated = true;
+                    break;
+                }
+                Some("macro") => self.error(&line.location, AssemblerErrorKind::NestedMacroDefinition),
+                _ => body.push(line),
+            }
+        }
+        let definition = header.text.trim_start()[".macro".len()..].trim();
+        let (name, parameters) = match definition.split_once(char::is_whitespace) {
+            Some((name, parameters)) => (name, split_arguments(parameters)),
+            None => (definition, vec![]),
+        };
+        if !terminated {
+            let name = name.to_string();
+            self.error(&header.location, AssemblerErrorKind::UnterminatedMacro { name });
+            return;
+        }
+        if !is_identifier(name) || !parameters.iter().all(|p| is_identifier(p)) {
+            let definition = definition.to_string();
+            self.error(&header.location, AssemblerErrorKind::InvalidMacroDefinition { definition });
+            return;
+        }
+        let key = name.to_lowercase();
+        if self.macros.contains_key(&key) || Opcode::from(CompleteStr(name)) != Opcode::ILLEGAL || is_pseudo_instruction(name) {
+            let name = name.to_string();
+            self.error(&header.location, AssemblerErrorKind::SymbolAlreadyDefined { name });
+            return;
+        }
+        let labels = body.iter().filter_map(|line| declared_label(&line.text)).collect();
+        let name = name.to_string();
+        self.macros.insert(key, Macro { name, parameters, body, labels });
+    }
+
+    /// Appends `line` to `output`, replacing macro calls with their bodies.
+    fn expand_line(&mut self, line: SourceLine, depth: usize, output: &mut Vec<SourceLine>) {
+        let (label, definition, arguments) = match self.macro_call(&line.text) {
+            Some(call) => call,
+            None => {
+                self.record_constant(&line);
+                output.push(line);
+                return;
+            }
+        };
+        let name = definition.name.clone();
+        if self.gave_up {
+            return;
+        }
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.gave_up = true;
+            self.error(&line.location, AssemblerErrorKind::MacroRecursionLimit { name });
+            return;
+        }
+        if self.expansions >= MAX_EXPANSIONS {
+            self.gave_up = true;
+            self.error(&line.location, AssemblerErrorKind::MacroExpansionLimit { name });
+            return;
+        }
+        if arguments.len() != definition.parameters.len() {
+            let kind = AssemblerErrorKind::MacroArgumentCount {
+                name,
+                expected: definition.parameters.len(),
+                found: arguments.len(),
+            };
+            self.error(&line.location, kind);
+            return;
+        }
+        if let Some(label) = label {
+            output.push(SourceLine::new(format!("{label}:"), line.location.clone()));
+        }
+        self.expansions += 1;
+        let expansion = Arc::new(MacroExpansion {
+            name,
+            call_site: line.location,
+        });
+        let floor = std::mem::replace(&mut self.conditional_floor, self.conditionals.len());
+        for body_line in &definition.body {
+            let mut location = body_line.location.clone();
+            location.expansion = Some(expansion.clone());
+            let text = rename_labels(&body_line.text, &definition.labels, self.expansions);
+            match substitute_parameters(&text, &definition.parameters, &arguments, self.expansions) {
+                Ok(text) => {
+                    let line = SourceLine::new(text, location);
+                    if !self.conditional(&line) {
+                        self.expand_line(line, depth + 1, output);
+                    }
+                }
+                Err(kind) => self.error(&location, kind),
+            }
+        }
+        self.close_conditionals(floor);
+    }
+
+    fn is_active(&self) -> bool {
+        self.conditionals.last().is_none_or(|conditional| conditional.active)
+    }
+
+    /// Handles a conditional directive. Returns true if the line was consumed,
+    /// either because it is such a directive or because it sits in a branch
+    /// that is not assembled.
+    fn conditional(&mut self, line: &SourceLine) -> bool {
+        let name = match directive_name(&line.text) {
+            Some(name) => name,
+            None => return !self.is_active(),
+        };
+        let argument = line.text.trim_start()[name.len() + 1..].trim();
+        let location = &line.location;
+        match name.as_str() {
+            "if" | "ifdef" | "ifndef" => {
+                let enclosing_active = self.is_active();
+                let active = enclosing_active && self.condition(&name, argument, location);
+                self.conditionals.push(Conditional {
+                    location: location.clone(),
+                    enclosing_active,
+                    taken: active,
+                    active,
+                    seen_else: false,
+                });
+            }
+            "elif" | "else" | "endif" if self.conditionals.len() <= self.conditional_floor => {
+                self.error(location, AssemblerErrorKind::UnexpectedConditional { directive: name });
+            }
+            "elif" | "else" if self.conditionals.last().is_some_and(|conditional| conditional.seen_else) => {
+                self.error(location, AssemblerErrorKind::ConditionalAfterElse { directive: name });
+            }
+            "elif" => {
+                let conditional = &self.conditionals[self.conditionals.len() - 1];
+                let enabled = conditional.enclosing_active && !conditional.taken;
+                let active = enabled && self.condition("if", argument, location);
+                if let Some(conditional) = self.conditionals.last_mut() {
+                    conditional.active = active;
+                    conditional.taken |= active;
+                }
+            }
+            "else" => {
+                if let Some(conditional) = self.conditionals.last_mut() {
+                    conditional.active = conditional.enclosing_active && !conditional.taken;
+                    conditional.taken = true;
+                    conditional.seen_else = true;
+                }
+            }
+            "endif" => {
+                self.conditionals.pop();
+            }
+            _ => return !self.is_active(),
+        }
+        true
+    }
+
+    /// Evaluates the argument of `.if`, `.ifdef` or `.ifndef`.
+    fn condition(&mut self, directive: &str, argument: &str, location: &SourceLocation) -> bool {
+        if directive != "if" {
+            if !is_label_name(argument) {
+                let condition = argument.to_string();
+                self.error(location, AssemblerErrorKind::InvalidCondition { condition });
+                return false;
+            }
+            return self.symbols.symbol(argument).is_some() == (directive == "ifdef");
+        }
+        match expression(CompleteStr(argument)) {
+            Ok((rest, condition)) if rest.trim().is_empty() => match condition.evaluate(&self.symbols) {
+                Ok(value) => value != 0,
+                Err(kind) => {
+                    self.error(location, kind);
+                    false
+                }
+            },
+            _ => {
+                let condition = argument.to_string();
+                self.error(location, AssemblerErrorKind::InvalidCondition { condition });
+                false
+            }
+        }
+    }
+
+    /// Reports the blocks opened since `floor` that were never closed, then
+    /// restores the floor of the enclosing macro body or file.
+    fn close_conditionals(&mut self, floor: usize) {
+        while self.conditionals.len() > self.conditional_floor {
+            if let Some(conditional) = self.conditionals.pop() {
+                self.error(&conditional.location, AssemblerErrorKind::UnterminatedConditional);
+            }
+        }
+        self.conditional_floor = floor;
+    }
+
+    /// Remembers the value of a `.equ` or `.set` line so later conditions can
+    /// use it. Values that cannot be computed yet are left to the assembler.
+    fn record_constant(&mut self, line: &SourceLine) {
+        if !matches!(directive_name(&line.text).as_deref(), Some("equ" | "set")) {
+            return;
+        }
+        let program = match SyntaxTree::parse(&line.text).to_program(&line.location) {
+            Ok(program) => program,
+            Err(_) => return,
+        };
+        for instruction in &program.instructions {
+            if let Some((name, symbol_type, value)) = instruction.constant_definition() {
+                if let Ok(value) = AssemblerInstruction::operand_value(value, &self.symbols) {
+                    if !self.symbols.set_symbol_value(name, value) {
+                        self.symbols.add_symbol(Symbol::with_value(name.to_string(), symbol_type, value));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Splits a line into its label, macro and arguments if it calls a macro.
+    fn macro_call(&self, text: &str) -> Option<(Option<String>, Macro, Vec<String>)> {
+        let mut rest = text.trim();
+        let label = declared_label(rest);
+        if let Some(label) = &label {
+            rest = rest[label.len() + 1..].trim_start();
+        }
+        let (name, arguments) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let definition = self.macros.get(&name.to_lowercase())?;
+        Some((label, definition.clone(), split_arguments(arguments)))
+    }
+
+    fn error(&mut self, location: &SourceLocation, kind: AssemblerErrorKind) {
+        self.errors.push(AssemblerError::new(location.clone(), kind));
+    }
+}
+
+fn source_lines(source: &str, file: Option<Arc<SourceFile>>) -> Vec<SourceLine> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| {
+            let location = SourceLocation {
+                file: file.clone(),
+                ..SourceLocation::new(i + 1, 1)
+            };
+            SourceLine::new(split_comment(text).0.trim_end().to_string(), location)
+        })
+        .collect()
+}
+
+/// Splits a line into its code and the `;` comment ending it, if any. A `;`
+/// in a string or character literal does not start a comment.
+pub fn split_comment(text: &str) -> (&str, Option<&str>) {
+    let (mut in_char, mut in_string, mut escaped) = (false, false, false);
+    for (index, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_char || in_string => escaped = true,
+            '\'' if !in_string => in_char = !in_char,
+            '"' if !in_char => in_string = !in_string,
+            ';' if !in_char && !in_string => return (&text[..index], Some(&text[index..])),
+            _ => {}
+        }
+    }
+    (text, None)
+}
+
+/// Returns the lowercased name of the directive starting the line, if any.
+fn directive_name(text: &str) -> Option<String> {
+    let name = text.trim_start().strip_prefix('.')?;
+    let end = name.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(name.len());
+    Some(name[..end].to_lowercase())
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn is_label_char(c: char) -> bool {
+    is_identifier_char(c) || c == '.'
+}
+
+fn is_label_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(is_label_char)
+        && text != "."
+}
+
+/// Returns the label declared at the start of the line, if any.
+fn declared_label(text: &str) -> Option<String> {
+    let text = text.trim_start();
+    let (name, _) = text.split_once(':')?;
+    if is_label_name(name) {
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+/// Splits macro arguments or parameters. Arguments are separated by commas
+/// when there is one outside parentheses, and by whitespace otherwise.
+fn split_arguments(text: &str) -> Vec<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return vec![];
+    }
+    let mut depth = 0;
+    let mut in_char = false;
+    let mut previous = ' ';
+    let top_level_comma = text.chars().any(|c| {
+        let top_level = depth == 0 && !in_char;
+        match c {
+            '\'' if previous != '\\' => in_char = !in_char,
+            '(' if !in_char => depth += 1,
+            ')' if !in_char => depth -= 1,
+            _ => {}
+        }
+        previous = c;
+        top_level && c == ','
+    });
+    let mut arguments = vec![];
+    let mut current = String::new();
+    let (mut depth, mut in_char, mut previous) = (0, false, ' ');
+    for c in text.chars() {
+        let separator = if top_level_comma { c == ',' } else { c.is_whitespace() };
+        if separator && depth == 0 && !in_char {
+            if top_level_comma || !current.is_empty() {
+                arguments.push(current.trim().to_string());
+            }
+            current.clear();
+        } else {
+            match c {
+                '\'' if previous != '\\' => in_char = !in_char,
+                '(' if !in_char => depth += 1,
+                ')' if !in_char => depth -= 1,
+                _ => {}
+            }
+            current.push(c);
+        }
+        previous = c;
+    }
+    if top_level_comma || !current.is_empty() {
+        arguments.push(current.trim().to_string());
+    }
+    arguments
+}
+
+/// Renames the labels declared in a macro body so each expansion gets its own
+/// local copy. The mnemonic or directive of the line is never renamed.
+fn rename_labels(text: &str, labels: &[String], expansion: usize) -> String {
+    if labels.is_empty() {
+        return text.to_string();
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut seen_mnemonic = false;
+    let mut previous = ' ';
+    let mut in_char = false;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let starts_name = (c.is_ascii_alphabetic() || c == '_' || c == '.')
+            && !is_identifier_char(previous)
+            && previous != '\\'
+            && !in_char;
+        if !starts_name {
+            if c == '\'' && previous != '\\' {
+                in_char = !in_char;
+            }
+            result.push(c);
+            previous = c;
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let end = rest.find(|c| !is_label_char(c)).unwrap_or(rest.len());
+        let name = &rest[..end];
+        let is_declaration = rest[end..].starts_with(':');
+        if !seen_mnemonic && !is_declaration {
+            seen_mnemonic = true;
+            result.push_str(name);
+        } else if labels.iter().any(|label| label == name) {
+            result.push_str(&format!(".__{}_{expansion}", name.trim_start_matches('.')));
+        } else {
+            result.push_str(name);
+        }
+        previous = name.chars().last().unwrap_or(' ');
+        rest = &rest[end..];
+    }
+    result
+}
+
+/// Replaces `\parameter` with its argument and `\@` with the expansion number.
+fn substitute_parameters(
+    text: &str,
+    parameters: &[String],
+    arguments: &[String],
+    expansion: usize,
+) -> Result<String, AssemblerErrorKind> {
+    let mut result = String::with_capacity(text.len());
+    let mut in_char = false;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        if in_char || c != '\\' {
+            if c == '\'' {
+                in_char = !in_char;
+            } else if in_char && c == '\\' {
+                // Keep escapes such as '\'' inside character literals intact.
+                if let Some(escaped) = rest.chars().next() {
+                    result.push(c);
+                    result.push(escaped);
+                    rest = &rest[escaped.len_utf8()..];
+                    continue;
+                }
+            }
+            result.push(c);
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix('@') {
+            result.push_str(&expansion.to_string());
+            rest = after;
+            continue;
+        }
+        let end = rest.find(|c| !is_identifier_char(c)).unwrap_or(rest.len());
+        let name = &rest[..end];
+        match parameters.iter().position(|parameter| parameter == name) {
+            Some(index) => result.push_str(&arguments[index]),
+            None => {
+                let name = name.to_string();
+                return Err(AssemblerErrorKind::UndefinedMacroParameter { name });
+            }
+        }
+        rest = &rest[end..];
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Creates an empty directory for a test to write its source files into.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("iasm_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn expand(source: &str) -> Vec<String> {
+        let lines = Preprocessor::new().process(source).unwrap();
+        lines.into_iter().map(|line| line.text).collect()
+    }
+
+    #[test]
+    fn test_conditional_assembly() {
+        let source = ".equ LEVEL 2\n\
+                      .if LEVEL >> 2\nload $1 #3\n\
+                      .elif LEVEL & 2\nload $1 #2\n\
+                      .else\nload $1 #0\n.endif\n\
+                      .ifdef DEBUG\ninc $2\n.endif\n\
+                      .ifndef DEBUG\n.if 1\ndec $2\n.else\ninc $2\n.endif\n.endif";
+        assert_eq!(expand(source), vec![".equ LEVEL 2", "load $1 #2", "dec $2"]);
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.define("DEBUG", 1);
+        let lines = preprocessor.process(source).unwrap();
+        let texts: Vec<String> = lines.into_iter().map(|line| line.text).collect();
+        assert_eq!(texts, vec![".equ LEVEL 2", "load $1 #2", "inc $2"]);
+    }
+
+    #[test]
+    fn test_conditional_in_macro() {
+        let source = ".macro clear reg\n.ifdef FAST\nclr \\reg\n.else\nload \\reg #0\n.endif\n.endm\n\
+                      clear $1\n.set FAST 1\nclear $2";
+        assert_eq!(expand(source), vec!["load $1 #0", ".set FAST 1", "clr $2"]);
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        let errors = Preprocessor::new()
+            .process(".else\n.if 1\n.else\n.elif 1\n.endif\n.if MISSING\n.endif\n.ifdef 1x\n.endif\n.if 1")
+            .unwrap_err();
+        let kinds: Vec<AssemblerErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AssemblerErrorKind::UnexpectedConditional { directive: "else".to_string() },
+                AssemblerErrorKind::ConditionalAfterElse { directive: "elif".to_string() },
+                AssemblerErrorKind::UndefinedSymbol { name: "MISSING".to_string() },
+                AssemblerErrorKind::InvalidCondition { condition: "1x".to_string() },
+                AssemblerErrorKind::UnterminatedConditional,
+            ]
+        );
+        let errors = Preprocessor::new()
+            .process(".macro open\n.if 1\n.endm\n.if 1\nopen\n.endif\n.macro close\n.endif\n.endm\n.if 1\nclose\n.endif")
+            .unwrap_err();
+        let kinds: Vec<AssemblerErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AssemblerErrorKind::UnterminatedConditional,
+                AssemblerErrorKind::UnexpectedConditional { directive: "endif".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_arguments() {
+        assert_eq!(split_arguments("$1, #(2 + 3), @end"), vec!["$1", "#(2 + 3)", "@end"]);
+        assert_eq!(split_arguments("$1  #(2 + 3) @end"), vec!["$1", "#(2 + 3)", "@end"]);
+        assert_eq!(split_arguments("#',', $2"), vec!["#','", "$2"]);
+        assert!(split_arguments("  ").is_empty());
+    }
+
+    #[test]
+    fn test_expand_macro_with_parameters() {
+        let source = ".macro addi dst, value\nload $31 \\value\nadd \\dst $31 \\dst\n.endm\naddi $1, #5\nhlt";
+        assert_eq!(expand(source), vec!["load $31 #5", "add $1 $31 $1", "hlt"]);
+    }
+
+    #[test]
+    fn test_expand_renames_labels_per_expansion() {
+        let source = ".macro spin reg\nloop: inc \\reg\nneq \\reg $0\ndjeq @loop\n.endm\nspin $1\nspin $2";
+        assert_eq!(
+            expand(source),
+            vec![
+                ".__loop_1: inc $1",
+                "neq $1 $0",
+                "djeq @.__loop_1",
+                ".__loop_2: inc $2",
+                "neq $2 $0",
+                "djeq @.__loop_2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_renames_local_labels() {
+        let source = ".macro wait\n.spin: djeq @.spin\n.endm\nmain: wait\ndjeq @main.spin";
+        assert_eq!(expand(source), vec!["main:", ".__spin_1: djeq @.__spin_1", "djeq @main.spin"]);
+    }
+
+    #[test]
+    fn test_expand_nested_macros() {
+        let source = ".macro one r\ninc \\r\n.endm\n.macro two r\none \\r\none \\r\n.endm\nstart: two $3";
+        assert_eq!(expand(source), vec!["start:", "inc $3", "inc $3"]);
+        let lines = Preprocessor::new().process(source).unwrap();
+        let location = &lines[1].location;
+        assert_eq!(location.line, 2);
+        let outer = location.expansion.as_ref().unwrap();
+        assert_eq!(outer.name, "one");
+        assert_eq!(outer.call_site.line, 5);
+        let inner = outer.call_site.expansion.as_ref().unwrap();
+        assert_eq!(inner.name, "two");
+        assert_eq!(inner.call_site.line, 8);
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let errors = Preprocessor::new()
+            .process(".macro forever\nforever\n.endm\nforever\n.endm\n.macro load a\n.endm\n.macro open")
+            .unwrap_err();
+        let kinds: Vec<AssemblerErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AssemblerErrorKind::MacroRecursionLimit {
+                    name: "forever".to_string()
+                },
+                AssemblerErrorKind::UnexpectedEndm,
+                AssemblerErrorKind::SymbolAlreadyDefined {
+                    name: "load".to_string()
+                },
+                AssemblerErrorKind::UnterminatedMacro {
+                    name: "open".to_string()
+                },
+            ]
+        );
+        let errors = Preprocessor::new()
+            .process(".macro pair a, b\nload \\a \\c\n.endm\npair $1\npair $1, #2")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].kind,
+            AssemblerErrorKind::MacroArgumentCount {
+                name: "pair".to_string(),
+                expected: 2,
+                found: 1
+            }
+        );
+        assert_eq!(
+            errors[1].kind,
+            AssemblerErrorKind::UndefinedMacroParameter { name: "c".to_string() }
+        );
+        assert_eq!(errors[1].location.line, 2);
+        assert_eq!(errors[1].location.expansion.as_ref().unwrap().call_site.line, 5);
+    }
+
+    #[test]
+    fn test_macro_expansion_limits() {
+        let errors = Preprocessor::new().process(".macro boom\nboom\nboom\n.endm\nboom").unwrap_err();
+        assert_eq!(
+            errors.into_iter().map(|e| e.kind).collect::<Vec<_>>(),
+            vec![AssemblerErrorKind::MacroRecursionLimit { name: "boom".to_string() }]
+        );
+        let source = ".macro a\nnop\nnop\nnop\nnop\n.endm\n.macro b\na\na\na\na\n.endm\n\
+                      .macro c\nb\nb\nb\nb\n.endm\n.macro d\nc\nc\nc\nc\n.endm\n\
+                      .macro e\nd\nd\nd\nd\n.endm\n.macro f\ne\ne\ne\ne\n.endm\n\
+                      .macro g\nf\nf\nf\nf\n.endm\n.macro h\ng\ng\ng\ng\n.endm\n\
+                      .macro i\nh\nh\nh\nh\n.endm\n.macro j\ni\ni\ni\ni\n.endm\nj";
+        let errors = Preprocessor::new().process(source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind, AssemblerErrorKind::MacroExpansionLimit { .. }), "{errors:?}");
+    }
+
+    #[test]
+    fn test_include_relative_and_search_paths() {
+        let dir = test_dir("include");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/macros.iasm"), ".macro clear r\nload \\r #0\n.endm").unwrap();
+        fs::write(dir.join("src/consts.iasm"), ".equ SIZE 4\n.include \"macros.iasm\"").unwrap();
+        fs::write(dir.join("src/main.iasm"), ".include \"consts.iasm\"\nclear $1\nhlt").unwrap();
+
+        let mut preprocessor = Preprocessor::with_include_dirs(vec![dir.join("lib")]);
+        let lines = preprocessor.process_file(&dir.join("src/main.iasm")).unwrap();
+        let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, vec![".equ SIZE 4", "load $1 #0", "hlt"]);
+        let file = lines[0].location.file.as_ref().unwrap();
+        assert_eq!(file.path, dir.join("src/consts.iasm"));
+        assert_eq!(file.included_from.as_ref().unwrap().line, 1);
+
+        let errors = Preprocessor::new().process_file(&dir.join("src/main.iasm")).unwrap_err();
+        assert_eq!(
+            errors[0].kind,
+            AssemblerErrorKind::IncludeNotFound {
+                path: "macros.iasm".to_string()
+            }
+        );
+        assert_eq!(
+            errors[0].location.to_string(),
+            format!(
+                "{}, line 2, column 1 (included from {}, line 1, column 1)",
+                dir.join("src/consts.iasm").display(),
+                dir.join("src/main.iasm").display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = test_dir("include_cycle");
+        fs::write(dir.join("a.iasm"), "hlt\n.include \"b.iasm\"").unwrap();
+        fs::write(dir.join("b.iasm"), ".include \"a.iasm\"").unwrap();
+        let errors = Preprocessor::new().process_file(&dir.join("a.iasm")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].kind,
+            AssemblerErrorKind::IncludeCycle {
+                path: dir.join("a.iasm").display().to_string()
+            }
+        );
+        assert_eq!(errors[0].location.line, 1);
+
+        let errors = Preprocessor::new().process(".include macros.iasm").unwrap_err();
+        assert_eq!(
+            errors[0].kind,
+            AssemblerErrorKind::InvalidInclude {
+                argument: "macros.iasm".to_string()
+            }
+        );
+
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.forbid_include();
+        let errors = preprocessor.process(".include \"Cargo.toml\"").unwrap_err();
+        assert_eq!(errors[0].kind, AssemblerErrorKind::IncludeNotAllowed { path: "Cargo.toml".to_string() });
+    }
+}
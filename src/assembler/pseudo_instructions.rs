@@ -0,0 +1,199 @@
+use crate::assembler::assembler_errors::AssemblerErrorKind;
+use crate::assembler::instruction_parser::AssemblerInstruction;
+use crate::assembler::Token;
+use crate::instruction::Opcode;
+
+/// Register the expansions use for intermediate values. Programs using
+/// pseudo-instructions should treat it as clobbered.
+pub const SCRATCH_REGISTER: u8 = 31;
+
+/// Mnemonics that exist only in the assembler. `jmp` is also a pseudo-instruction
+/// when its operand is an address rather than a register.
+const PSEUDO_INSTRUCTIONS: [&str; 5] = ["mov", "nop", "clr", "beq", "not"];
+
+pub fn is_pseudo_instruction(name: &str) -> bool {
+    PSEUDO_INSTRUCTIONS.contains(&name.to_lowercase().as_str())
+}
+
+fn usage(name: &str) -> &'static str {
+    match name {
+        "mov" => "mov $dst $src` or `mov $dst #value",
+        "nop" => "nop",
+        "clr" => "clr $reg",
+        "beq" => "beq $a $b @label",
+        "not" => "not $reg",
+        _ => "jmp $reg` or `jmp @label",
+    }
+}
+
+fn register(number: u8) -> Token {
+    Token::Register { register_number: number }
+}
+
+fn is_value(token: &Token) -> bool {
+    matches!(token, Token::Number { .. } | Token::LabelUsage { .. } | Token::Expression { .. })
+}
+
+fn is_scratch(token: &Option<Token>) -> bool {
+    matches!(token, Some(Token::Register { register_number: SCRATCH_REGISTER }))
+}
+
+/// Whether the expansion of `name` overwrites the scratch register before
+/// reading one of these operands.
+fn reads_scratch(name: &str, operands: (&Option<Token>, &Option<Token>, &Option<Token>)) -> bool {
+    match name {
+        "not" => is_scratch(operands.0),
+        "mov" => is_scratch(operands.1),
+        "beq" => is_scratch(operands.0) || is_scratch(operands.1),
+        _ => false,
+    }
+}
+
+/// Lowers a pseudo-instruction to the real instructions it stands for, or
+/// returns `None` if `instruction` is not a pseudo-instruction.
+///
+/// | pseudo             | expansion                                  |
+/// |--------------------|--------------------------------------------|
+/// | `nop`              | `load $31 #0`                              |
+/// | `clr $r`           | `load $r #0`                               |
+/// | `mov $d $s`        | `load $31 #0`, `add $s $31 $d`             |
+/// | `mov $d #v`        | `load $d #v`                               |
+/// | `not $r`           | `inc $r`, `load $31 #0`, `sub $31 $r $r`   |
+/// | `jmp @l`           | `load $31 @l`, `jmp $31`                   |
+/// | `beq $a $b @l`     | `load $31 @l`, `eq $a $b`, `jeq $31`       |
+///
+/// `$31` cannot be the register `not` inverts, the source of `mov` or
+/// compared by `beq`, since the expansion overwrites it first.
+pub fn expand(instruction: &AssemblerInstruction) -> Option<Result<Vec<AssemblerInstruction>, AssemblerErrorKind>> {
+    let name = match &instruction.opcode {
+        Some(Token::PseudoOp { name }) => name.as_str(),
+        Some(Token::Op { code: Opcode::JMP }) if !matches!(instruction.operand1, Some(Token::Register { .. })) => "jmp",
+        _ => return None,
+    };
+    let real = |code: Opcode, operands: Vec<Token>| {
+        let mut operands = operands.into_iter();
+        AssemblerInstruction {
+            opcode: Some(Token::Op { code }),
+            label: None,
+            directive: None,
+            operand1: operands.next(),
+            operand2: operands.next(),
+            operand3: operands.next(),
+            location: instruction.location.clone(),
+        }
+    };
+    let scratch = register(SCRATCH_REGISTER);
+    let zero = Token::Number { value: 0 };
+    let operands = (&instruction.operand1, &instruction.operand2, &instruction.operand3);
+    if reads_scratch(name, operands) {
+        return Some(Err(AssemblerErrorKind::ScratchRegisterOperand { name: name.to_string() }));
+    }
+    let expansion = match (name, operands) {
+        ("nop", (None, None, None)) => vec![real(Opcode::LOAD, vec![scratch, zero])],
+        ("clr", (Some(reg @ Token::Register { .. }), None, None)) => {
+            vec![real(Opcode::LOAD, vec![reg.clone(), zero])]
+        }
+        ("mov", (Some(dst @ Token::Register { .. }), Some(src @ Token::Register { .. }), None)) => vec![
+            real(Opcode::LOAD, vec![scratch.clone(), zero]),
+            real(Opcode::ADD, vec![src.clone(), scratch, dst.clone()]),
+        ],
+        ("mov", (Some(dst @ Token::Register { .. }), Some(value), None)) if is_value(value) => {
+            vec![real(Opcode::LOAD, vec![dst.clone(), value.clone()])]
+        }
+        ("not", (Some(reg @ Token::Register { .. }), None, None)) => vec![
+            real(Opcode::INC, vec![reg.clone()]),
+            real(Opcode::LOAD, vec![scratch.clone(), zero]),
+            real(Opcode::SUB, vec![scratch, reg.clone(), reg.clone()]),
+        ],
+        ("jmp", (Some(target), None, None)) if is_value(target) => vec![
+            real(Opcode::LOAD, vec![scratch.clone(), target.clone()]),
+            real(Opcode::JMP, vec![scratch]),
+        ],
+        ("beq", (Some(lhs @ Token::Register { .. }), Some(rhs @ Token::Register { .. }), Some(target)))
+            if is_value(target) =>
+        {
+            vec![
+                real(Opcode::LOAD, vec![scratch.clone(), target.clone()]),
+                real(Opcode::EQ, vec![lhs.clone(), rhs.clone()]),
+                real(Opcode::JEQ, vec![scratch]),
+            ]
+        }
+        _ => {
+            return Some(Err(AssemblerErrorKind::InvalidPseudoOperands {
+                name: name.to_string(),
+                usage: usage(name),
+            }))
+        }
+    };
+    Some(Ok(expansion))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::syntax::SyntaxTree;
+    use crate::assembler::SourceLocation;
+
+    fn expanded(source: &str) -> Option<Result<Vec<String>, AssemblerErrorKind>> {
+        let program = SyntaxTree::parse(source).to_program(&SourceLocation::new(1, 1)).unwrap();
+        expand(&program.instructions[0]).map(|result| result.map(|real| real.iter().map(|i| i.to_string()).collect()))
+    }
+
+    #[test]
+    fn test_expand_pseudo_instructions() {
+        assert_eq!(expanded("nop"), Some(Ok(vec!["load $31 #0".to_string()])));
+        assert_eq!(expanded("CLR $4"), Some(Ok(vec!["load $4 #0".to_string()])));
+        assert_eq!(
+            expanded("mov $1 $2"),
+            Some(Ok(vec!["load $31 #0".to_string(), "add $2 $31 $1".to_string()]))
+        );
+        assert_eq!(expanded("mov $1 #SIZE * 2"), Some(Ok(vec!["load $1 #SIZE * 2".to_string()])));
+        assert_eq!(
+            expanded("not $3"),
+            Some(Ok(vec!["inc $3".to_string(), "load $31 #0".to_string(), "sub $31 $3 $3".to_string()]))
+        );
+        assert_eq!(
+            expanded("jmp @loop"),
+            Some(Ok(vec!["load $31 @loop".to_string(), "jmp $31".to_string()]))
+        );
+        assert_eq!(
+            expanded("beq $1 $2 @done"),
+            Some(Ok(vec![
+                "load $31 @done".to_string(),
+                "eq $1 $2".to_string(),
+                "jeq $31".to_string()
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_real_instructions_are_not_expanded() {
+        assert_eq!(expanded("jmp $3"), None);
+        assert_eq!(expanded("load $1 #2"), None);
+    }
+
+    #[test]
+    fn test_expand_rejects_invalid_operands() {
+        assert_eq!(
+            expanded("beq $1 @done"),
+            Some(Err(AssemblerErrorKind::InvalidPseudoOperands {
+                name: "beq".to_string(),
+                usage: "beq $a $b @label"
+            }))
+        );
+        assert!(matches!(expanded("clr #1"), Some(Err(_))));
+    }
+
+    #[test]
+    fn test_expand_rejects_reading_the_scratch_register() {
+        for source in ["not $31", "mov $1 $31", "beq $31 $2 @done", "beq $1 $31 @done"] {
+            let name = source.split(' ').next().unwrap().to_string();
+            assert_eq!(expanded(source), Some(Err(AssemblerErrorKind::ScratchRegisterOperand { name })), "{source}");
+        }
+        assert_eq!(
+            expanded("mov $31 $2"),
+            Some(Ok(vec!["load $31 #0".to_string(), "add $2 $31 $31".to_string()]))
+        );
+        assert_eq!(expanded("clr $31"), Some(Ok(vec!["load $31 #0".to_string()])));
+    }
+}
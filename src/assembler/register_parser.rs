@@ -1,13 +1,29 @@
+use crate::assembler::assembler_errors::INVALID_REGISTER;
 use crate::assembler::Token;
-use nom::digit;
 use nom::types::CompleteStr;
+use nom::{digit, ErrorKind};
+
+/// Number of general purpose registers in the VM.
+pub const REGISTER_COUNT: u8 = 32;
+
+fn parse_register_number(digits: CompleteStr) -> Result<u8, ()> {
+    match digits.parse::<u8>() {
+        Ok(number) if number < REGISTER_COUNT => Ok(number),
+        _ => Err(()),
+    }
+}
+
+named!(register_number<CompleteStr, u8>,
+    return_error!(ErrorKind::Custom(INVALID_REGISTER), map_res!(digit, parse_register_number))
+);
 
 named!(pub register<CompleteStr, Token>,
     ws!(
         do_parse!(
-            tag!("$") >> register_number: digit >>
+            tag!("$") >>
+            register_number: register_number >>
             (
-                Token::Register {register_number: register_number.parse::<u8>().unwrap()}
+                Token::Register {register_number}
             )
         )
     )
@@ -16,6 +32,7 @@ named!(pub register<CompleteStr, Token>,
 #[cfg(test)]
 mod tests {
     use super::*;
+    use nom::{Context, Err};
 
     #[test]
     fn test_parse_register() {
@@ -28,4 +45,17 @@ mod tests {
         let result = register(CompleteStr("$"));
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_register_out_of_range() {
+        let result = register(CompleteStr("$31"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Register { register_number: 31 })));
+        let result = register(CompleteStr("$32"));
+        assert_eq!(
+            result,
+            Err(Err::Failure(Context::Code(CompleteStr("32"), ErrorKind::Custom(INVALID_REGISTER))))
+        );
+        let result = register(CompleteStr("$300"));
+        assert!(matches!(result, Err(Err::Failure(_))));
+    }
 }
@@ -0,0 +1,256 @@
+use crate::assembler::assembler_errors::{AssemblerError, AssemblerErrorKind};
+use crate::assembler::instruction_parser::AssemblerInstruction;
+use crate::assembler::syntax::Program;
+use crate::assembler::Token;
+use std::collections::{HashMap, HashSet};
+
+/// Where an instruction sits: the namespaces around it and the global label
+/// its local labels belong to.
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    namespaces: Vec<String>,
+    global: Option<String>,
+}
+
+impl Scope {
+    fn qualify(&self, name: &str) -> String {
+        if self.namespaces.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{name}", self.namespaces.join("."))
+        }
+    }
+
+    fn qualify_local(&self, name: &str) -> String {
+        match &self.global {
+            Some(global) => format!("{global}{name}"),
+            None if self.namespaces.is_empty() => name.to_string(),
+            None => format!("{}{name}", self.namespaces.join(".")),
+        }
+    }
+}
+
+fn is_numeric_label(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Gives every label and constant its fully qualified name and rewrites the
+/// references to them.
+///
+/// * `.name` is local to the closest global label above it, so `.loop` after
+///   `main:` becomes `main.loop`.
+/// * Numeric labels such as `1:` may be declared many times; `@1b` refers to
+///   the closest one before the reference and `@1f` to the closest one after.
+/// * Inside `.namespace math` ... `.endnamespace`, `sqrt:` becomes `math.sqrt`.
+///   A reference is looked up in the innermost namespace first, then in each
+///   enclosing one, and finally as written.
+///
+/// References that match nothing are left alone so that evaluation reports
+/// them as undefined.
+pub fn resolve_scopes(program: &mut Program) -> Vec<AssemblerError> {
+    let mut errors = vec![];
+    let mut scopes = Vec::with_capacity(program.instructions.len());
+    let mut defined = HashSet::new();
+    let mut numeric_labels: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut scope = Scope::default();
+
+    for (index, instruction) in program.instructions.iter_mut().enumerate() {
+        match directive_name(instruction) {
+            Some("namespace") => {
+                if let Some(Token::Identifier { name }) = &instruction.operand1 {
+                    scope.namespaces.push(name.clone());
+                    scope.global = None;
+                }
+            }
+            Some("endnamespace") => {
+                if scope.namespaces.pop().is_none() {
+                    errors.push(AssemblerError::new(
+                        instruction.location.clone(),
+                        AssemblerErrorKind::UnexpectedEndNamespace,
+                    ));
+                }
+                scope.global = None;
+            }
+            _ => {}
+        }
+        if let Some(Token::LabelDeclaration { name }) = &mut instruction.label {
+            if is_numeric_label(name) {
+                let occurrences = numeric_labels.entry(name.clone()).or_default();
+                occurrences.push(index);
+                *name = format!("{name}@{}", occurrences.len());
+            } else if name.starts_with('.') {
+                *name = scope.qualify_local(name);
+            } else {
+                *name = scope.qualify(name);
+                scope.global = Some(name.clone());
+            }
+            defined.insert(name.clone());
+        }
+        if instruction.constant_definition().is_some() {
+            if let Some(Token::Identifier { name }) = &mut instruction.operand1 {
+                *name = scope.qualify(name);
+                defined.insert(name.clone());
+            }
+        }
+        scopes.push(scope.clone());
+    }
+    if let Some(name) = scope.namespaces.last() {
+        let location = program
+            .instructions
+            .last()
+            .map(|instruction| instruction.location.clone())
+            .unwrap_or_default();
+        errors.push(AssemblerError::new(
+            location,
+            AssemblerErrorKind::UnterminatedNamespace { name: name.clone() },
+        ));
+    }
+
+    for (index, (instruction, scope)) in program.instructions.iter_mut().zip(&scopes).enumerate() {
+        let mut resolve = |name: &mut String| {
+            if let Some(resolved) = resolve(name, index, scope, &defined, &numeric_labels) {
+                *name = resolved;
+            }
+        };
+        let skip = usize::from(instruction.constant_definition().is_some());
+        let operands = [&mut instruction.operand1, &mut instruction.operand2, &mut instruction.operand3];
+        for operand in operands.into_iter().skip(skip).flatten() {
+            rename_references(operand, &mut resolve);
+        }
+    }
+    errors
+}
+
+fn rename_references(token: &mut Token, f: &mut dyn FnMut(&mut String)) {
+    match token {
+        Token::LabelUsage { name } => f(name),
+        Token::Expression { expression } => expression.for_each_symbol_mut(f),
+        Token::Values { values } => values.iter_mut().for_each(|value| rename_references(value, f)),
+        _ => {}
+    }
+}
+
+fn directive_name(instruction: &AssemblerInstruction) -> Option<&str> {
+    match &instruction.directive {
+        Some(Token::Directive { name }) => Some(name.as_str()),
+        _ => None,
+    }
+}
+
+fn resolve(
+    name: &str,
+    index: usize,
+    scope: &Scope,
+    defined: &HashSet<String>,
+    numeric_labels: &HashMap<String, Vec<usize>>,
+) -> Option<String> {
+    let (number, direction) = name.split_at(name.len().saturating_sub(1));
+    if is_numeric_label(number) && (direction == "b" || direction == "f") {
+        let occurrences = numeric_labels.get(number)?;
+        let position = match direction {
+            "b" => occurrences.iter().rposition(|&declared| declared <= index)?,
+            _ => occurrences.iter().position(|&declared| declared > index)?,
+        };
+        return Some(format!("{number}@{}", position + 1));
+    }
+    if name.starts_with('.') {
+        return Some(scope.qualify_local(name));
+    }
+    (0..=scope.namespaces.len()).rev().find_map(|depth| {
+        let candidate = match depth {
+            0 => name.to_string(),
+            _ => format!("{}.{name}", scope.namespaces[..depth].join(".")),
+        };
+        Some(candidate).filter(|candidate| defined.contains(candidate))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::syntax::SyntaxTree;
+    use crate::assembler::SourceLocation;
+
+    fn parsed(source: &str) -> Program {
+        SyntaxTree::parse(source).to_program(&SourceLocation::new(1, 1)).unwrap()
+    }
+
+    fn resolved(source: &str) -> Vec<(Option<String>, Option<String>)> {
+        let mut program = parsed(source);
+        assert_eq!(resolve_scopes(&mut program), vec![]);
+        program
+            .instructions
+            .iter()
+            .map(|instruction| {
+                let usage = match &instruction.operand1 {
+                    Some(Token::LabelUsage { name }) => Some(name.clone()),
+                    _ => None,
+                };
+                (instruction.label_name(), usage)
+            })
+            .collect()
+    }
+
+    fn some(name: &str) -> Option<String> {
+        Some(name.to_string())
+    }
+
+    #[test]
+    fn test_resolve_local_labels() {
+        let source = "main: hlt\n.loop: djeq @.loop\nother: djeq @.loop\n.loop: djeq @main.loop";
+        assert_eq!(
+            resolved(source),
+            vec![
+                (some("main"), None),
+                (some("main.loop"), some("main.loop")),
+                (some("other"), some("other.loop")),
+                (some("other.loop"), some("main.loop")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_numeric_labels() {
+        let source = "1: djeq @1f\n1: djeq @1b\ndjeq @1b\ndjeq @1f\n1: hlt";
+        assert_eq!(
+            resolved(source),
+            vec![
+                (some("1@1"), some("1@2")),
+                (some("1@2"), some("1@2")),
+                (None, some("1@2")),
+                (None, some("1@3")),
+                (some("1@3"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_namespaces() {
+        let source = ".namespace math\nsqrt: djeq @done\ndone: djeq @main\n.endnamespace\n\
+                      main: djeq @math.sqrt\n.loop: djeq @sqrt";
+        assert_eq!(
+            resolved(source),
+            vec![
+                (None, None),
+                (some("math.sqrt"), some("math.done")),
+                (some("math.done"), some("main")),
+                (None, None),
+                (some("main"), some("math.sqrt")),
+                (some("main.loop"), some("sqrt")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_reports_unbalanced_namespaces() {
+        let mut program = parsed(".endnamespace\n.namespace io\nhlt");
+        let kinds: Vec<AssemblerErrorKind> = resolve_scopes(&mut program).into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AssemblerErrorKind::UnexpectedEndNamespace,
+                AssemblerErrorKind::UnterminatedNamespace { name: "io".to_string() },
+            ]
+        );
+    }
+}
@@ -0,0 +1,714 @@
+use crate::assembler::assembler_errors::{AssemblerError, AssemblerErrorKind, INVALID_NUMBER, INVALID_REGISTER};
+use crate::assembler::directive_parser::{data_value, DATA_DIRECTIVES, DIRECTIVES};
+use crate::assembler::expression_parser::identifier;
+use crate::assembler::instruction_parser::AssemblerInstruction;
+use crate::assembler::opcode_parser::opcode;
+use crate::assembler::operand_parser::{integer_value, operand};
+use crate::assembler::{SourceLocation, Token};
+use crate::instruction::Opcode;
+use nom::types::CompleteStr;
+use nom::{alpha1, digit, space0, Context, Err, ErrorKind, IResult};
+use std::fmt;
+use std::ops::Range;
+
+/// A range of bytes in the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn contains(&self, offset: usize) -> bool {
+        self.start <= offset && offset < self.end
+    }
+
+    /// The smallest span covering both spans.
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxKind {
+    Whitespace,
+    Newline,
+    /// `;` up to the end of the line.
+    Comment,
+    /// Mnemonics, directive names (with their `.`), labels and constants.
+    Identifier,
+    /// Decimal, hex or binary literals; also numeric labels such as `1` or `1b`.
+    Number,
+    /// `$` followed by the register number.
+    Register,
+    Char,
+    String,
+    /// `\name` or `\@` in a macro body.
+    MacroParameter,
+    Hash,
+    At,
+    Colon,
+    Comma,
+    LeftParen,
+    RightParen,
+    Operator,
+    /// Text no other kind matches, such as an unterminated literal.
+    Error,
+}
+
+impl SyntaxKind {
+    /// Whether tokens of this kind only separate or annotate the code.
+    pub fn is_trivia(self) -> bool {
+        matches!(self, SyntaxKind::Whitespace | SyntaxKind::Newline | SyntaxKind::Comment)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyntaxToken {
+    pub kind: SyntaxKind,
+    pub span: Span,
+}
+
+const OPERATORS: [&str; 8] = ["<<", ">>", "==", "!=", "<=", ">=", "&&", "||"];
+
+/// Splits `source` into tokens. Every byte ends up in exactly one token, so
+/// the tokens concatenate back to the source.
+pub fn lex(source: &str) -> Vec<SyntaxToken> {
+    let mut tokens = vec![];
+    let mut offset = 0;
+    while let Some(c) = source[offset..].chars().next() {
+        let rest = &source[offset..];
+        let (kind, len) = match c {
+            '\n' => (SyntaxKind::Newline, 1),
+            '\r' if rest.starts_with("\r\n") => (SyntaxKind::Newline, 2),
+            c if c.is_whitespace() => (SyntaxKind::Whitespace, prefix_len(rest, |c| c != '\n' && c.is_whitespace())),
+            ';' => (SyntaxKind::Comment, prefix_len(rest, |c| c != '\n' && c != '\r')),
+            '$' => match prefix_len(&rest[1..], |c| c.is_ascii_digit()) {
+                0 => (SyntaxKind::Error, 1),
+                digits => (SyntaxKind::Register, 1 + digits),
+            },
+            '\\' if rest[1..].starts_with('@') => (SyntaxKind::MacroParameter, 2),
+            '\\' => (SyntaxKind::MacroParameter, 1 + prefix_len(&rest[1..], is_word_char)),
+            '\'' => literal(rest, '\'', SyntaxKind::Char),
+            '"' => literal(rest, '"', SyntaxKind::String),
+            c if c.is_ascii_digit() => (SyntaxKind::Number, prefix_len(rest, is_word_char)),
+            c if c.is_alphabetic() || c == '_' || c == '.' => {
+                (SyntaxKind::Identifier, prefix_len(rest, |c| is_word_char(c) || c == '.'))
+            }
+            '#' => (SyntaxKind::Hash, 1),
+            '@' => (SyntaxKind::At, 1),
+            ':' => (SyntaxKind::Colon, 1),
+            ',' => (SyntaxKind::Comma, 1),
+            '(' => (SyntaxKind::LeftParen, 1),
+            ')' => (SyntaxKind::RightParen, 1),
+            _ if OPERATORS.iter().any(|operator| rest.starts_with(operator)) => (SyntaxKind::Operator, 2),
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' | '!' | '<' | '>' | '=' => (SyntaxKind::Operator, 1),
+            c => (SyntaxKind::Error, c.len_utf8()),
+        };
+        tokens.push(SyntaxToken {
+            kind,
+            span: Span::new(offset, offset + len),
+        });
+        offset += len;
+    }
+    tokens
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Length in bytes of the longest prefix of `text` whose characters match.
+fn prefix_len(text: &str, matches: impl Fn(char) -> bool) -> usize {
+    text.find(|c| !matches(c)).unwrap_or(text.len())
+}
+
+/// A character or string literal. Without a closing quote on the same line
+/// the rest of the line is an error token.
+fn literal(text: &str, quote: char, kind: SyntaxKind) -> (SyntaxKind, usize) {
+    let mut escaped = false;
+    for (index, c) in text.char_indices().skip(1) {
+        match c {
+            '\n' | '\r' => break,
+            '\\' if !escaped => escaped = true,
+            c if c == quote && !escaped => return (kind, index + 1),
+            _ => escaped = false,
+        }
+    }
+    (SyntaxKind::Error, prefix_len(text, |c| c != '\n' && c != '\r'))
+}
+
+/// `name:` at the start of a line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelNode {
+    /// The name and the colon.
+    pub span: Span,
+    pub name: Span,
+}
+
+/// An instruction, directive or macro call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementNode {
+    /// From the name to the end of the last operand.
+    pub span: Span,
+    /// The mnemonic, directive or macro name.
+    pub name: Span,
+    /// Each operand, without the commas or whitespace between them.
+    pub operands: Vec<Span>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxLine {
+    /// Indices of the line's tokens, including trivia and the newline.
+    pub tokens: Range<usize>,
+    pub span: Span,
+    pub label: Option<LabelNode>,
+    pub statement: Option<StatementNode>,
+}
+
+impl SyntaxLine {
+    /// The label and statement, without leading whitespace or a comment.
+    pub fn code(&self) -> Option<Span> {
+        let label = self.label.as_ref().map(|label| label.span);
+        let statement = self.statement.as_ref().map(|statement| statement.span);
+        match (label, statement) {
+            (Some(label), Some(statement)) => Some(label.to(statement)),
+            (label, statement) => label.or(statement),
+        }
+    }
+}
+
+/// The instructions and directives derived from a syntax tree, in source
+/// order.
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub(crate) instructions: Vec<AssemblerInstruction>,
+}
+
+/// Source as a concrete syntax tree: one node per line holding its label and
+/// statement, over tokens that keep all whitespace and comments. The tree
+/// prints back to exactly the text it was parsed from, and every node knows
+/// its byte span, which is what formatters, editors and diagnostics need.
+///
+/// [`SyntaxTree::to_program`] derives the instructions from the nodes,
+/// parsing only the text of each name and operand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxTree {
+    source: String,
+    tokens: Vec<SyntaxToken>,
+    lines: Vec<SyntaxLine>,
+}
+
+impl SyntaxTree {
+    pub fn parse(source: &str) -> SyntaxTree {
+        let tokens = lex(source);
+        let mut lines = vec![];
+        let mut start = 0;
+        for (index, token) in tokens.iter().enumerate() {
+            if token.kind == SyntaxKind::Newline {
+                lines.push(parse_line(&tokens, start..index + 1));
+                start = index + 1;
+            }
+        }
+        if start < tokens.len() {
+            lines.push(parse_line(&tokens, start..tokens.len()));
+        }
+        SyntaxTree {
+            source: source.to_string(),
+            tokens,
+            lines,
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn tokens(&self) -> &[SyntaxToken] {
+        &self.tokens
+    }
+
+    pub fn lines(&self) -> &[SyntaxLine] {
+        &self.lines
+    }
+
+    pub fn text(&self, span: Span) -> &str {
+        &self.source[span.start..span.end]
+    }
+
+    pub fn token_at(&self, offset: usize) -> Option<&SyntaxToken> {
+        self.tokens.iter().find(|token| token.span.contains(offset))
+    }
+
+    /// The 1-based line and column of a byte offset.
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let location = SourceLocation::of_suffix(&self.source, &self.source[offset..]);
+        (location.line, location.column)
+    }
+
+    /// The location of a byte offset, given that the source starts at
+    /// `location`.
+    fn location(&self, location: &SourceLocation, offset: usize) -> SourceLocation {
+        let (line, column) = self.position(offset);
+        SourceLocation {
+            line: location.line + line - 1,
+            column: if line == 1 { location.column + column - 1 } else { column },
+            ..location.clone()
+        }
+    }
+
+    /// Derives the instructions of every line from its label and statement,
+    /// parsing the text of each name and operand on its own. `location` is
+    /// where the source starts; the instructions and errors get locations
+    /// relative to it.
+    pub fn to_program(&self, location: &SourceLocation) -> Result<Program, Vec<AssemblerError>> {
+        let mut instructions = vec![];
+        let mut errors = vec![];
+        for line in &self.lines {
+            match self.line_instructions(line, location) {
+                Ok(mut parsed) => instructions.append(&mut parsed),
+                Err(error) => errors.push(error),
+            }
+        }
+        if errors.is_empty() {
+            Ok(Program { instructions })
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn line_instructions(
+        &self,
+        line: &SyntaxLine,
+        location: &SourceLocation,
+    ) -> Result<Vec<AssemblerInstruction>, AssemblerError> {
+        let mut instructions = vec![];
+        let mut label = match &line.label {
+            Some(node) => {
+                let name = self.parse_span(node.name, label_name, location)?;
+                Some((node.span.start, Token::LabelDeclaration { name: name.to_string() }))
+            }
+            None => None,
+        };
+        let statement = match &line.statement {
+            Some(statement) => statement,
+            None => {
+                instructions.extend(label.map(|(start, label)| label_only(label, self.location(location, start))));
+                return Ok(instructions);
+            }
+        };
+        let name = self.text(statement.name);
+        // A label in front of a directive other than a data directive is an
+        // instruction of its own, so that `.equ` and friends stay label-free.
+        let is_data = name
+            .strip_prefix('.')
+            .map(|directive| DATA_DIRECTIVES.contains(&directive.to_lowercase().as_str()));
+        if is_data == Some(false) {
+            if let Some((start, label)) = label.take() {
+                instructions.push(label_only(label, self.location(location, start)));
+            }
+        }
+        let start = label.as_ref().map_or(statement.name.start, |(start, _)| *start);
+        let mut instruction = match is_data {
+            Some(_) => self.directive(statement, location)?,
+            None => {
+                let opcode = self.parse_span(statement.name, opcode, location)?;
+                if opcode == (Token::Op { code: Opcode::ILLEGAL }) {
+                    let kind = AssemblerErrorKind::UnknownMnemonic { name: name.to_string() };
+                    return Err(AssemblerError::new(self.location(location, statement.name.start), kind));
+                }
+                let operands = self.operands(statement, location)?;
+                let mut operands = operands.into_iter();
+                AssemblerInstruction {
+                    opcode: Some(opcode),
+                    label: None,
+                    directive: None,
+                    operand1: operands.next(),
+                    operand2: operands.next(),
+                    operand3: operands.next(),
+                    location: SourceLocation::default(),
+                }
+            }
+        };
+        if instruction.expansion().is_none() {
+            if let Err((index, kind)) = instruction.check_operands() {
+                let offset = statement.operands.get(index).map_or(statement.name.start, |operand| operand.start);
+                return Err(AssemblerError::new(self.location(location, offset), kind));
+            }
+        }
+        instruction.label = label.map(|(_, label)| label);
+        instruction.location = self.location(location, start);
+        instructions.push(instruction);
+        Ok(instructions)
+    }
+
+    /// Builds a directive. `.equ`, `.set`, `.namespace` and the data
+    /// directives have operands of their own; when those do not match, the
+    /// directive takes ordinary operands like an instruction.
+    fn directive(
+        &self,
+        statement: &StatementNode,
+        location: &SourceLocation,
+    ) -> Result<AssemblerInstruction, AssemblerError> {
+        let name = self.parse_span(Span::new(statement.name.start + 1, statement.name.end), alpha1, location)?;
+        let lowercase = name.to_lowercase();
+        let operands = &statement.operands;
+        let directive = |operand1, operand2| AssemblerInstruction {
+            opcode: None,
+            label: None,
+            directive: Some(Token::Directive { name: lowercase.clone() }),
+            operand1: Some(operand1),
+            operand2,
+            operand3: None,
+            location: SourceLocation::default(),
+        };
+        match lowercase.as_str() {
+            "equ" | "set" => {
+                if let Some((constant, value)) = self.constant(statement, location) {
+                    return Ok(directive(constant, Some(value)));
+                }
+            }
+            "namespace" if operands.len() == 1 => {
+                if let Ok(name) = self.parse_span(operands[0], identifier, location) {
+                    return Ok(directive(Token::Identifier { name: name.to_string() }, None));
+                }
+            }
+            name if DATA_DIRECTIVES.contains(&name) && !operands.is_empty() && self.separated_by(operands, ",") => {
+                let values: Result<Vec<Token>, AssemblerError> = operands
+                    .iter()
+                    .map(|&operand| self.parse_span(operand, data_value, location))
+                    .collect();
+                if let Ok(values) = values {
+                    return Ok(directive(Token::Values { values }, None));
+                }
+            }
+            name if !DIRECTIVES.contains(&name) => {
+                let kind = AssemblerErrorKind::UnknownDirective { name: name.to_string() };
+                return Err(AssemblerError::new(self.location(location, statement.name.start), kind));
+            }
+            _ => {}
+        }
+        let mut operands = self.operands(statement, location)?.into_iter();
+        Ok(AssemblerInstruction {
+            opcode: None,
+            label: None,
+            directive: Some(Token::Directive { name: name.to_string() }),
+            operand1: operands.next(),
+            operand2: operands.next(),
+            operand3: operands.next(),
+            location: SourceLocation::default(),
+        })
+    }
+
+    /// The name and value of `.equ NAME value` or `.set NAME value`. The
+    /// value is the rest of the statement, since a value starting with a sign
+    /// does not split from the name.
+    fn constant(&self, statement: &StatementNode, location: &SourceLocation) -> Option<(Token, Token)> {
+        let mut tokens = self
+            .tokens
+            .iter()
+            .filter(|token| !token.kind.is_trivia() && statement.span.contains(token.span.start))
+            .skip(1);
+        let name = tokens.next()?;
+        let value = tokens.next()?;
+        if value.span.start == name.span.end {
+            return None;
+        }
+        let name = self.parse_span(name.span, identifier, location).ok()?;
+        let value = self.parse_span(Span::new(value.span.start, statement.span.end), constant_value, location).ok()?;
+        Some((Token::Identifier { name: name.to_string() }, value))
+    }
+
+    /// Parses up to three whitespace-separated operands.
+    fn operands(&self, statement: &StatementNode, location: &SourceLocation) -> Result<Vec<Token>, AssemblerError> {
+        let mut operands = vec![];
+        for (index, &span) in statement.operands.iter().enumerate() {
+            if index == 3 {
+                return Err(self.parse_error(location, span.start, span.end, None));
+            }
+            if index > 0 && !self.separated_by(&statement.operands[index - 1..=index], "") {
+                let separator = statement.operands[index - 1].end;
+                let between = self.text(Span::new(separator, span.start));
+                let offset = separator + between.find(|c: char| !c.is_whitespace()).unwrap_or(0);
+                return Err(self.parse_error(location, offset, span.end, None));
+            }
+            operands.push(self.parse_span(span, operand, location)?);
+        }
+        Ok(operands)
+    }
+
+    /// Whether only `separator` and whitespace stand between the operands.
+    fn separated_by(&self, operands: &[Span], separator: &str) -> bool {
+        operands
+            .windows(2)
+            .all(|pair| self.text(Span::new(pair[0].end, pair[1].start)).trim() == separator)
+    }
+
+    /// Runs `parser` over the text of `span`, which it has to consume
+    /// entirely. Errors point at the first text it could not parse.
+    fn parse_span<'a, T>(
+        &'a self,
+        span: Span,
+        parser: impl Fn(CompleteStr<'a>) -> IResult<CompleteStr<'a>, T>,
+        location: &SourceLocation,
+    ) -> Result<T, AssemblerError> {
+        let text = self.text(span);
+        let (remainder, code) = match parser(CompleteStr(text)) {
+            Ok((remainder, value)) if remainder.trim().is_empty() => return Ok(value),
+            Ok((remainder, _)) | Err(Err::Error(Context::Code(remainder, _))) => (remainder, None),
+            Err(Err::Failure(Context::Code(remainder, ErrorKind::Custom(code)))) => (remainder, Some(code)),
+            Err(_) => (CompleteStr(text), None),
+        };
+        let offset = span.end - remainder.trim_start().len();
+        Err(self.parse_error(location, offset, span.end, code))
+    }
+
+    /// Builds the error reported when parsing stopped at `offset`, blaming the
+    /// word found there, which ends before `end` at the latest.
+    fn parse_error(&self, location: &SourceLocation, offset: usize, end: usize, code: Option<u32>) -> AssemblerError {
+        let found = self.source[offset..end.max(offset)]
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let kind = match code {
+            Some(INVALID_REGISTER) => AssemblerErrorKind::InvalidRegister { register: found },
+            Some(INVALID_NUMBER) => AssemblerErrorKind::InvalidNumber { literal: found },
+            _ => AssemblerErrorKind::UnexpectedInput { found },
+        };
+        AssemblerError::new(self.location(location, offset), kind)
+    }
+}
+
+named!(label_name<CompleteStr, CompleteStr>, alt!(identifier | digit));
+
+// The value of `.equ` and `.set`, which may be written as an integer operand.
+named!(constant_value<CompleteStr, Token>,
+    preceded!(opt!(terminated!(tag!("#"), space0)), integer_value)
+);
+
+fn label_only(label: Token, location: SourceLocation) -> AssemblerInstruction {
+    AssemblerInstruction {
+        opcode: None,
+        label: Some(label),
+        directive: None,
+        operand1: None,
+        operand2: None,
+        operand3: None,
+        location,
+    }
+}
+
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for token in &self.tokens {
+            f.write_str(self.text(token.span))?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_line(tokens: &[SyntaxToken], range: Range<usize>) -> SyntaxLine {
+    let line = &tokens[range.clone()];
+    let span = Span::new(line[0].span.start, line[line.len() - 1].span.end);
+    let significant: Vec<&SyntaxToken> = line.iter().filter(|token| !token.kind.is_trivia()).collect();
+    let label = match significant[..] {
+        [name, colon, ..]
+            if matches!(name.kind, SyntaxKind::Identifier | SyntaxKind::Number)
+                && colon.kind == SyntaxKind::Colon
+                && name.span.end == colon.span.start =>
+        {
+            Some(LabelNode {
+                span: name.span.to(colon.span),
+                name: name.span,
+            })
+        }
+        _ => None,
+    };
+    let rest = &significant[if label.is_some() { 2 } else { 0 }..];
+    let statement = rest.first().map(|name| {
+        let operands = &line[line.iter().position(|token| token == *name).unwrap_or(0) + 1..];
+        StatementNode {
+            span: name.span.to(rest[rest.len() - 1].span),
+            name: name.span,
+            operands: split_operands(operands),
+        }
+    });
+    SyntaxLine {
+        tokens: range,
+        span,
+        label,
+        statement,
+    }
+}
+
+/// Splits operands at commas outside parentheses if there are any, and at
+/// whitespace otherwise. Whitespace next to an operator or after `#` or `@`
+/// does not split, so `#SIZE * 2` stays one operand.
+fn split_operands(tokens: &[SyntaxToken]) -> Vec<Span> {
+    let mut depth = 0;
+    let top_level_comma = tokens.iter().any(|token| {
+        match token.kind {
+            SyntaxKind::LeftParen => depth += 1,
+            SyntaxKind::RightParen => depth -= 1,
+            _ => {}
+        }
+        depth == 0 && token.kind == SyntaxKind::Comma
+    });
+    let mut operands = vec![];
+    let mut current: Option<Span> = None;
+    let (mut depth, mut separated, mut previous) = (0, false, SyntaxKind::Whitespace);
+    for token in tokens.iter().filter(|token| token.kind != SyntaxKind::Newline && token.kind != SyntaxKind::Comment) {
+        match token.kind {
+            SyntaxKind::Whitespace => {
+                separated = true;
+                continue;
+            }
+            SyntaxKind::Comma if top_level_comma && depth == 0 => {
+                operands.extend(current.take());
+                separated = false;
+                continue;
+            }
+            SyntaxKind::LeftParen => depth += 1,
+            SyntaxKind::RightParen => depth -= 1,
+            _ => {}
+        }
+        let joined = matches!(previous, SyntaxKind::Operator | SyntaxKind::Hash | SyntaxKind::At)
+            || token.kind == SyntaxKind::Operator;
+        let splits = !top_level_comma && separated && depth == 0 && !joined;
+        current = match current {
+            Some(span) if !splits => Some(span.to(token.span)),
+            Some(span) => {
+                operands.push(span);
+                Some(token.span)
+            }
+            None => Some(token.span),
+        };
+        separated = false;
+        previous = token.kind;
+    }
+    operands.extend(current);
+    operands
+}
+
+/// Splits `text` into operands the way instruction operands are split, e.g.
+/// `1, 2, ' '` into three.
+pub fn split_arguments(text: &str) -> Vec<&str> {
+    split_operands(&lex(text))
+        .into_iter()
+        .map(|span| &text[span.start..span.end])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::expression_parser::Expression;
+
+    const SOURCE: &str = "; setup\r\nstart:  load $1 #(SIZE * 2) ; twice\n\t.byte 1, 'a', -2\n1: djeq @1b\n'oops";
+
+    #[test]
+    fn test_tree_is_lossless() {
+        let tree = SyntaxTree::parse(SOURCE);
+        assert_eq!(tree.to_string(), SOURCE);
+        let tokens = tree.tokens();
+        assert!(tokens.windows(2).all(|pair| pair[0].span.end == pair[1].span.start));
+        assert_eq!(tokens.last().map(|token| token.kind), Some(SyntaxKind::Error));
+        assert_eq!(tree.lines().len(), 5);
+    }
+
+    #[test]
+    fn test_nodes_have_spans() {
+        let tree = SyntaxTree::parse(SOURCE);
+        let text = |span| tree.text(span);
+        let lines = tree.lines();
+        assert_eq!((lines[0].label.clone(), lines[0].statement.clone()), (None, None));
+        let start = &lines[1];
+        assert_eq!(start.label.as_ref().map(|label| text(label.name)), Some("start"));
+        let statement = start.statement.as_ref().unwrap();
+        assert_eq!(text(statement.span), "load $1 #(SIZE * 2)");
+        let operands: Vec<&str> = statement.operands.iter().map(|&span| text(span)).collect();
+        assert_eq!(operands, ["$1", "#(SIZE * 2)"]);
+        let data = lines[2].statement.as_ref().unwrap();
+        let operands: Vec<&str> = data.operands.iter().map(|&span| text(span)).collect();
+        assert_eq!((text(data.name), operands), (".byte", vec!["1", "'a'", "-2"]));
+        assert_eq!(lines[3].label.as_ref().map(|label| text(label.span)), Some("1:"));
+        let comment = tree.token_at(SOURCE.find("; twice").unwrap()).unwrap();
+        assert_eq!((comment.kind, text(comment.span)), (SyntaxKind::Comment, "; twice"));
+        assert_eq!(tree.position(SOURCE.find(".byte").unwrap()), (3, 2));
+    }
+
+    #[test]
+    fn test_derive_program() {
+        let tree = SyntaxTree::parse("  start: load $1 #2 ; one\n\nhlt ; done\n");
+        let program = tree.to_program(&SourceLocation::new(10, 1)).unwrap();
+        let locations: Vec<(usize, usize)> = program
+            .instructions
+            .iter()
+            .map(|instruction| (instruction.location.line, instruction.location.column))
+            .collect();
+        assert_eq!(locations, [(10, 3), (12, 1)]);
+        assert_eq!(program.instructions[0].to_string(), "start: load $1 #2");
+        let errors = SyntaxTree::parse("load $1 #2\n  load $1 #2 %").to_program(&SourceLocation::new(1, 1)).unwrap_err();
+        assert_eq!(errors[0].to_string(), "line 2, column 14: unexpected input `%`");
+    }
+
+    #[test]
+    fn test_derive_statements() {
+        let source = "x: .code\n.equ LOW -1\ntable: .byte 1, 'a'\n.NameSpace io\n  end:";
+        let program = SyntaxTree::parse(source).to_program(&SourceLocation::new(1, 1)).unwrap();
+        let lines: Vec<String> = program.instructions.iter().map(ToString::to_string).collect();
+        assert_eq!(lines, ["x:", ".code", ".equ LOW #-1", "table: .byte #1, #97", ".namespace io", "end:"]);
+        let locations: Vec<(usize, usize)> = program
+            .instructions
+            .iter()
+            .map(|instruction| (instruction.location.line, instruction.location.column))
+            .collect();
+        assert_eq!(locations, [(1, 1), (1, 4), (2, 1), (3, 1), (4, 1), (5, 3)]);
+
+        let source = "load $1, #2\nhlt hlt\nadd $1 $2 $3 $4\nload $40 #1\nload $1 #0x\n1b: hlt\nx:  lod $2 #3\n.Foo 1";
+        let errors = SyntaxTree::parse(source).to_program(&SourceLocation::new(1, 1)).unwrap_err();
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "line 1, column 8: unexpected input `,`",
+                "line 2, column 5: unexpected input `hlt`",
+                "line 3, column 14: unexpected input `$4`",
+                "line 4, column 7: invalid register `$40`, expected $0 to $31",
+                "line 5, column 11: unexpected input `x`",
+                "line 6, column 2: unexpected input `b`",
+                "line 7, column 5: unknown instruction `lod`",
+                "line 8, column 1: unknown directive `.foo`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_derive_labels_and_data() {
+        let source = "__loop_1: inc $0\nmain.loop: inc $0\n.loop:\n1: inc $0\ntable: .word @start, #2, 'a' + 1";
+        let program = SyntaxTree::parse(source).to_program(&SourceLocation::new(1, 1)).unwrap();
+        let labels: Vec<Option<String>> = program.instructions.iter().map(AssemblerInstruction::label_name).collect();
+        let names = ["__loop_1", "main.loop", ".loop", "1", "table"];
+        assert_eq!(labels, names.map(|name| Some(name.to_string())));
+        assert_eq!(program.instructions[4].to_string(), "table: .word @start, #2, #98");
+        let program = SyntaxTree::parse(".set total #(BUF_SIZE * 2)").to_program(&SourceLocation::new(1, 1)).unwrap();
+        assert!(matches!(
+            program.instructions[0].operand2,
+            Some(Token::Expression { expression: Expression::Binary(..) })
+        ));
+        assert!(SyntaxTree::parse(".: inc $0").to_program(&SourceLocation::new(1, 1)).is_err());
+    }
+}
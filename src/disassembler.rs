@@ -0,0 +1,130 @@
+use crate::assembler::pseudo_instructions::SCRATCH_REGISTER;
+use crate::assembler::SymbolType;
+use crate::instruction::Opcode;
+use crate::symbol_map::{Section, SymbolMap};
+
+/// Bytes every instruction is encoded in.
+pub const INSTRUCTION_SIZE: usize = 4;
+
+/// One instruction, or one row of data, decoded back to assembly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisassembledInstruction {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    /// Labels pointing at `offset`.
+    pub labels: Vec<String>,
+}
+
+/// Decodes up to `count` instructions of `program` starting at byte `from`.
+/// Bytes from the first data label on, and bytes that are not a valid
+/// instruction, are shown as `.byte` directives.
+pub fn disassemble(program: &[u8], symbols: &SymbolMap, from: usize, count: usize) -> Vec<DisassembledInstruction> {
+    let data_start = symbols
+        .entries()
+        .iter()
+        .filter(|entry| entry.section == Section::Data)
+        .map(|entry| entry.value as usize)
+        .min()
+        .unwrap_or(usize::MAX);
+    let mut offset = from;
+    let mut instructions = vec![];
+    while instructions.len() < count && offset < program.len() {
+        let end = (offset + INSTRUCTION_SIZE).min(program.len());
+        let bytes = program[offset..end].to_vec();
+        let text = match offset < data_start {
+            true => decode(&bytes, symbols),
+            false => None,
+        };
+        instructions.push(DisassembledInstruction {
+            offset,
+            text: text.unwrap_or_else(|| data(&bytes)),
+            labels: labels_at(symbols, offset),
+            bytes,
+        });
+        offset = end;
+    }
+    instructions
+}
+
+/// Decodes a single instruction. Returns `None` for an unknown opcode or
+/// a truncated instruction.
+pub fn decode(bytes: &[u8], symbols: &SymbolMap) -> Option<String> {
+    let [opcode, a, b, c] = *bytes else {
+        return None;
+    };
+    let opcode = Opcode::from(opcode);
+    let immediate = u16::from_be_bytes([b, c]);
+    let operands = match opcode {
+        Opcode::HLT => String::new(),
+        // `load $31 @label` is how pseudo-instructions reach labels.
+        Opcode::LOAD => match label_named(symbols, immediate as usize) {
+            Some(label) if a == SCRATCH_REGISTER => format!(" ${a} @{label}"),
+            _ => format!(" ${a} #{immediate}"),
+        },
+        Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => format!(" ${a} ${b} ${c}"),
+        Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GE | Opcode::LE => format!(" ${a} ${b}"),
+        Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ | Opcode::ALLOC | Opcode::INC | Opcode::DEC => {
+            format!(" ${a}")
+        }
+        Opcode::DJEQ => {
+            let target = u16::from_be_bytes([a, b]);
+            match label_named(symbols, target as usize) {
+                Some(label) => format!(" @{label}"),
+                None => format!(" #{target}"),
+            }
+        }
+        Opcode::ILLEGAL => return None,
+    };
+    Some(format!("{opcode}{operands}"))
+}
+
+fn data(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+    format!(".byte {}", bytes.join(", "))
+}
+
+fn labels_at(symbols: &SymbolMap, address: usize) -> Vec<String> {
+    symbols
+        .entries()
+        .iter()
+        .filter(|entry| entry.kind == SymbolType::Label && entry.section != Section::Absolute)
+        .filter(|entry| entry.value == address as i64)
+        .map(|entry| entry.name.clone())
+        .collect()
+}
+
+fn label_named(symbols: &SymbolMap, address: usize) -> Option<String> {
+    labels_at(symbols, address).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_disassemble() {
+        let program = Assembler::new()
+            .assemble("start: load $1 #65533\nloop: add $1 $2 $3\neq $1 $2\ndjeq @loop\njmp @start\n.data\nvalue: .byte 7")
+            .unwrap();
+        let instructions = disassemble(&program.image(), &program.symbol_map(), 0, 10);
+        let texts: Vec<&str> = instructions.iter().map(|instruction| instruction.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            ["load $1 #65533", "add $1 $2 $3", "eq $1 $2", "djeq @loop", "load $31 @start", "jmp $31", ".byte 7"]
+        );
+        assert_eq!(instructions[1].labels, ["loop"]);
+        assert_eq!(instructions[6].labels, ["value"]);
+        assert_eq!(disassemble(&program.image(), &program.symbol_map(), 20, 1)[0].offset, 20);
+    }
+
+    #[test]
+    fn test_decode_invalid_bytes() {
+        let symbols = SymbolMap::new();
+        assert_eq!(decode(&[1, 31, 0, 8], &symbols), Some("load $31 #8".to_string()));
+        assert_eq!(decode(&[200, 0, 0, 0], &symbols), None);
+        assert_eq!(decode(&[0, 0], &symbols), None);
+        assert_eq!(disassemble(&[200, 1, 2, 3, 0], &symbols, 0, 5)[1].text, ".byte 0");
+    }
+}
@@ -1,4 +1,5 @@
 use nom::types::CompleteStr;
+use std::fmt;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Opcode {
@@ -82,6 +83,13 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
     }
 }
 
+/// Writes the mnemonic as it is written in assembly, e.g. `load`.
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&format!("{self:?}").to_lowercase())
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Instruction {
     opcode: Opcode,
@@ -0,0 +1,9 @@
+#[macro_use]
+extern crate nom;
+pub mod assembler;
+pub mod disassembler;
+pub mod instruction;
+pub mod program_file;
+pub mod repl;
+pub mod symbol_map;
+pub mod vm;
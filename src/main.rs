@@ -1,11 +1,275 @@
-#[macro_use]
-extern crate nom;
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use virtual_machine::assembler::formatter::format_source;
+use virtual_machine::assembler::{parse_define, Assembler};
+use virtual_machine::repl;
+use virtual_machine::repl::server::Server;
+
+/// File in the home directory the REPL history is kept in.
+const HISTORY_FILE: &str = ".virtual_machine_history";
+
+const USAGE: &str = "usage: virtual_machine [-O] [-D NAME[=VALUE]]... [-I DIR]... [--script FILE]\n       virtual_machine [-O] [-D NAME[=VALUE]]... [-I DIR]... --serve ADDR [--shared] [--secret-file FILE]\n       virtual_machine fmt [--check] FILE...\n       virtual_machine symbols [-O] [-D NAME[=VALUE]]... [-I DIR]... FILE [-o MAP]\n       virtual_machine listing [-O] [-D NAME[=VALUE]]... [-I DIR]... FILE [-o LISTING]";
+
+/// What to do instead of reading commands from the terminal.
+#[derive(Default)]
+struct Options {
+    /// Script to run.
+    script: Option<PathBuf>,
+    /// Address to serve REPL sessions on.
+    serve: Option<String>,
+    /// Whether served sessions share one VM.
+    shared: bool,
+    /// File holding the secret clients have to give.
+    secret_file: Option<PathBuf>,
+}
+
+/// Applies the command line options to the assembler used by the REPL and
+/// returns the rest.
+fn parse_args(mut args: impl Iterator<Item = String>, asm: &mut Assembler) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O" => asm.optimize = true,
+            "--script" => options.script = Some(PathBuf::from(args.next().ok_or("`--script` expects a file")?)),
+            "--serve" => options.serve = Some(args.next().ok_or("`--serve` expects an address")?),
+            "--shared" => options.shared = true,
+            "--secret-file" => {
+                options.secret_file = Some(PathBuf::from(args.next().ok_or("`--secret-file` expects a file")?))
+            }
+            _ => define_or_include(&arg, &mut args, asm)?,
+        }
+    }
+    if options.serve.is_none() && (options.shared || options.secret_file.is_some()) {
+        return Err("`--shared` and `--secret-file` need `--serve`".to_string());
+    }
+    Ok(options)
+}
+
+/// Applies a `-D` or `-I` option, whose value is either attached or the next
+/// argument.
+fn define_or_include(arg: &str, args: &mut impl Iterator<Item = String>, asm: &mut Assembler) -> Result<(), String> {
+    let (option, inline) = match arg.get(..2) {
+        Some(option @ ("-D" | "-I")) => (option, &arg[2..]),
+        _ => return Err(format!("unknown argument `{arg}`")),
+    };
+    let value = match inline {
+        "" => args.next().ok_or_else(|| format!("`{option}` expects a value"))?,
+        inline => inline.to_string(),
+    };
+    if option == "-D" {
+        let (name, value) = parse_define(&value)?;
+        asm.define(&name, value);
+    } else {
+        asm.include_dirs.push(PathBuf::from(value));
+    }
+    Ok(())
+}
+
+/// Formats each file in place, or with `--check` lists the files that are not
+/// formatted. Returns the exit code: 1 if a checked file is not formatted, 2
+/// if a file could not be read or written.
+fn format_files(args: impl Iterator<Item = String>) -> i32 {
+    let (checks, files): (Vec<String>, Vec<String>) = args.partition(|arg| arg == "--check");
+    if files.is_empty() {
+        eprintln!("`fmt` expects at least one file\n{USAGE}");
+        return 2;
+    }
+    let mut code = 0;
+    for file in files {
+        let result = fs::read_to_string(&file).and_then(|source| {
+            let formatted = format_source(&source);
+            if formatted == source {
+                Ok(())
+            } else if checks.is_empty() {
+                fs::write(&file, formatted)
+            } else {
+                println!("{file} is not formatted");
+                code = code.max(1);
+                Ok(())
+            }
+        });
+        if let Err(error) = result {
+            eprintln!("{file}: {error}");
+            code = 2;
+        }
+    }
+    code
+}
+
+/// What the `symbols` and `listing` subcommands write.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output {
+    /// The symbol map in the machine-readable form.
+    Symbols,
+    /// The listing of addresses, bytes and source lines.
+    Listing,
+}
+
+impl Output {
+    fn name(self) -> &'static str {
+        match self {
+            Output::Symbols => "symbols",
+            Output::Listing => "listing",
+        }
+    }
+}
+
+/// Assembles a file and writes its symbol map or listing to the `-o` file,
+/// or prints it. Returns the exit code: 1 if the file does not assemble, 2
+/// for bad arguments or if the output could not be written.
+fn write_output(mut args: impl Iterator<Item = String>, kind: Output) -> i32 {
+    let mut asm = Assembler::new();
+    asm.generate_listing = kind == Output::Listing;
+    let mut source = None;
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        let result = match arg.as_str() {
+            "-O" => {
+                asm.optimize = true;
+                Ok(())
+            }
+            "-o" => match args.next() {
+                Some(path) => {
+                    output = Some(PathBuf::from(path));
+                    Ok(())
+                }
+                None => Err("`-o` expects a file".to_string()),
+            },
+            _ if !arg.starts_with('-') && source.is_none() => {
+                source = Some(PathBuf::from(&arg));
+                Ok(())
+            }
+            _ => define_or_include(&arg, &mut args, &mut asm),
+        };
+        if let Err(message) = result {
+            eprintln!("{message}\n{USAGE}");
+            return 2;
+        }
+    }
+    let Some(source) = source else {
+        eprintln!("`{}` expects a file\n{USAGE}", kind.name());
+        return 2;
+    };
+    let text = match asm.assemble_file(&source) {
+        Ok(program) => match kind {
+            Output::Symbols => program.symbol_map().to_machine_readable(),
+            Output::Listing => program.listing.map(|listing| listing.to_string()).unwrap_or_default(),
+        },
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}: {error}", source.display());
+            }
+            return 1;
+        }
+    };
+    match output {
+        Some(path) => {
+            if let Err(error) = fs::write(&path, text) {
+                eprintln!("{}: {error}", path.display());
+                return 2;
+            }
+        }
+        None => print!("{text}"),
+    }
+    0
+}
+
+/// Runs a REPL script. Returns the exit code: 1 if any line failed, 2 if the
+/// script could not be read.
+fn run_script(repl: &mut repl::REPL, path: &Path) -> i32 {
+    match fs::read_to_string(path) {
+        Ok(script) => match repl.run_script(&script) {
+            Ok(0) => 0,
+            Ok(_) => 1,
+            Err(error) => {
+                eprintln!("{error}");
+                2
+            }
+        },
+        Err(error) => {
+            eprintln!("{}: {error}", path.display());
+            2
+        }
+    }
+}
+
+/// Serves REPL sessions on `addr` until the listener fails. Returns the exit
+/// code: 2 if the secret could not be read or the server failed.
+fn serve(addr: &str, asm: Assembler, options: &Options) -> i32 {
+    let result = Server::bind(addr, asm).and_then(|mut server| {
+        if options.shared {
+            server = server.shared_vm();
+        }
+        match &options.secret_file {
+            Some(path) => server = server.secret(fs::read_to_string(path)?.trim_end()),
+            None => eprintln!("warning: without --secret-file anyone who can connect gets a REPL"),
+        }
+        println!("Serving on {}", server.local_addr()?);
+        server.run()
+    });
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("{addr}: {error}");
+            2
+        }
+    }
+}
 
 fn main() {
-    let mut repl = repl::REPL::new();
-    repl.run();
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("fmt") {
+        process::exit(format_files(args.skip(1)));
+    }
+    if args.peek().map(String::as_str) == Some("symbols") {
+        process::exit(write_output(args.skip(1), Output::Symbols));
+    }
+    if args.peek().map(String::as_str) == Some("listing") {
+        process::exit(write_output(args.skip(1), Output::Listing));
+    }
+    let mut asm = Assembler::new();
+    let options = match parse_args(args, &mut asm) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}\n{USAGE}");
+            process::exit(2);
+        }
+    };
+    if let Some(addr) = &options.serve {
+        process::exit(serve(addr, asm, &options));
+    }
+    let mut repl = repl::REPL::with_assembler(asm);
+    if let Some(script) = options.script {
+        process::exit(run_script(&mut repl, &script));
+    }
+    if let Some(home) = env::var_os("HOME") {
+        if let Err(error) = repl.load_history(&PathBuf::from(home).join(HISTORY_FILE)) {
+            eprintln!("unable to read history: {error}");
+        }
+    }
+    if let Err(error) = repl.run_terminal() {
+        eprintln!("{error}");
+        process::exit(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_listing() {
+        let dir = env::temp_dir().join(format!("listing-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (source, listing) = (dir.join("main.iasm"), dir.join("main.lst"));
+        fs::write(&source, "start: load $1 #VALUE\nhlt").unwrap();
+        let args = [source.to_str().unwrap(), "-DVALUE=7", "-o", listing.to_str().unwrap()];
+        assert_eq!(write_output(args.iter().map(|arg| arg.to_string()), Output::Listing), 0);
+        let text = fs::read_to_string(&listing).unwrap();
+        assert!(text.starts_with("0000  01 01 00 07  start: load $1 #VALUE\n"), "{text}");
+        assert!(text.contains("start  label          0  0x0000\n"), "{text}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,133 @@
+use crate::symbol_map::{SymbolMap, SymbolMapError};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// First bytes of every program file.
+const MAGIC: &[u8; 4] = b"VMPG";
+const VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgramFileError {
+    NotAProgram,
+    UnsupportedVersion(u16),
+    Truncated,
+    TrailingBytes,
+    InvalidSymbols(SymbolMapError),
+}
+
+impl fmt::Display for ProgramFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProgramFileError::NotAProgram => write!(f, "not a program file"),
+            ProgramFileError::UnsupportedVersion(version) => write!(f, "unsupported program file version {version}"),
+            ProgramFileError::Truncated => write!(f, "program file is truncated"),
+            ProgramFileError::TrailingBytes => write!(f, "program file has bytes after its symbols"),
+            ProgramFileError::InvalidSymbols(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+/// An assembled program saved with its symbols, so it can be run again
+/// without the source.
+///
+/// The file holds, big-endian: the magic `VMPG`, a `u16` version, the entry
+/// point as a `u32`, the program as a `u32` length and its bytes, and the
+/// symbols in their machine-readable form (see
+/// [`SymbolMap::to_machine_readable`]) as a `u32` length and UTF-8 text.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProgramFile {
+    pub program: Vec<u8>,
+    /// Where execution starts.
+    pub entry: u32,
+    pub symbols: SymbolMap,
+}
+
+impl ProgramFile {
+    pub fn new(program: Vec<u8>, entry: u32, symbols: SymbolMap) -> ProgramFile {
+        ProgramFile { program, entry, symbols }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let symbols = self.symbols.to_machine_readable();
+        let mut bytes = MAGIC.to_vec();
+        // Writing to a Vec cannot fail.
+        bytes.write_u16::<BigEndian>(VERSION).unwrap();
+        bytes.write_u32::<BigEndian>(self.entry).unwrap();
+        bytes.write_u32::<BigEndian>(self.program.len() as u32).unwrap();
+        bytes.extend_from_slice(&self.program);
+        bytes.write_u32::<BigEndian>(symbols.len() as u32).unwrap();
+        bytes.extend_from_slice(symbols.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<ProgramFile, ProgramFileError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(ProgramFileError::NotAProgram);
+        }
+        bytes = &bytes[MAGIC.len()..];
+        let version = bytes.read_u16::<BigEndian>().map_err(|_| ProgramFileError::Truncated)?;
+        if version != VERSION {
+            return Err(ProgramFileError::UnsupportedVersion(version));
+        }
+        let entry = bytes.read_u32::<BigEndian>().map_err(|_| ProgramFileError::Truncated)?;
+        let program = read_block(&mut bytes)?;
+        let symbols = String::from_utf8(read_block(&mut bytes)?).map_err(|_| ProgramFileError::NotAProgram)?;
+        if !bytes.is_empty() {
+            return Err(ProgramFileError::TrailingBytes);
+        }
+        let symbols = SymbolMap::parse(&symbols).map_err(ProgramFileError::InvalidSymbols)?;
+        Ok(ProgramFile { program, entry, symbols })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> io::Result<ProgramFile> {
+        let bytes = fs::read(path)?;
+        ProgramFile::from_bytes(&bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+    }
+}
+
+/// Reads a `u32` length and that many bytes. The length is checked against
+/// what is left before anything is allocated.
+fn read_block(bytes: &mut &[u8]) -> Result<Vec<u8>, ProgramFileError> {
+    let len = bytes.read_u32::<BigEndian>().map_err(|_| ProgramFileError::Truncated)? as usize;
+    if len > bytes.len() {
+        return Err(ProgramFileError::Truncated);
+    }
+    let (block, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(block.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_round_trip() {
+        let program = Assembler::new().assemble("load $1 #5\nstart: jmp @start\n.entry @start").unwrap();
+        let file = ProgramFile::new(program.image(), program.entry, program.symbol_map());
+        let bytes = file.to_bytes();
+        assert_eq!(bytes[..14], [b'V', b'M', b'P', b'G', 0, 1, 0, 0, 0, 4, 0, 0, 0, 12]);
+        assert_eq!(ProgramFile::from_bytes(&bytes), Ok(file));
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        assert_eq!(ProgramFile::from_bytes(b"# symbol map v1"), Err(ProgramFileError::NotAProgram));
+        assert_eq!(ProgramFile::from_bytes(b"VMPG\0\x02"), Err(ProgramFileError::UnsupportedVersion(2)));
+        assert_eq!(ProgramFile::from_bytes(b"VMPG\0\x01\0\0"), Err(ProgramFileError::Truncated));
+        assert_eq!(ProgramFile::from_bytes(b"VMPG\0\x01\0\0\0\0\0\0\0\x08\0\0"), Err(ProgramFileError::Truncated));
+        // A length far beyond the file is refused before anything is allocated.
+        assert_eq!(ProgramFile::from_bytes(b"VMPG\0\x01\0\0\0\0\xff\xff\xff\xff"), Err(ProgramFileError::Truncated));
+        let mut bytes = ProgramFile::default().to_bytes();
+        bytes.push(0);
+        assert_eq!(ProgramFile::from_bytes(&bytes), Err(ProgramFileError::TrailingBytes));
+    }
+}
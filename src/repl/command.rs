@@ -0,0 +1,345 @@
+use crate::assembler::directive_parser::{DIRECTIVES, PREPROCESSOR_DIRECTIVES};
+use crate::assembler::expression_parser::{expression, Expression};
+use crate::assembler::syntax::split_arguments;
+use nom::types::CompleteStr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+/// Parts of the VM `.reset` can reinitialize on their own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetPart {
+    Registers,
+    Pc,
+    Heap,
+    /// The equal flag and the remainder.
+    Flags,
+    /// The program and its symbols.
+    Program,
+}
+
+impl ResetPart {
+    fn parse(name: &str) -> Option<ResetPart> {
+        match name {
+            "registers" => Some(ResetPart::Registers),
+            "pc" => Some(ResetPart::Pc),
+            "heap" => Some(ResetPart::Heap),
+            "flags" => Some(ResetPart::Flags),
+            "program" => Some(ResetPart::Program),
+            _ => None,
+        }
+    }
+}
+
+/// Number of registers `.registers` and `.set` accept.
+const REGISTER_COUNT: usize = 32;
+
+/// Name, usage and description of every command, in the order `.help` lists
+/// them.
+pub const COMMANDS: [(&str, &str, &str); 25] = [
+    (".help", ".help [command]", "Shows the commands, or how to use one of them"),
+    (".quit", ".quit", "Leaves the REPL"),
+    (".history", ".history", "Shows the numbered history; `!N` runs entry N again"),
+    (".program", ".program", "Shows the bytes of the loaded program"),
+    (".disasm", ".disasm [from] [count]", "Shows instructions decoded from `from`, e.g. `.disasm @loop 4`, or around the pc"),
+    (".registers", ".registers [from[-to]]", "Shows registers that are non-zero or changed, or the given ones, e.g. `.registers 0-7`"),
+    (".set", ".set $reg value", "Stores a value in a register, e.g. `.set $3 -1`"),
+    (".flag", ".flag [true|false]", "Shows or sets the equal flag"),
+    (".pc", ".pc [addr]", "Shows or sets the program counter, e.g. `.pc @loop`"),
+    (".heap", ".heap [size]", "Shows the heap size and the start of the heap, or resizes it"),
+    (".mem", ".mem addr len", "Shows `len` bytes of the heap in hex and ASCII, e.g. `.mem 0 32`"),
+    (".mem.write", ".mem.write addr bytes", "Writes bytes to the heap, e.g. `.mem.write 4 1, 2, 'a'`"),
+    (".symbols", ".symbols [load path]", "Shows the symbols of the loaded program, or names addresses after a symbol map file"),
+    (".listing", ".listing", "Shows the listing of the last assembled file"),
+    (".clear", ".clear", "Removes the loaded program"),
+    (".reset", ".reset [part]...", "Reinitializes the VM, or only its registers, pc, heap, flags or program"),
+    (".load_file", ".load_file path", "Assembles a file and appends it to the program"),
+    (".save", ".save path", "Writes the program and its symbols to a binary file"),
+    (".load_bin", ".load_bin path", "Replaces the program with one written by .save"),
+    (".run", ".run [until @label]", "Runs until the program halts or reaches a breakpoint, or until the pc reaches a label"),
+    (".continue", ".continue", "Runs on from a breakpoint"),
+    (".break", ".break [addr]", "Stops running before the instruction at `addr`, e.g. `.break @loop`, or lists the breakpoints"),
+    (".delete", ".delete [addr]", "Removes the breakpoint at `addr`, or all of them"),
+    (".step", ".step [count]", "Executes one instruction, or `count` of them"),
+    (".next", ".next", "Executes one instruction"),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help { command: Option<String> },
+    Quit,
+    History,
+    Program,
+    Disasm { from: Option<Expression>, count: Option<usize> },
+    Registers { range: Option<RangeInclusive<usize>> },
+    Set { register: usize, value: Expression },
+    Flag { value: Option<bool> },
+    Pc { value: Option<Expression> },
+    Heap { size: Option<Expression> },
+    Mem { address: Expression, len: Expression },
+    MemWrite { address: Expression, bytes: Vec<Expression> },
+    Symbols,
+    LoadSymbols { path: PathBuf },
+    Listing,
+    Clear,
+    /// Reinitializes the given parts, or the whole VM if there are none.
+    Reset { parts: Vec<ResetPart> },
+    LoadFile { path: PathBuf },
+    Save { path: PathBuf },
+    LoadBin { path: PathBuf },
+    Run { until: Option<Expression> },
+    Continue,
+    Break { address: Option<Expression> },
+    Delete { address: Option<Expression> },
+    Step { count: usize },
+}
+
+impl Command {
+    /// Parses a line starting with `.`. Returns `None` for anything else, and
+    /// for assembler directives such as `.equ` and `.include`, which are
+    /// assembled instead. `.set` is the command when its first argument is a
+    /// register and the directive otherwise. Directives that open or close a
+    /// block, such as `.macro`, cannot be typed a line at a time.
+    pub fn parse(line: &str) -> Option<Result<Command, String>> {
+        let line = line.trim();
+        let name = line.split_whitespace().next()?;
+        let directive = name.strip_prefix('.')?.to_lowercase();
+        let arguments = line[name.len()..].trim();
+        let is_set_command = name == ".set" && arguments.starts_with('$');
+        if (DIRECTIVES.contains(&directive.as_str()) && !is_set_command) || directive == "include" {
+            return None;
+        }
+        if PREPROCESSOR_DIRECTIVES.contains(&directive.as_str()) {
+            return Some(Err(format!("`.{directive}` spans several lines and is not supported interactively, use .load_file")));
+        }
+        Some(Command::parse_command(name, arguments))
+    }
+
+    fn parse_command(name: &str, arguments: &str) -> Result<Command, String> {
+        let usage = || {
+            let (_, usage, _) = COMMANDS.iter().find(|(command, _, _)| *command == name).copied().unwrap_or_default();
+            format!("usage: {usage}")
+        };
+        let no_arguments = |command: Command| match arguments.is_empty() {
+            true => Ok(command),
+            false => Err(usage()),
+        };
+        match name {
+            ".help" => match arguments.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => Ok(Command::Help { command: None }),
+                [command] => Ok(Command::Help {
+                    command: Some(format!(".{}", command.trim_start_matches('.'))),
+                }),
+                _ => Err(usage()),
+            },
+            ".quit" => no_arguments(Command::Quit),
+            ".history" => no_arguments(Command::History),
+            ".program" => no_arguments(Command::Program),
+            ".disasm" => match arguments.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => Ok(Command::Disasm { from: None, count: None }),
+                [from] => Ok(Command::Disasm {
+                    from: Some(parse_address(from).ok_or_else(usage)?),
+                    count: None,
+                }),
+                [from, count] => Ok(Command::Disasm {
+                    from: Some(parse_address(from).ok_or_else(usage)?),
+                    count: Some(parse_count(count).ok_or_else(usage)?),
+                }),
+                _ => Err(usage()),
+            },
+            ".registers" if arguments.is_empty() => Ok(Command::Registers { range: None }),
+            ".registers" => parse_register_range(arguments)
+                .map(|range| Command::Registers { range: Some(range) })
+                .ok_or_else(usage),
+            ".set" => {
+                let (register, value) = arguments.split_once(char::is_whitespace).ok_or_else(usage)?;
+                let register = parse_register(register).ok_or_else(usage)?;
+                let value = parse_expression(value).ok_or_else(usage)?;
+                Ok(Command::Set { register, value })
+            }
+            ".flag" => match arguments {
+                "" => Ok(Command::Flag { value: None }),
+                "true" | "1" => Ok(Command::Flag { value: Some(true) }),
+                "false" | "0" => Ok(Command::Flag { value: Some(false) }),
+                _ => Err(usage()),
+            },
+            ".pc" if arguments.is_empty() => Ok(Command::Pc { value: None }),
+            ".pc" => Ok(Command::Pc {
+                value: Some(parse_address(arguments).ok_or_else(usage)?),
+            }),
+            ".heap" if arguments.is_empty() => Ok(Command::Heap { size: None }),
+            ".heap" => Ok(Command::Heap {
+                size: Some(parse_expression(arguments).ok_or_else(usage)?),
+            }),
+            ".mem" => match split_arguments(arguments)[..] {
+                [address, len] => Ok(Command::Mem {
+                    address: parse_address(address).ok_or_else(usage)?,
+                    len: parse_expression(len).ok_or_else(usage)?,
+                }),
+                _ => Err(usage()),
+            },
+            ".mem.write" => {
+                let (address, bytes) = arguments.split_once(char::is_whitespace).ok_or_else(usage)?;
+                Ok(Command::MemWrite {
+                    address: parse_address(address).ok_or_else(usage)?,
+                    bytes: split_arguments(bytes)
+                        .into_iter()
+                        .map(parse_expression)
+                        .collect::<Option<_>>()
+                        .ok_or_else(usage)?,
+                })
+            }
+            ".symbols" if arguments.is_empty() => Ok(Command::Symbols),
+            ".symbols" => match arguments.strip_prefix("load").map(str::trim) {
+                Some(path) if !path.is_empty() => Ok(Command::LoadSymbols { path: PathBuf::from(path) }),
+                _ => Err(usage()),
+            },
+            ".listing" => no_arguments(Command::Listing),
+            ".clear" => no_arguments(Command::Clear),
+            ".reset" => arguments
+                .split_whitespace()
+                .map(ResetPart::parse)
+                .collect::<Option<_>>()
+                .map(|parts| Command::Reset { parts })
+                .ok_or_else(usage),
+            ".load_file" | ".save" | ".load_bin" if arguments.is_empty() => Err(usage()),
+            ".load_file" => Ok(Command::LoadFile {
+                path: PathBuf::from(arguments),
+            }),
+            ".save" => Ok(Command::Save {
+                path: PathBuf::from(arguments),
+            }),
+            ".load_bin" => Ok(Command::LoadBin {
+                path: PathBuf::from(arguments),
+            }),
+            ".run" if arguments.is_empty() => Ok(Command::Run { until: None }),
+            ".run" => {
+                let target = arguments.strip_prefix("until").ok_or_else(usage)?.trim();
+                let target = parse_address(target).ok_or_else(usage)?;
+                Ok(Command::Run { until: Some(target) })
+            }
+            ".continue" => no_arguments(Command::Continue),
+            ".break" if arguments.is_empty() => Ok(Command::Break { address: None }),
+            ".break" => parse_address(arguments).map(|address| Command::Break { address: Some(address) }).ok_or_else(usage),
+            ".delete" if arguments.is_empty() => Ok(Command::Delete { address: None }),
+            ".delete" => parse_address(arguments).map(|address| Command::Delete { address: Some(address) }).ok_or_else(usage),
+            ".step" if arguments.is_empty() => Ok(Command::Step { count: 1 }),
+            ".step" => parse_count(arguments).map(|count| Command::Step { count }).ok_or_else(usage),
+            ".next" => no_arguments(Command::Step { count: 1 }),
+            _ => Err(format!("unknown command `{name}`, type .help for a list of commands")),
+        }
+    }
+}
+
+/// A register such as `$3` or `3`.
+fn parse_register(text: &str) -> Option<usize> {
+    let register: usize = text.trim().trim_start_matches('$').parse().ok()?;
+    (register < REGISTER_COUNT).then_some(register)
+}
+
+/// A register, or an inclusive range such as `0-7`.
+fn parse_register_range(text: &str) -> Option<RangeInclusive<usize>> {
+    let (from, to) = text.split_once('-').unwrap_or((text, text));
+    let (from, to) = (parse_register(from)?, parse_register(to)?);
+    (from <= to).then_some(from..=to)
+}
+
+fn parse_expression(text: &str) -> Option<Expression> {
+    match expression(CompleteStr(text.trim())) {
+        Ok((rest, expression)) if rest.is_empty() => Some(expression),
+        _ => None,
+    }
+}
+
+/// An address such as `16`, `@loop` or `loop+4`.
+fn parse_address(text: &str) -> Option<Expression> {
+    parse_expression(text.trim().trim_start_matches('@'))
+}
+
+fn parse_count(text: &str) -> Option<usize> {
+    text.parse().ok().filter(|count| *count > 0)
+}
+
+/// The text `.help` prints, for every command or for the named one.
+pub fn help(command: Option<&str>) -> Result<String, String> {
+    match command {
+        None => Ok(COMMANDS
+            .iter()
+            .map(|(_, usage, description)| format!("{usage:<24}{description}\n"))
+            .collect()),
+        Some(name) => COMMANDS
+            .iter()
+            .find(|(command, _, _)| *command == name)
+            .map(|(_, usage, description)| format!("usage: {usage}\n{description}\n"))
+            .ok_or_else(|| format!("unknown command `{name}`, type .help for a list of commands")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Command, String> {
+        Command::parse(line).expect("not a command")
+    }
+
+    #[test]
+    fn test_parse_commands_with_arguments() {
+        assert_eq!(parse(".load_file  examples/count.iasm"), Ok(Command::LoadFile { path: PathBuf::from("examples/count.iasm") }));
+        assert_eq!(parse(".step 10"), Ok(Command::Step { count: 10 }));
+        assert_eq!(parse(".next"), Ok(Command::Step { count: 1 }));
+        assert_eq!(parse(".registers 0-7"), Ok(Command::Registers { range: Some(0..=7) }));
+        assert_eq!(parse(".registers $4"), Ok(Command::Registers { range: Some(4..=4) }));
+        assert_eq!(parse(".help step"), Ok(Command::Help { command: Some(".step".to_string()) }));
+        assert!(matches!(parse(".set $3 42"), Ok(Command::Set { register: 3, .. })));
+        assert!(matches!(parse(".run until @loop"), Ok(Command::Run { until: Some(_) })));
+        assert!(matches!(parse(".break @loop"), Ok(Command::Break { address: Some(_) })));
+        assert_eq!(parse(".delete"), Ok(Command::Delete { address: None }));
+        assert_eq!(parse(".continue"), Ok(Command::Continue));
+        assert!(matches!(parse(".disasm @loop 4"), Ok(Command::Disasm { from: Some(_), count: Some(4) })));
+        assert_eq!(parse(".disasm"), Ok(Command::Disasm { from: None, count: None }));
+        assert!(matches!(parse(".mem BUFFER 16"), Ok(Command::Mem { .. })));
+        assert_eq!(parse(".reset"), Ok(Command::Reset { parts: vec![] }));
+        assert_eq!(parse(".flag true"), Ok(Command::Flag { value: Some(true) }));
+        assert!(matches!(parse(".pc @loop"), Ok(Command::Pc { value: Some(_) })));
+        assert_eq!(parse(".reset pc heap"), Ok(Command::Reset { parts: vec![ResetPart::Pc, ResetPart::Heap] }));
+        assert_eq!(parse(".save out.bin"), Ok(Command::Save { path: PathBuf::from("out.bin") }));
+        assert_eq!(parse(".symbols load out.map"), Ok(Command::LoadSymbols { path: PathBuf::from("out.map") }));
+        assert!(matches!(parse(".mem.write 4 1, ' ', 0xff"), Ok(Command::MemWrite { bytes, .. }) if bytes.len() == 3));
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_commands() {
+        assert_eq!(parse(".load_file"), Err("usage: .load_file path".to_string()));
+        assert_eq!(parse(".step 0"), Err("usage: .step [count]".to_string()));
+        assert_eq!(parse(".disasm 0 x"), Err("usage: .disasm [from] [count]".to_string()));
+        assert_eq!(parse(".mem.write 4"), Err("usage: .mem.write addr bytes".to_string()));
+        assert_eq!(parse(".reset stack"), Err("usage: .reset [part]...".to_string()));
+        assert_eq!(parse(".flag yes"), Err("usage: .flag [true|false]".to_string()));
+        assert_eq!(parse(".load_bin"), Err("usage: .load_bin path".to_string()));
+        assert_eq!(parse(".registers 7-0"), Err("usage: .registers [from[-to]]".to_string()));
+        assert_eq!(parse(".set $32 1"), Err("usage: .set $reg value".to_string()));
+        assert_eq!(parse(".quit now"), Err("usage: .quit".to_string()));
+        assert_eq!(parse(".continue 2"), Err("usage: .continue".to_string()));
+        assert_eq!(parse(".symbols load"), Err("usage: .symbols [load path]".to_string()));
+        assert_eq!(parse(".frobnicate"), Err("unknown command `.frobnicate`, type .help for a list of commands".to_string()));
+        for directive in [".macro double reg", ".ENDM", ".if DEBUG", ".else", ".endif"] {
+            let message = parse(directive).unwrap_err();
+            assert!(message.ends_with("spans several lines and is not supported interactively, use .load_file"), "{message}");
+        }
+    }
+
+    #[test]
+    fn test_directives_and_instructions_are_not_commands() {
+        assert_eq!(Command::parse(".equ SIZE 4"), None);
+        assert_eq!(Command::parse(".set step 2"), None);
+        assert_eq!(Command::parse(".include \"lib.iasm\""), None);
+        assert_eq!(Command::parse("load $1 #2"), None);
+    }
+
+    #[test]
+    fn test_help() {
+        assert_eq!(help(Some(".step")), Ok("usage: .step [count]\nExecutes one instruction, or `count` of them\n".to_string()));
+        assert!(help(None).unwrap().lines().count() == COMMANDS.len());
+        assert!(help(Some(".nope")).is_err());
+    }
+}
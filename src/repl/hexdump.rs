@@ -0,0 +1,43 @@
+/// Bytes shown on each row.
+const BYTES_PER_ROW: usize = 16;
+
+/// Formats `bytes` as rows of an address, the bytes in hex and the bytes as
+/// ASCII, with `.` for anything not printable. Addresses start at `base`.
+pub fn hexdump(bytes: &[u8], base: usize) -> String {
+    let mut output = String::new();
+    for (row, chunk) in bytes.chunks(BYTES_PER_ROW).enumerate() {
+        let mut hex = String::new();
+        for (i, byte) in chunk.iter().enumerate() {
+            if i == BYTES_PER_ROW / 2 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{byte:02x} "));
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| match byte.is_ascii_graphic() || byte == b' ' {
+                true => byte as char,
+                false => '.',
+            })
+            .collect();
+        let width = 3 * BYTES_PER_ROW + 1;
+        output.push_str(&format!("{:04x}  {hex:<width$} |{ascii}|\n", base + row * BYTES_PER_ROW));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hexdump() {
+        let bytes: Vec<u8> = b"Hello, heap!\0\x01\x7f\xff end".to_vec();
+        assert_eq!(
+            hexdump(&bytes, 32),
+            "0020  48 65 6c 6c 6f 2c 20 68  65 61 70 21 00 01 7f ff  |Hello, heap!....|\n\
+             0030  20 65 6e 64                                       | end|\n"
+        );
+        assert_eq!(hexdump(&[], 0), "");
+    }
+}
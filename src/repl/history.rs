@@ -0,0 +1,117 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Number of entries kept, in memory and in the history file.
+const MAX_ENTRIES: usize = 1000;
+
+/// Lines entered at the prompt, oldest first. A history opened from a file
+/// appends every new entry to it, so it carries over to the next session.
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl History {
+    /// An empty history that is not saved anywhere.
+    pub fn new() -> History {
+        History::default()
+    }
+
+    /// Reads the history kept in `path`, which does not have to exist yet.
+    pub fn open(path: &Path) -> io::Result<History> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+        let mut entries: Vec<String> = contents.lines().filter(|line| !line.is_empty()).map(String::from).collect();
+        if entries.len() > MAX_ENTRIES {
+            entries.drain(..entries.len() - MAX_ENTRIES);
+            fs::write(path, entries.iter().map(|entry| format!("{entry}\n")).collect::<String>())?;
+        }
+        Ok(History {
+            entries,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Entry `number` as `.history` numbers them, starting at 1.
+    pub fn get(&self, number: usize) -> Option<&str> {
+        self.entries.get(number.checked_sub(1)?).map(String::as_str)
+    }
+
+    /// Adds a line and appends it to the history file. Empty lines are not
+    /// kept. If the file cannot be written the error is returned once and
+    /// the history is only kept in memory from then on.
+    pub fn push(&mut self, line: &str) -> io::Result<()> {
+        if line.is_empty() {
+            return Ok(());
+        }
+        self.entries.push(line.to_string());
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+        if let Some(path) = &self.path {
+            let result = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{line}"));
+            if result.is_err() {
+                self.path = None;
+            }
+            return result;
+        }
+        Ok(())
+    }
+
+    /// Index of the most recent entry before `before` that contains `query`.
+    pub fn search(&self, query: &str, before: usize) -> Option<usize> {
+        if query.is_empty() {
+            return None;
+        }
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let mut history = History::new();
+        for line in ["load $1 #5", ".registers", "load $2 #6", ""] {
+            history.push(line).unwrap();
+        }
+        assert_eq!(history.entries().len(), 3);
+        assert_eq!(history.get(2), Some(".registers"));
+        assert_eq!(history.get(0), None);
+        assert_eq!(history.search("load", 3), Some(2));
+        assert_eq!(history.search("load", 2), Some(0));
+        assert_eq!(history.search("load", 0), None);
+        assert_eq!(history.search("", 3), None);
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = std::env::temp_dir().join(format!("history-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut history = History::open(&path).unwrap();
+        history.push(".pc").unwrap();
+        history.push("load $1 #5").unwrap();
+        let reopened = History::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reopened.entries(), [".pc", "load $1 #5"]);
+    }
+}
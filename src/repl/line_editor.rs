@@ -0,0 +1,438 @@
+use crate::repl::history::History;
+use std::io;
+use std::io::{IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+
+/// A key press, decoded from the bytes a terminal sends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Char(char),
+    /// A letter pressed with Ctrl, such as `Ctrl('r')`.
+    Ctrl(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Unknown,
+}
+
+/// Reads one key press. Returns `None` at the end of the input.
+pub fn read_key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let byte = match read_byte(input)? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => read_escape(input)?,
+        0x01..=0x1a => Key::Ctrl((b'a' + byte - 1) as char),
+        0x20..=0x7e => Key::Char(byte as char),
+        0x80.. => read_utf8(byte, input)?,
+        _ => Key::Unknown,
+    };
+    Ok(Some(key))
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Decodes the rest of an escape sequence, e.g. `[A` for the up arrow or
+/// `[3~` for delete.
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    let key = match read_byte(input)? {
+        Some(b'[') => {
+            let mut parameters = Vec::new();
+            loop {
+                match read_byte(input)? {
+                    Some(byte @ (b'0'..=b'9' | b';')) => parameters.push(byte),
+                    Some(byte) => break escape_key(&parameters, byte),
+                    None => break Key::Unknown,
+                }
+            }
+        }
+        Some(b'O') => read_byte(input)?.map_or(Key::Unknown, |byte| escape_key(&[], byte)),
+        _ => Key::Unknown,
+    };
+    Ok(key)
+}
+
+fn escape_key(parameters: &[u8], last: u8) -> Key {
+    match (parameters, last) {
+        (_, b'A') => Key::Up,
+        (_, b'B') => Key::Down,
+        (_, b'C') => Key::Right,
+        (_, b'D') => Key::Left,
+        (_, b'H') | (b"1" | b"7", b'~') => Key::Home,
+        (_, b'F') | (b"4" | b"8", b'~') => Key::End,
+        (b"3", b'~') => Key::Delete,
+        _ => Key::Unknown,
+    }
+}
+
+fn read_utf8(first: u8, input: &mut impl Read) -> io::Result<Key> {
+    let len = match first {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Ok(Key::Unknown),
+    };
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        match read_byte(input)? {
+            Some(byte) => bytes.push(byte),
+            None => return Ok(Key::Unknown),
+        }
+    }
+    let key = std::str::from_utf8(&bytes).ok().and_then(|text| text.chars().next());
+    Ok(key.map_or(Key::Unknown, Key::Char))
+}
+
+/// What the caller should do after a key press.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Continue,
+    Submit(String),
+    /// Ctrl-C: drop the line and start over.
+    Cancel,
+    /// Ctrl-D on an empty line.
+    Eof,
+}
+
+/// A reverse search started with Ctrl-R.
+#[derive(Debug)]
+struct Search {
+    query: String,
+    /// Index of the history entry shown.
+    found: Option<usize>,
+    /// The line as it was before searching, restored by Ctrl-G.
+    original: Vec<char>,
+}
+
+/// The line being edited, with emacs-style key bindings.
+#[derive(Debug, Default)]
+pub struct LineState {
+    buffer: Vec<char>,
+    cursor: usize,
+    /// History entry shown by the arrow keys, and the line that was being
+    /// typed before browsing started.
+    browsing: Option<(usize, Vec<char>)>,
+    search: Option<Search>,
+}
+
+impl LineState {
+    pub fn line(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    pub fn handle(&mut self, key: Key, history: &History) -> Action {
+        if let Some(action) = self.handle_search(key, history) {
+            return action;
+        }
+        match key {
+            Key::Char(c) => {
+                self.buffer.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Enter => return Action::Submit(self.line()),
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.buffer.remove(self.cursor);
+            }
+            Key::Ctrl('d') if self.buffer.is_empty() => return Action::Eof,
+            Key::Delete | Key::Ctrl('d') if self.cursor < self.buffer.len() => {
+                self.buffer.remove(self.cursor);
+            }
+            Key::Left | Key::Ctrl('b') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.cursor = (self.cursor + 1).min(self.buffer.len()),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.buffer.len(),
+            Key::Ctrl('k') => self.buffer.truncate(self.cursor),
+            Key::Ctrl('u') => {
+                self.buffer.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Ctrl('w') => {
+                let mut start = self.cursor;
+                while start > 0 && self.buffer[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !self.buffer[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                self.buffer.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Up | Key::Ctrl('p') => self.previous(history),
+            Key::Down | Key::Ctrl('n') => self.next(history),
+            Key::Ctrl('r') => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                    original: self.buffer.clone(),
+                })
+            }
+            Key::Ctrl('c') => return Action::Cancel,
+            _ => {}
+        }
+        Action::Continue
+    }
+
+    /// Handles a key while searching. Keys that end the search accept the
+    /// entry found and return `None`, so they take effect on it as usual.
+    fn handle_search(&mut self, key: Key, history: &History) -> Option<Action> {
+        let search = self.search.as_mut()?;
+        match key {
+            Key::Char(c) => {
+                search.query.push(c);
+                let before = search.found.map_or(history.entries().len(), |found| found + 1);
+                search.found = history.search(&search.query, before);
+            }
+            Key::Backspace => {
+                search.query.pop();
+                search.found = history.search(&search.query, history.entries().len());
+            }
+            Key::Ctrl('r') => {
+                let before = search.found.unwrap_or(history.entries().len());
+                search.found = history.search(&search.query, before).or(search.found);
+            }
+            Key::Ctrl('g') | Key::Ctrl('c') => {
+                let original = std::mem::take(&mut search.original);
+                self.search = None;
+                self.set_buffer(original);
+            }
+            _ => {
+                if let Some(found) = search.found {
+                    self.set_buffer(history.entries()[found].chars().collect());
+                }
+                self.search = None;
+                return None;
+            }
+        }
+        Some(Action::Continue)
+    }
+
+    fn previous(&mut self, history: &History) {
+        let index = self.browsing.as_ref().map_or(history.entries().len(), |(index, _)| *index);
+        if index == 0 {
+            return;
+        }
+        let draft = match self.browsing.take() {
+            Some((_, draft)) => draft,
+            None => self.buffer.clone(),
+        };
+        self.browsing = Some((index - 1, draft));
+        self.set_buffer(history.entries()[index - 1].chars().collect());
+    }
+
+    fn next(&mut self, history: &History) {
+        let (index, draft) = match self.browsing.take() {
+            Some(browsing) => browsing,
+            None => return,
+        };
+        match history.entries().get(index + 1) {
+            Some(entry) => {
+                self.set_buffer(entry.chars().collect());
+                self.browsing = Some((index + 1, draft));
+            }
+            None => self.set_buffer(draft),
+        }
+    }
+
+    fn set_buffer(&mut self, buffer: Vec<char>) {
+        self.cursor = buffer.len();
+        self.buffer = buffer;
+    }
+
+    /// What to write to the terminal to redraw the line after `prompt` and
+    /// put the cursor in place.
+    pub fn render(&self, prompt: &str, history: &History) -> String {
+        let (prompt, line, cursor) = match &self.search {
+            Some(search) => {
+                let found = search.found.map_or("", |found| history.entries()[found].as_str());
+                let failed = match search.found.is_none() && !search.query.is_empty() {
+                    true => "failed ",
+                    false => "",
+                };
+                let prompt = format!("({failed}reverse-i-search)`{}': ", search.query);
+                (prompt, found.to_string(), found.chars().count())
+            }
+            None => (prompt.to_string(), self.line(), self.cursor),
+        };
+        let mut output = format!("\r\x1b[K{prompt}{line}");
+        let back = line.chars().count() - cursor;
+        if back > 0 {
+            output.push_str(&format!("\x1b[{back}D"));
+        }
+        output
+    }
+}
+
+/// Keeps the terminal from echoing input or buffering it by line while it
+/// lives, and restores the previous settings when dropped. The settings are
+/// changed with `stty`.
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> Option<RawMode> {
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "-isig", "-ixon", "min", "1"])?;
+        Some(RawMode {
+            saved: saved.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Shows `prompt` and reads a line from stdin. On a terminal the line can be
+/// edited, and earlier entries recalled with the arrow keys or searched with
+/// Ctrl-R; otherwise lines are read as they come. Returns `None` at the end
+/// of the input.
+pub fn read_line(prompt: &str, history: &History) -> io::Result<Option<String>> {
+    let raw_mode = match io::stdin().is_terminal() && io::stdout().is_terminal() {
+        true => RawMode::enable(),
+        false => None,
+    };
+    match raw_mode {
+        Some(_raw_mode) => edit_line(prompt, history),
+        None => {
+            print!("{prompt}");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line)? {
+                0 => Ok(None),
+                _ => Ok(Some(line.trim_end_matches(['\n', '\r']).to_string())),
+            }
+        }
+    }
+}
+
+fn edit_line(prompt: &str, history: &History) -> io::Result<Option<String>> {
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout();
+    let mut state = LineState::default();
+    write!(stdout, "{}", state.render(prompt, history))?;
+    stdout.flush()?;
+    while let Some(key) = read_key(&mut stdin)? {
+        match state.handle(key, history) {
+            Action::Continue => {}
+            Action::Submit(line) => {
+                writeln!(stdout, "{}", state.render(prompt, history))?;
+                return Ok(Some(line));
+            }
+            Action::Cancel => {
+                writeln!(stdout, "^C")?;
+                state = LineState::default();
+            }
+            Action::Eof => {
+                writeln!(stdout)?;
+                return Ok(None);
+            }
+        }
+        write!(stdout, "{}", state.render(prompt, history))?;
+        stdout.flush()?;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(mut input: &[u8]) -> Vec<Key> {
+        let mut keys = vec![];
+        while let Some(key) = read_key(&mut input).unwrap() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    fn history(lines: &[&str]) -> History {
+        let mut history = History::new();
+        for line in lines {
+            history.push(line).unwrap();
+        }
+        history
+    }
+
+    fn type_keys(state: &mut LineState, history: &History, input: &[u8]) -> Action {
+        keys(input).into_iter().fold(Action::Continue, |_, key| state.handle(key, history))
+    }
+
+    #[test]
+    fn test_read_keys() {
+        assert_eq!(
+            keys(b"a\x1b[A\x1b[3~\x1bOH\x7f\x12\r"),
+            [Key::Char('a'), Key::Up, Key::Delete, Key::Home, Key::Backspace, Key::Ctrl('r'), Key::Enter]
+        );
+        assert_eq!(keys("é".as_bytes()), [Key::Char('é')]);
+    }
+
+    #[test]
+    fn test_edit_line() {
+        let history = History::new();
+        let mut state = LineState::default();
+        type_keys(&mut state, &history, b"lod $1\x1b[D\x1b[D\x1b[D\x1b[Da");
+        assert_eq!(state.line(), "load $1");
+        assert_eq!(state.render(">>> ", &history), "\r\x1b[K>>> load $1\x1b[4D");
+        assert_eq!(type_keys(&mut state, &history, b"\x05 #5\x17#6\r"), Action::Submit("load $1 #6".to_string()));
+    }
+
+    #[test]
+    fn test_browse_history() {
+        let history = history(&["load $1 #5", ".registers"]);
+        let mut state = LineState::default();
+        type_keys(&mut state, &history, b".p\x1b[A\x1b[A\x1b[A");
+        assert_eq!(state.line(), "load $1 #5");
+        type_keys(&mut state, &history, b"\x1b[B");
+        assert_eq!(state.line(), ".registers");
+        type_keys(&mut state, &history, b"\x1b[B");
+        assert_eq!(state.line(), ".p");
+    }
+
+    #[test]
+    fn test_reverse_search() {
+        let history = history(&["load $1 #5", ".registers", "load $2 #6"]);
+        let mut state = LineState::default();
+        type_keys(&mut state, &history, b"\x12load");
+        assert_eq!(state.render(">>> ", &history), "\r\x1b[K(reverse-i-search)`load': load $2 #6");
+        type_keys(&mut state, &history, b"\x12\x12");
+        assert_eq!(state.render(">>> ", &history), "\r\x1b[K(reverse-i-search)`load': load $1 #5");
+        type_keys(&mut state, &history, b"x");
+        assert!(state.render(">>> ", &history).contains("(failed reverse-i-search)`loadx': "));
+        type_keys(&mut state, &history, b"\x7f\x07");
+        assert_eq!(state.line(), "");
+        assert_eq!(type_keys(&mut state, &history, b"\x12regi\r"), Action::Submit(".registers".to_string()));
+    }
+}
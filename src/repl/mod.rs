@@ -1,112 +1,827 @@
-use std::fs::File;
-use crate::assembler::program_parser::program;
-use crate::vm::VM;
+pub mod command;
+pub mod hexdump;
+pub mod history;
+pub mod line_editor;
+pub mod server;
+
+use crate::assembler::assembled_program::{AssembledProgram, AssemblySession};
+use crate::assembler::listing::Listing;
+use crate::assembler::expression_parser::Expression;
+use crate::assembler::preprocessor::split_comment;
+use crate::assembler::SymbolTable;
+use crate::program_file::ProgramFile;
+use crate::repl::command::{help, Command, ResetPart};
+use crate::symbol_map::SymbolMap;
+use crate::repl::hexdump::hexdump;
+use crate::repl::history::History;
+use crate::vm::{StopReason, VM};
 use std::io;
-use std::io::{Read, Write};
+use std::io::{BufRead, Write};
+use std::ops::RangeInclusive;
 use std::path::Path;
-use nom::types::CompleteStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
 use crate::assembler::Assembler;
+use crate::disassembler::{disassemble, INSTRUCTION_SIZE};
+
+const PROMPT: &str = ">>> ";
+
+const GREETING: &str = "Back at it again.";
+
+/// Instructions `.disasm` shows before the pc, and in all, when not told.
+const DISASM_BEFORE_PC: usize = 2;
+const DISASM_COUNT: usize = 8;
+
+/// Bytes of the heap `.heap` shows.
+const HEAP_PREVIEW: usize = 256;
 
-pub struct REPL {
-    command_buffer: Vec<String>,
+/// Instructions run between unlocking the machine, so REPLs sharing it are
+/// not held up by a long `.run`.
+const RUN_SLICE: usize = 10_000;
+
+/// The VM and the program assembled into it. REPLs sharing a machine see
+/// and change the same VM.
+pub struct Machine {
     vm: VM,
-    asm: Assembler,
+    /// Assembles everything loaded into the VM, so later lines can refer to
+    /// labels and constants defined earlier.
+    session: AssemblySession,
+    /// Listing of the last assembled file.
+    listing: Option<Listing>,
+    /// Where the program starts, as `.save` records it.
+    entry: usize,
+}
+
+pub type SharedMachine = Arc<Mutex<Machine>>;
+
+impl Machine {
+    /// Creates a machine whose programs are assembled by `asm`, e.g. one set
+    /// up with defines and include directories from the command line.
+    pub fn new(mut asm: Assembler) -> Machine {
+        asm.generate_listing = true;
+        Machine {
+            vm: VM::new(),
+            session: AssemblySession::new(asm),
+            listing: None,
+            entry: 0,
+        }
+    }
+
+    pub fn shared(asm: Assembler) -> SharedMachine {
+        Arc::new(Mutex::new(Machine::new(asm)))
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    /// Removes the program, its symbols and its listing.
+    fn clear_program(&mut self) {
+        self.vm.program.clear();
+        self.vm.symbols = SymbolMap::new();
+        self.session = AssemblySession::new(self.session.assembler().clone());
+        self.listing = None;
+        self.entry = 0;
+    }
+
+    /// Evaluates an address or a length against the symbols assembled so far.
+    fn evaluate_address(&self, expression: &Expression) -> Result<usize, String> {
+        let value = expression.evaluate(self.session.symbols()).map_err(|error| error.to_string())?;
+        usize::try_from(value).map_err(|_| format!("{value} is not an address"))
+    }
+
+    /// `len` bytes of the heap from `address`, or why they are out of range.
+    fn heap_range(&self, address: usize, len: usize) -> Result<&[u8], String> {
+        let heap = self.vm.heap();
+        address
+            .checked_add(len)
+            .and_then(|end| heap.get(address..end))
+            .ok_or_else(|| format!("{address}..{} is outside the heap of {} bytes", address.saturating_add(len), heap.len()))
+    }
+
+    /// An address in hex, with the closest label if there are symbols, e.g.
+    /// `0008 (loop+4)`.
+    fn describe_address(&self, address: usize) -> String {
+        match self.vm.symbols.is_empty() {
+            true => format!("{address:04x}"),
+            false => format!("{address:04x} ({})", self.vm.symbols.describe_address(address)),
+        }
+    }
+
+    /// Why running stopped, for the prompt. A fault is an error, so that a
+    /// script hitting one fails.
+    fn stopped(&self, reason: StopReason) -> Result<String, String> {
+        match reason {
+            StopReason::Halted => Ok("halted\n".to_string()),
+            StopReason::EndOfProgram => Ok("ran off the end of the program\n".to_string()),
+            StopReason::Breakpoint(address) => Ok(format!("stopped at breakpoint {}\n", self.describe_address(address))),
+            StopReason::Fault { address, fault } => Err(format!("{fault} at {}", self.describe_address(address))),
+        }
+    }
+
+    /// Decoded instructions from `from` on, with their labels and the one at
+    /// the pc marked `=>`.
+    fn disassembly(&self, from: usize, count: usize) -> Result<String, String> {
+        let instructions = disassemble(&self.vm.program, &self.vm.symbols, from, count);
+        if instructions.is_empty() {
+            return Err(format!("there is no code at {from}, the program is {} bytes long", self.vm.program.len()));
+        }
+        let mut output = String::new();
+        for instruction in instructions {
+            for label in &instruction.labels {
+                output.push_str(&format!("{label}:\n"));
+            }
+            let marker = match instruction.offset == self.vm.pc {
+                true => "=>",
+                false => "",
+            };
+            let bytes: Vec<String> = instruction.bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+            output.push_str(&format!("{marker:<2} {:04x}  {:<11}  {}\n", instruction.offset, bytes.join(" "), instruction.text));
+        }
+        Ok(output)
+    }
+
+    /// Assembles a line typed at the prompt onto the end of the program and
+    /// executes it. A line that does not assemble is not added. Execution
+    /// waits at a line that uses labels not defined yet, until they are.
+    fn assemble_line(&mut self, line: &str) -> Result<String, String> {
+        let assembled_program = self.session.add(line).map_err(|errors| {
+            let errors: Vec<String> = errors.iter().map(|error| error.kind.to_string()).collect();
+            errors.join("\n")
+        })?;
+        self.load(&assembled_program);
+        match self.session.unresolved_at(self.vm.pc) {
+            Some(names) => Ok(format!(
+                "waiting for `{}` to be defined before running {:04x}\n",
+                names.join("`, `"),
+                self.vm.pc
+            )),
+            // Lines such as `.equ` add no code to run.
+            None => match self.vm.run_once() {
+                None | Some(StopReason::EndOfProgram) => Ok(String::new()),
+                Some(reason) => self.stopped(reason),
+            },
+        }
+    }
+
+    /// Gives the VM the session's program, which includes the new piece and
+    /// any earlier ones fixed up by it.
+    /// A piece with an `.entry` pointing past its start moves the entry.
+    fn load(&mut self, assembled_program: &AssembledProgram) {
+        self.vm.program = self.session.image().to_vec();
+        self.vm.symbols = assembled_program.symbol_map();
+        if assembled_program.entry != assembled_program.code_offset {
+            self.entry = assembled_program.entry as usize;
+        }
+    }
+}
+
+/// Reads commands and assembly lines and writes what they show to `W`.
+pub struct REPL<W: Write = io::Stdout> {
+    history: History,
+    machine: SharedMachine,
+    /// Registers as `.registers` last showed them, to point out changes.
+    shown_registers: [i32; 32],
+    /// Set by `.quit`.
+    quit: bool,
+    /// Instructions a single `.run`, `.continue` or `.step` may execute.
+    step_limit: Option<usize>,
+    /// Whether commands may read and write files.
+    allow_files: bool,
+    output: W,
+}
+
+/// How `run_program` runs the VM.
+enum RunMode {
+    /// Until it stops, or until the pc reaches the address.
+    Run { until: Option<Expression> },
+    /// Like `Run`, but past a breakpoint at the pc.
+    Continue,
+    /// This many instructions, without stopping at breakpoints.
+    Step { count: usize },
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl REPL {
     pub fn new() -> REPL {
+        REPL::with_assembler(Assembler::new())
+    }
+
+    /// Creates a REPL on stdout whose programs are assembled by `asm`.
+    pub fn with_assembler(asm: Assembler) -> REPL {
+        REPL::with_machine(Machine::shared(asm), io::stdout())
+    }
+
+    /// Reads lines from the terminal, with line editing and history if stdin
+    /// is one, until the input ends or `.quit`.
+    pub fn run_terminal(&mut self) -> io::Result<()> {
+        writeln!(self.output, "{GREETING}")?;
+        while !self.quit {
+            let line = match line_editor::read_line(PROMPT, &self.history)? {
+                Some(line) => line,
+                None => break,
+            };
+            self.respond(&line)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> REPL<W> {
+    /// Creates a REPL writing to `output` that works on `machine`, which other
+    /// REPLs may share.
+    pub fn with_machine(machine: SharedMachine, output: W) -> REPL<W> {
         REPL {
-            command_buffer: vec![],
-            vm: VM::new(),
-            asm: Assembler::new(),
-        }
-    }
-
-    pub fn run(&mut self) {
-        println!("Back at it again.");
-        loop {
-            let mut buffer = String::new();
-            let stdin = io::stdin();
-            print!(">>> ");
-            io::stdout().flush().expect("Unable to flush stdout");
-            stdin.read_line(&mut buffer).expect("Unable to read line");
-            let buffer = buffer.trim();
-            self.command_buffer.push(buffer.to_string());
-            match buffer {
-                ".quit" => {
-                    println!("Already leavin?");
-                    std::process::exit(0);
+            history: History::new(),
+            machine,
+            shown_registers: [0; 32],
+            quit: false,
+            step_limit: None,
+            allow_files: true,
+            output,
+        }
+    }
+
+    /// Stops running after `limit` instructions, for programs that may loop
+    /// forever where nobody can interrupt them.
+    pub fn with_step_limit(mut self, limit: usize) -> REPL<W> {
+        self.step_limit = Some(limit);
+        self
+    }
+
+    /// Refuses the commands that read or write files, for sessions whose
+    /// user should not see the files of the machine the REPL runs on. Only
+    /// the machine's assembler can refuse `.include`.
+    pub fn without_files(mut self) -> REPL<W> {
+        self.allow_files = false;
+        self
+    }
+
+    pub fn machine(&self) -> &SharedMachine {
+        &self.machine
+    }
+
+    /// Keeps the history in `path`, starting with the entries already there.
+    pub fn load_history(&mut self, path: &Path) -> io::Result<()> {
+        self.history = History::open(path)?;
+        Ok(())
+    }
+
+    /// Shows a prompt and reads lines from `input` until it ends or `.quit`.
+    pub fn run(&mut self, mut input: impl BufRead) -> io::Result<()> {
+        writeln!(self.output, "{GREETING}")?;
+        while !self.quit {
+            write!(self.output, "{PROMPT}")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                break;
+            }
+            self.respond(&line)?;
+        }
+        Ok(())
+    }
+
+    /// Runs a line and writes its output or error.
+    fn respond(&mut self, line: &str) -> io::Result<bool> {
+        let (text, ok) = match self.process_line(line) {
+            Ok(output) => (output, true),
+            Err(message) => (format!("{message}\n"), false),
+        };
+        self.output.write_all(text.as_bytes())?;
+        self.output.flush()?;
+        Ok(ok)
+    }
+
+    /// Runs each line of `script` as if it was typed at the prompt, and shows
+    /// it after the prompt so the output reads like a session. Blank and
+    /// comment-only lines are skipped, and `.quit` ends the script early.
+    /// Returns the number of lines that failed.
+    pub fn run_script(&mut self, script: &str) -> io::Result<usize> {
+        let mut failures = 0;
+        for line in script.lines() {
+            if split_comment(line).0.trim().is_empty() {
+                continue;
+            }
+            writeln!(self.output, "{PROMPT}{}", line.trim())?;
+            if !self.respond(line)? {
+                failures += 1;
+            }
+            if self.quit {
+                break;
+            }
+        }
+        Ok(failures)
+    }
+
+    /// Runs a command or assembles an instruction. `!N` stands for entry `N`
+    /// of the history, which is shown and added to the history again.
+    /// Returns what the line shows.
+    fn process_line(&mut self, line: &str) -> Result<String, String> {
+        let mut output = String::new();
+        let mut line = line.trim().to_string();
+        if let Some(number) = line.strip_prefix('!') {
+            let entry = number.trim().parse().ok().and_then(|number| self.history.get(number));
+            line = entry.ok_or_else(|| format!("there is no entry `{number}` in the history"))?.to_string();
+            output.push_str(&format!("{line}\n"));
+        }
+        if let Err(error) = self.history.push(&line) {
+            output.push_str(&format!("unable to save history: {error}\n"));
+        }
+        let result = match Command::parse(&line) {
+            Some(Ok(command)) => self.execute(command)?,
+            Some(Err(message)) => return Err(message),
+            None => self.lock().assemble_line(&line)?,
+        };
+        output.push_str(&result);
+        Ok(output)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Machine> {
+        // A command that panicked leaves the machine usable.
+        self.machine.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Executes a command. A command that fails leaves the VM and the
+    /// session as they were and returns the message to show at the prompt.
+    /// Otherwise returns what the command shows.
+    fn execute(&mut self, command: Command) -> Result<String, String> {
+        match command {
+            Command::Run { until } => self.run_program(RunMode::Run { until }),
+            Command::Continue => self.run_program(RunMode::Continue),
+            Command::Step { count } => self.run_program(RunMode::Step { count }),
+            Command::LoadFile { .. } | Command::Save { .. } | Command::LoadBin { .. } | Command::LoadSymbols { .. }
+                if !self.allow_files =>
+            {
+                Err("files cannot be read or written in this session".to_string())
+            }
+            command => self.execute_locked(command),
+        }
+    }
+
+    /// Runs the VM, unlocking the machine every `RUN_SLICE` instructions.
+    /// Returns why it stopped.
+    fn run_program(&mut self, mode: RunMode) -> Result<String, String> {
+        let mut machine = self.lock();
+        let limit = self.step_limit.unwrap_or(usize::MAX);
+        let (count, breakpoints, past_breakpoint) = match &mode {
+            RunMode::Run { until: None } => (limit, true, false),
+            // Runs to a breakpoint at the target that is removed again,
+            // unless it was set with .break.
+            RunMode::Run { until: Some(_) } | RunMode::Continue => (limit, true, true),
+            RunMode::Step { count } => (limit.min(*count), false, false),
+        };
+        let target = match &mode {
+            RunMode::Run { until: Some(target) } => Some(machine.evaluate_address(target)?),
+            _ => None,
+        };
+        let temporary = target.filter(|&target| machine.vm.add_breakpoint(target));
+        let mut executed = 0;
+        let reason = loop {
+            if executed == count {
+                break None;
+            }
+            let pc = machine.vm.pc;
+            if breakpoints && machine.vm.has_breakpoint(pc) && !(past_breakpoint && executed == 0) {
+                break Some(StopReason::Breakpoint(pc));
+            }
+            if let Some(reason) = machine.vm.run_once() {
+                break Some(reason);
+            }
+            executed += 1;
+            if executed % RUN_SLICE == 0 {
+                drop(machine);
+                thread::yield_now();
+                machine = self.lock();
+            }
+        };
+        if let Some(target) = temporary {
+            machine.vm.remove_breakpoint(target);
+        }
+        match reason {
+            Some(StopReason::Breakpoint(address)) if Some(address) == target => Ok(String::new()),
+            Some(reason) => machine.stopped(reason),
+            None if matches!(mode, RunMode::Step { count } if count == executed) => Ok(String::new()),
+            None => Ok(format!("stopped after {executed} instructions, the limit for this session; .continue goes on\n")),
+        }
+    }
+
+    /// Executes a command other than running the VM, with the machine locked.
+    fn execute_locked(&mut self, command: Command) -> Result<String, String> {
+        let shared = Arc::clone(&self.machine);
+        let mut guard = shared.lock().unwrap_or_else(PoisonError::into_inner);
+        let machine = &mut *guard;
+        let mut output = String::new();
+        match command {
+            Command::Help { command } => output = help(command.as_deref())?,
+            Command::Quit => {
+                output.push_str("Already leavin?\n");
+                self.quit = true;
+            }
+            Command::History => {
+                for (number, entry) in self.history.entries().iter().enumerate() {
+                    output.push_str(&format!("{:>4}  {entry}\n", number + 1));
                 }
-                ".history" => {
-                    for command in &self.command_buffer {
-                        println!("{command}");
-                    }
+            }
+            Command::Program => {
+                for instruction in &machine.vm.program {
+                    output.push_str(&format!("{instruction} "));
+                }
+                output.push('\n');
+            }
+            Command::Disasm { from, count } => {
+                let from = match from {
+                    Some(from) => machine.evaluate_address(&from)?,
+                    None => machine.vm.pc.saturating_sub(DISASM_BEFORE_PC * INSTRUCTION_SIZE),
+                };
+                output = machine.disassembly(from, count.unwrap_or(DISASM_COUNT))?;
+            }
+            Command::Registers { range } => output = self.registers(machine.vm.registers, range),
+            Command::Set { register, value } => {
+                let value = value.evaluate(machine.session.symbols()).map_err(|error| error.to_string())?;
+                // Values up to u32::MAX are taken as bit patterns, e.g. 0xffffffff.
+                let value = i32::try_from(value)
+                    .or_else(|_| u32::try_from(value).map(|value| value as i32))
+                    .map_err(|_| format!("{value} does not fit in a register"))?;
+                machine.vm.registers[register] = value;
+            }
+            Command::Flag { value: None } => output = format!("{}\n", machine.vm.equal_flag),
+            Command::Flag { value: Some(value) } => machine.vm.equal_flag = value,
+            Command::Pc { value: None } => {
+                output = match machine.vm.symbols.is_empty() {
+                    true => format!("{}\n", machine.vm.pc),
+                    false => format!("{} ({})\n", machine.vm.pc, machine.vm.describe_pc()),
+                };
+            }
+            Command::Pc { value: Some(value) } => machine.vm.pc = machine.evaluate_address(&value)?,
+            Command::Heap { size: Some(size) } => {
+                let size = machine.evaluate_address(&size)?;
+                machine.vm.resize_heap(size).map_err(|fault| fault.to_string())?;
+            }
+            Command::Heap { size: None } => {
+                let heap = machine.vm.heap();
+                output = format!("heap: {} bytes\n", heap.len());
+                output.push_str(&hexdump(&heap[..heap.len().min(HEAP_PREVIEW)], 0));
+                if heap.len() > HEAP_PREVIEW {
+                    output.push_str(&format!("... {} more bytes, see .mem\n", heap.len() - HEAP_PREVIEW));
                 }
-                ".program" => {
-                    for instruction in &self.vm.program {
-                        print!("{instruction} ");
+            }
+            Command::Mem { address, len } => {
+                let address = machine.evaluate_address(&address)?;
+                let len = machine.evaluate_address(&len)?;
+                output = hexdump(machine.heap_range(address, len)?, address);
+            }
+            Command::MemWrite { address, bytes } => {
+                let address = machine.evaluate_address(&address)?;
+                let mut values = Vec::with_capacity(bytes.len());
+                for byte in &bytes {
+                    let value = byte.evaluate(machine.session.symbols()).map_err(|error| error.to_string())?;
+                    match value {
+                        -128..=255 => values.push(value as u8),
+                        _ => return Err(format!("{value} does not fit in a byte")),
                     }
-                    println!();
                 }
-                ".registers" => {
-                    for register in self.vm.registers {
-                        print!("{register:} ");
+                machine.heap_range(address, values.len())?;
+                machine.vm.heap_mut()[address..address + values.len()].copy_from_slice(&values);
+            }
+            Command::Symbols => output = machine.vm.symbols.to_string(),
+            Command::LoadSymbols { path } => {
+                let symbols = SymbolMap::load(&path).map_err(|error| format!("unable to load {}: {error}", path.display()))?;
+                machine.vm.symbols = symbols;
+            }
+            Command::Listing => match &machine.listing {
+                Some(listing) => output = listing.to_string(),
+                None => output.push_str("No file has been assembled yet\n"),
+            },
+            Command::Clear => machine.clear_program(),
+            Command::Reset { parts } if parts.is_empty() => {
+                machine.vm = VM::new();
+                machine.clear_program();
+            }
+            Command::Reset { parts } => {
+                for part in parts {
+                    match part {
+                        ResetPart::Registers => machine.vm.registers = [0; 32],
+                        ResetPart::Pc => machine.vm.pc = 0,
+                        ResetPart::Heap => machine.vm.clear_heap(),
+                        ResetPart::Flags => machine.vm.reset_flags(),
+                        ResetPart::Program => machine.clear_program(),
                     }
-                    println!();
                 }
-                ".flag" => {
-                    println!("{:}", self.vm.equal_flag);
+            }
+            Command::LoadFile { path } => {
+                let assembled_program = machine.session.add_file(&path).map_err(|errors| {
+                    let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                    format!("unable to assemble file:\n{}", errors.join("\n"))
+                })?;
+                machine.load(&assembled_program);
+                for warning in &assembled_program.warnings {
+                    output.push_str(&format!("{warning}\n"));
                 }
-                ".pc" => {
-                    println!("{:}", self.vm.pc);
+                if let Some(report) = &assembled_program.optimizations {
+                    output.push_str(&report.to_string());
                 }
-                ".clear" => {
-                    self.vm.program.clear();
+                machine.listing = assembled_program.listing;
+            }
+            Command::Save { path } => {
+                let unresolved = machine.session.unresolved();
+                if !unresolved.is_empty() {
+                    return Err(format!("cannot save while `{}` is not defined", unresolved.join("`, `")));
                 }
-                ".load_file" => {
-                    print!("File path:");
-                    io::stdout().flush().expect("unable to flush stdout");
-                    let mut tmp = String::new();
-                    stdin.read_line(&mut tmp).expect("unable to read user input");
-                    let tmp = tmp.trim();
-                    let filename = Path::new(&tmp);
-                    let mut f = File::open(filename).expect("unable to open file");
-                    let mut contents = String::new();
-                    f.read_to_string(&mut contents).expect("unable to read file");
-                    match self.asm.assemble(&contents) {
-                        Some(mut assembled_program) => {
-                            self.vm.program.append(&mut assembled_program);
-                        }
-                        None => {
-                            println!("unable to assemble file");
-                            return;
-                        }
-                    }
+                let entry = machine.entry as u32;
+                let file = ProgramFile::new(machine.vm.program.clone(), entry, machine.vm.symbols.clone());
+                file.save(&path).map_err(|error| format!("unable to write {}: {error}", path.display()))?;
+            }
+            Command::LoadBin { path } => {
+                let file = ProgramFile::load(&path).map_err(|error| format!("unable to load {}: {error}", path.display()))?;
+                let symbols = SymbolTable::from_symbol_map(&file.symbols);
+                machine.session = AssemblySession::from_image(machine.session.assembler().clone(), file.program.clone(), symbols);
+                machine.vm.program = file.program;
+                machine.vm.symbols = file.symbols;
+                machine.entry = file.entry as usize;
+                machine.vm.pc = machine.entry;
+                machine.listing = None;
+            }
+            Command::Run { .. } | Command::Continue | Command::Step { .. } => {
+                unreachable!("`execute` runs the VM")
+            }
+            Command::Break { address: None } => {
+                for address in machine.vm.breakpoints() {
+                    output.push_str(&format!("{}\n", machine.describe_address(address)));
                 }
-                ".run" => {
-                    if self.vm.program.is_empty() {
-                        continue;
-                    }
-                    self.vm.run();
+                if output.is_empty() {
+                    output.push_str("no breakpoints\n");
                 }
-                ".next" => {
-                    if self.vm.program.is_empty() {
-                        continue;
-                    }
-                    self.vm.run_once();
+            }
+            Command::Break { address: Some(address) } => {
+                let address = machine.evaluate_address(&address)?;
+                if !machine.vm.add_breakpoint(address) {
+                    return Err(format!("there already is a breakpoint at {}", machine.describe_address(address)));
                 }
-                _ => {
-                    let program = match program(buffer.into()) {
-                        Ok((_, program)) => program,
-                        Err(_) => {
-                            println!("Unable to parse input");
-                            continue;
-                        }
-                    };
-                    self.vm.program.append(&mut program.to_bytes(&self.asm.symbols));
-                    self.vm.run_once();
+            }
+            Command::Delete { address: None } => machine.vm.clear_breakpoints(),
+            Command::Delete { address: Some(address) } => {
+                let address = machine.evaluate_address(&address)?;
+                if !machine.vm.remove_breakpoint(address) {
+                    return Err(format!("there is no breakpoint at {}", machine.describe_address(address)));
                 }
             }
         }
+        Ok(output)
+    }
+
+    /// One line per register in `range` with its value in hex and decimal,
+    /// or without a range, per register that is non-zero or changed. Changes
+    /// since the registers were last shown are marked `*`.
+    fn registers(&mut self, registers: [i32; 32], range: Option<RangeInclusive<usize>>) -> String {
+        let changed = |register: usize| registers[register] != self.shown_registers[register];
+        let shown: Vec<usize> = match range {
+            Some(range) => range.collect(),
+            None => (0..registers.len()).filter(|&register| registers[register] != 0 || changed(register)).collect(),
+        };
+        let mut output = String::new();
+        for &register in &shown {
+            let value = registers[register];
+            let marker = if changed(register) { "  *" } else { "" };
+            let name = format!("${register}");
+            output.push_str(&format!("{name:<4} = {:#010x}  {value}{marker}\n", value as u32));
+        }
+        if shown.is_empty() {
+            output.push_str("all registers are 0\n");
+        }
+        for register in shown {
+            self.shown_registers[register] = registers[register];
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_failed_commands_keep_the_program() {
+        let mut repl = REPL::new();
+        repl.lock().assemble_line("start: load $1 #5").unwrap();
+        let program = repl.lock().vm.program.clone();
+        let missing = Command::LoadFile { path: PathBuf::from("no/such/file.iasm") };
+        assert!(repl.execute(missing).unwrap_err().starts_with("unable to assemble file:"));
+        assert!(repl.lock().assemble_line("load $40 #1").is_err());
+        assert!(repl.execute(Command::parse(".run until @nowhere").unwrap().unwrap()).is_err());
+        assert_eq!(repl.lock().vm.program, program);
+        assert_eq!(repl.lock().vm.registers[1], 5);
+        repl.lock().assemble_line("load $2 #7").unwrap();
+        let machine = repl.lock();
+        assert_eq!((machine.vm.registers[2], machine.vm.pc), (7, 8));
+        drop(machine);
+        assert_eq!(repl.process_line("div $1 $3 $4").unwrap_err(), "division by zero at 0008 (start+8)");
+        assert_eq!(repl.process_line(".set $3 2").unwrap(), "");
+        assert_eq!(repl.process_line(".step").unwrap(), "");
+        assert_eq!(repl.lock().vm.registers[4], 2);
+    }
+
+    #[test]
+    fn test_typo_leaves_the_program_alone() {
+        let mut repl = REPL::new();
+        repl.process_line("load $1 #5").unwrap();
+        assert_eq!(repl.process_line("lod $2 #3").unwrap_err(), "unknown instruction `lod`");
+        assert_eq!(repl.lock().vm.program, [1, 1, 0, 5]);
+        assert_eq!(repl.process_line("load $2 #3").unwrap(), "");
+        let machine = repl.lock();
+        assert_eq!((machine.vm.registers[2], machine.vm.pc), (3, 8));
+    }
+
+    #[test]
+    fn test_directives_at_the_prompt() {
+        let mut repl = REPL::new();
+        let message = repl.process_line(".include \"missing.iasm\"").unwrap_err();
+        assert_eq!(message, "cannot find included file `missing.iasm`");
+        let message = repl.process_line(".macro double reg").unwrap_err();
+        assert_eq!(message, "`.macro` spans several lines and is not supported interactively, use .load_file");
+        assert_eq!(repl.process_line(".foo 1").unwrap_err(), "unknown command `.foo`, type .help for a list of commands");
+        assert!(repl.lock().vm.program.is_empty());
+    }
+
+    #[test]
+    fn test_disassembly_marks_the_pc() {
+        let mut repl = REPL::new();
+        repl.process_line("start: load $1 #5").unwrap();
+        repl.process_line("loop: inc $1").unwrap();
+        assert_eq!(
+            repl.lock().disassembly(0, 8).unwrap(),
+            "start:\n   0000  01 01 00 05  load $1 #5\nloop:\n   0004  11 01 00 00  inc $1\n"
+        );
+        repl.lock().vm.pc = 4;
+        assert!(repl.lock().disassembly(4, 1).unwrap().contains("=> 0004  11 01 00 00  inc $1"));
+        assert!(repl.lock().disassembly(8, 1).is_err());
+    }
+
+    #[test]
+    fn test_write_and_read_the_heap() {
+        let mut repl = REPL::new();
+        repl.process_line(".equ SIZE 8").unwrap();
+        repl.process_line("load $1 #SIZE").unwrap();
+        repl.process_line("alloc $1").unwrap();
+        repl.process_line(".mem.write 2 'h', 'i', -1").unwrap();
+        assert_eq!(repl.lock().vm.heap(), [0, 0, b'h', b'i', 0xff, 0, 0, 0]);
+        assert!(repl.process_line(".mem 4 SIZE").is_err());
+        assert!(repl.process_line(".mem.write 7 1, 2").is_err());
+        assert!(repl.process_line(".mem.write 0 256").is_err());
+        assert_eq!(repl.lock().heap_range(2, 3), Ok(&b"hi\xff"[..]));
+    }
+
+    #[test]
+    fn test_labels_persist_across_lines() {
+        let mut repl = REPL::new();
+        repl.process_line("loop: inc $0").unwrap();
+        repl.process_line("eq $0 $0").unwrap();
+        repl.process_line("djeq @done").unwrap();
+        assert_eq!(repl.lock().vm.pc, 8);
+        repl.process_line("djeq @loop").unwrap();
+        assert_eq!(repl.lock().vm.pc, 8);
+        repl.process_line("done: hlt").unwrap();
+        assert_eq!(repl.lock().vm.program[8..12], [19, 0, 16, 0]);
+        assert_eq!(repl.lock().vm.program[12..16], [19, 0, 0, 0]);
+        assert_eq!(repl.lock().vm.pc, 16);
+    }
+
+    #[test]
+    fn test_reset_parts() {
+        let mut repl = REPL::new();
+        repl.process_line("load $1 #4").unwrap();
+        repl.process_line("alloc $1").unwrap();
+        repl.process_line(".reset heap pc").unwrap();
+        let machine = repl.lock();
+        assert_eq!((machine.vm.heap().len(), machine.vm.pc, machine.vm.registers[1]), (0, 0, 4));
+        drop(machine);
+        assert_eq!(repl.lock().vm.program.len(), 8);
+        repl.process_line(".reset").unwrap();
+        let machine = repl.lock();
+        assert_eq!((machine.vm.registers[1], machine.vm.program.len()), (0, 0));
+        drop(machine);
+        assert!(repl.lock().session.image().is_empty());
+    }
+
+    #[test]
+    fn test_save_and_load_binary() {
+        let path = std::env::temp_dir().join(format!("repl-save-test-{}.bin", std::process::id()));
+        let mut repl = REPL::new();
+        repl.process_line("start: load $1 #5").unwrap();
+        repl.process_line("jmp @start").unwrap();
+        repl.process_line(".entry @start + 4").unwrap();
+        repl.process_line(&format!(".save {}", path.display())).unwrap();
+        let mut loaded = REPL::new();
+        loaded.process_line(&format!(".load_bin {}", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.lock().vm.program, repl.lock().vm.program);
+        assert_eq!(loaded.lock().vm.symbols, repl.lock().vm.symbols);
+        assert_eq!(loaded.lock().vm.pc, 4);
+        loaded.process_line("djeq @start").unwrap();
+        assert_eq!(loaded.lock().vm.program[12..], [19, 0, 0, 0]);
+        assert!(loaded.process_line(".load_bin no/such/file.bin").is_err());
+        repl.process_line("djeq @later").unwrap();
+        assert!(repl.process_line(".save unused.bin").is_err());
+    }
+
+    #[test]
+    fn test_load_symbol_map() {
+        let path = std::env::temp_dir().join(format!("repl-symbols-test-{}.map", std::process::id()));
+        let program = Assembler::new().assemble("load $1 #5\nloop: inc $1\njmp @loop").unwrap();
+        program.symbol_map().save(&path).unwrap();
+        let mut repl = REPL::new();
+        repl.process_line(&format!(".symbols load {}", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(repl.process_line(".symbols").unwrap(), "loop  code      label          4\n");
+        repl.process_line(".pc 8").unwrap();
+        assert_eq!(repl.process_line(".pc").unwrap(), "8 (loop+4)\n");
+        assert!(repl.process_line(".symbols load no/such/file.map").is_err());
+    }
+
+    #[test]
+    fn test_edit_state_and_show_registers() {
+        let mut repl = REPL::new();
+        assert_eq!(repl.process_line(".registers").unwrap(), "all registers are 0\n");
+        repl.process_line(".set $5 -1").unwrap();
+        repl.process_line(".set $2 0xffffffff").unwrap();
+        repl.process_line(".flag true").unwrap();
+        repl.process_line(".heap 16").unwrap();
+        assert_eq!(repl.process_line(".registers").unwrap(), "$2   = 0xffffffff  -1  *\n$5   = 0xffffffff  -1  *\n");
+        repl.process_line(".set $2 0").unwrap();
+        repl.process_line("start: load $1 #10").unwrap();
+        assert_eq!(
+            repl.process_line(".registers").unwrap(),
+            "$1   = 0x0000000a  10  *\n$2   = 0x00000000  0  *\n$5   = 0xffffffff  -1\n"
+        );
+        assert_eq!(repl.process_line(".registers 0-1").unwrap(), "$0   = 0x00000000  0\n$1   = 0x0000000a  10\n");
+        repl.process_line(".pc @start").unwrap();
+        let machine = repl.lock();
+        assert_eq!((machine.vm.pc, machine.vm.equal_flag, machine.vm.heap().len()), (0, true, 16));
+        drop(machine);
+        assert!(repl.process_line(".set $1 0x100000000").is_err());
+        assert!(repl.process_line(".heap 9223372036854775807").unwrap_err().starts_with("cannot make the heap"));
+    }
+
+    #[test]
+    fn test_run_script() {
+        let mut repl = REPL::with_machine(Machine::shared(Assembler::new()), vec![]);
+        let script = "; sets up a counter\n\nload $1 #3\n.step 0\n.set $2 $1\n.quit\ninc $1\n";
+        assert_eq!(repl.run_script(script).unwrap(), 2);
+        assert!(repl.quit);
+        let machine = repl.lock();
+        assert_eq!((machine.vm.registers[1], machine.vm.program.len()), (3, 4));
+        drop(machine);
+        let output = String::from_utf8(repl.output).unwrap();
+        assert!(output.starts_with(">>> load $1 #3\n>>> .step 0\n"), "{output}");
+        assert!(output.ends_with(">>> .quit\nAlready leavin?\n"), "{output}");
+        let mut repl = REPL::with_machine(Machine::shared(Assembler::new()), vec![]);
+        assert_eq!(repl.run_script("load $1 #1\n.byte 200, 0, 0, 0\n").unwrap(), 1);
+        let output = String::from_utf8(repl.output).unwrap();
+        assert!(output.ends_with(">>> .byte 200, 0, 0, 0\ninvalid opcode 200 at 0004\n"), "{output}");
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut repl = REPL::new();
+        for line in ["start: load $1 #0", "loop: inc $1", "inc $1", "done: hlt"] {
+            repl.process_line(line).unwrap();
+        }
+        repl.process_line(".pc @start").unwrap();
+        repl.process_line(".break @loop").unwrap();
+        repl.process_line(".break done").unwrap();
+        assert!(repl.process_line(".break @loop").is_err());
+        assert_eq!(repl.process_line(".break").unwrap(), "0004 (loop)\n000c (done)\n");
+        assert_eq!(repl.process_line(".run").unwrap(), "stopped at breakpoint 0004 (loop)\n");
+        assert_eq!(repl.process_line(".run").unwrap(), "stopped at breakpoint 0004 (loop)\n");
+        assert_eq!(repl.process_line(".continue").unwrap(), "stopped at breakpoint 000c (done)\n");
+        assert_eq!(repl.lock().vm.registers[1], 2);
+        assert_eq!(repl.process_line(".continue").unwrap(), "halted\n");
+        repl.process_line(".delete @loop").unwrap();
+        assert!(repl.process_line(".delete @loop").is_err());
+        repl.process_line(".pc @start").unwrap();
+        assert_eq!(repl.process_line(".run until @loop+4").unwrap(), "");
+        assert_eq!(repl.lock().vm.pc, 8);
+        assert_eq!(repl.process_line(".break").unwrap(), "000c (done)\n");
+        repl.process_line(".delete").unwrap();
+        assert_eq!(repl.process_line(".run").unwrap(), "halted\n");
+        assert_eq!(repl.process_line(".break").unwrap(), "no breakpoints\n");
+    }
+
+    #[test]
+    fn test_rerun_history_entry() {
+        let mut repl = REPL::new();
+        repl.process_line(".set $1 2").unwrap();
+        repl.process_line("add $1 $1 $1").unwrap();
+        repl.process_line("!2").unwrap();
+        assert_eq!(repl.lock().vm.registers[1], 8);
+        assert_eq!(repl.history.entries(), [".set $1 2", "add $1 $1 $1", "add $1 $1 $1"]);
+        assert!(repl.process_line("!9").is_err());
+        assert_eq!(repl.history.entries().len(), 3);
     }
 }
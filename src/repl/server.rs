@@ -0,0 +1,161 @@
+use crate::assembler::Assembler;
+use crate::repl::{Machine, SharedMachine, REPL};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+/// Instructions a remote `.run` may execute, since nobody can interrupt it.
+const STEP_LIMIT: usize = 10_000_000;
+
+/// Longest answer read when asking for the secret.
+const MAX_SECRET_LEN: u64 = 1024;
+
+/// Serves REPL sessions over TCP. Each connection gets its own REPL and, unless
+/// the VM is shared, its own VM.
+pub struct Server {
+    listener: TcpListener,
+    assembler: Assembler,
+    /// The machine every connection works on, if they share one.
+    shared: Option<SharedMachine>,
+    /// What a client has to send as its first line before it gets a prompt.
+    secret: Option<Arc<str>>,
+}
+
+impl Server {
+    /// Listens on `addr`. Programs are assembled by `assembler`, except that
+    /// clients may not include files, nor read or write them with commands.
+    pub fn bind(addr: impl ToSocketAddrs, mut assembler: Assembler) -> io::Result<Server> {
+        assembler.allow_include = false;
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            assembler,
+            shared: None,
+            secret: None,
+        })
+    }
+
+    /// Lets all connections work on the same VM.
+    pub fn shared_vm(mut self) -> Server {
+        self.shared = Some(Machine::shared(self.assembler.clone()));
+        self
+    }
+
+    /// Asks every client for `secret` before giving it a prompt.
+    pub fn secret(mut self, secret: &str) -> Server {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the listener fails, each on its own thread.
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let machine = match &self.shared {
+                Some(machine) => Arc::clone(machine),
+                None => Machine::shared(self.assembler.clone()),
+            };
+            let secret = self.secret.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                if let Err(error) = serve(stream, machine, secret.as_deref()) {
+                    eprintln!("connection from {peer} failed: {error}");
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Runs a REPL on `stream` once the client has given the secret, if there is one.
+fn serve(stream: TcpStream, machine: SharedMachine, secret: Option<&str>) -> io::Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut output = stream;
+    if let Some(secret) = secret {
+        output.write_all(b"secret: ")?;
+        output.flush()?;
+        let mut answer = String::new();
+        input.by_ref().take(MAX_SECRET_LEN).read_line(&mut answer)?;
+        if answer.trim_end_matches(['\r', '\n']) != secret {
+            output.write_all(b"access denied\n")?;
+            return Ok(());
+        }
+    }
+    REPL::with_machine(machine, output).with_step_limit(STEP_LIMIT).without_files().run(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Shutdown;
+    use std::time::Duration;
+
+    /// Starts a server on a free local port and returns its address.
+    fn start(server: Server) -> SocketAddr {
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        addr
+    }
+
+    /// Sends `lines`, ends the input and returns everything the server wrote
+    /// until it closed the connection.
+    fn session(addr: SocketAddr, lines: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(lines.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_connections_share_a_vm() {
+        let addr = start(Server::bind("127.0.0.1:0", Assembler::new()).unwrap().shared_vm());
+        session(addr, "load $3 #42\n.quit\n");
+        let output = session(addr, ".registers 3\n.quit\n");
+        assert!(output.contains("$3   = 0x0000002a  42"), "{output}");
+        assert!(output.ends_with("Already leavin?\n"), "{output}");
+
+        let addr = start(Server::bind("127.0.0.1:0", Assembler::new()).unwrap());
+        session(addr, "load $3 #42\n");
+        let output = session(addr, ".registers 3\n");
+        assert!(output.contains("$3   = 0x00000000  0"), "{output}");
+    }
+
+    #[test]
+    fn test_runs_are_limited() {
+        let addr = start(Server::bind("127.0.0.1:0", Assembler::new()).unwrap().shared_vm());
+        session(addr, "loop: load $1 #1\nload $2 @loop\njmp $2\n");
+        let running = thread::spawn(move || session(addr, ".pc @loop\n.run\n"));
+        thread::sleep(Duration::from_millis(50));
+        // Another connection gets the machine while the loop runs.
+        assert!(session(addr, ".registers 1\n").contains("$1   = 0x00000001  1"));
+        assert!(!running.is_finished());
+        let output = running.join().unwrap();
+        assert!(output.contains("stopped after 10000000 instructions"), "{output}");
+    }
+
+    #[test]
+    fn test_files_are_refused() {
+        let addr = start(Server::bind("127.0.0.1:0", Assembler::new()).unwrap());
+        let path = std::env::temp_dir().join("virtual_machine_remote_save.bin");
+        let lines = format!(".load_file Cargo.toml\n.save {}\n.load_bin Cargo.toml\n.symbols load Cargo.toml\n", path.display());
+        let output = session(addr, &lines);
+        assert_eq!(output.matches("files cannot be read or written in this session").count(), 4, "{output}");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_secret() {
+        let addr = start(Server::bind("127.0.0.1:0", Assembler::new()).unwrap().secret("open sesame"));
+        assert_eq!(session(addr, "guess\n"), "secret: access denied\n");
+        let output = session(addr, "open sesame\n.registers\nhlt\n");
+        assert!(output.starts_with("secret: Back at it again.\n>>> all registers are 0\n"), "{output}");
+        assert!(output.ends_with(">>> halted\n>>> "), "{output}");
+    }
+}
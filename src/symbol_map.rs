@@ -0,0 +1,211 @@
+use crate::assembler::SymbolType;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// First line of the machine-readable form, identifying the format.
+const HEADER: &str = "# symbol map v1";
+
+/// Where a symbol's value points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    /// An offset into the program's code.
+    Code,
+    /// An offset into the program's data, which follows the code.
+    Data,
+    /// A plain value, such as a `.equ` constant.
+    Absolute,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Section::Code => "code",
+            Section::Data => "data",
+            Section::Absolute => "absolute",
+        })
+    }
+}
+
+impl FromStr for Section {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "code" => Ok(Section::Code),
+            "data" => Ok(Section::Data),
+            "absolute" => Ok(Section::Absolute),
+            _ => Err(format!("unknown section `{s}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolEntry {
+    pub name: String,
+    pub value: i64,
+    pub section: Section,
+    pub kind: SymbolType,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolMapError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "symbol map line {}: {}", self.line, self.message)
+    }
+}
+
+/// The symbols of an assembled program, kept after assembly so the VM, the
+/// REPL and other tools can name addresses.
+///
+/// `Display` gives an aligned table for people; [`SymbolMap::to_machine_readable`]
+/// and [`SymbolMap::parse`] write and read a tab-separated form with one
+/// `value section kind name` line per symbol.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolMap {
+    entries: Vec<SymbolEntry>,
+}
+
+impl SymbolMap {
+    pub fn new() -> SymbolMap {
+        SymbolMap { entries: vec![] }
+    }
+
+    pub fn push(&mut self, entry: SymbolEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[SymbolEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SymbolEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Names `address` relative to the closest label at or before it, e.g.
+    /// `loop` or `loop+4`. Falls back to hex when no label precedes it.
+    pub fn describe_address(&self, address: usize) -> String {
+        let address = address as i64;
+        let mut closest: Option<&SymbolEntry> = None;
+        for entry in &self.entries {
+            let is_candidate = entry.section != Section::Absolute && entry.kind == SymbolType::Label && entry.value <= address;
+            if is_candidate && closest.is_none_or(|closest| entry.value > closest.value) {
+                closest = Some(entry);
+            }
+        }
+        match closest {
+            Some(entry) if entry.value == address => entry.name.clone(),
+            Some(entry) => format!("{}+{}", entry.name, address - entry.value),
+            None => format!("{address:#06x}"),
+        }
+    }
+
+    pub fn to_machine_readable(&self) -> String {
+        let mut output = format!("{HEADER}\n");
+        for entry in &self.entries {
+            output.push_str(&format!("{}\t{}\t{}\t{}\n", entry.value, entry.section, entry.kind, entry.name));
+        }
+        output
+    }
+
+    /// Reads the machine-readable form. Blank lines and lines starting with
+    /// `#` are ignored.
+    pub fn parse(text: &str) -> Result<SymbolMap, SymbolMapError> {
+        let mut map = SymbolMap::new();
+        for (index, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| SymbolMapError { line: index + 1, message };
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [value, section, kind, name] = fields[..] else {
+                return Err(error(format!("expected 4 tab-separated fields, found {}", fields.len())));
+            };
+            map.push(SymbolEntry {
+                name: name.to_string(),
+                value: value.parse().map_err(|_| error(format!("invalid value `{value}`")))?,
+                section: section.parse().map_err(error)?,
+                kind: kind.parse().map_err(error)?,
+            });
+        }
+        Ok(map)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_machine_readable())
+    }
+
+    pub fn load(path: &Path) -> io::Result<SymbolMap> {
+        let text = fs::read_to_string(path)?;
+        SymbolMap::parse(&text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+    }
+}
+
+impl fmt::Display for SymbolMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.entries.iter().map(|entry| entry.name.len()).max().unwrap_or(0);
+        for entry in &self.entries {
+            writeln!(
+                f,
+                "{:<width$}  {:<8}  {:<8}  {:>6}",
+                entry.name, entry.section, entry.kind, entry.value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> SymbolMap {
+        let mut map = SymbolMap::new();
+        let entries = [
+            ("SIZE", 16, Section::Absolute, SymbolType::Constant),
+            ("start", 0, Section::Code, SymbolType::Label),
+            ("loop", 8, Section::Code, SymbolType::Label),
+        ];
+        for (name, value, section, kind) in entries {
+            map.push(SymbolEntry { name: name.to_string(), value, section, kind });
+        }
+        map
+    }
+
+    #[test]
+    fn test_describe_address() {
+        let map = map();
+        assert_eq!(map.describe_address(0), "start");
+        assert_eq!(map.describe_address(4), "start+4");
+        assert_eq!(map.describe_address(12), "loop+4");
+        assert_eq!(SymbolMap::new().describe_address(12), "0x000c");
+    }
+
+    #[test]
+    fn test_machine_readable_round_trip() {
+        let map = map();
+        let text = map.to_machine_readable();
+        assert_eq!(text, "# symbol map v1\n16\tabsolute\tconstant\tSIZE\n0\tcode\tlabel\tstart\n8\tcode\tlabel\tloop\n");
+        assert_eq!(SymbolMap::parse(&text), Ok(map));
+    }
+
+    #[test]
+    fn test_parse_reports_bad_lines() {
+        let error = SymbolMap::parse("# symbol map v1\n\n8\tcode\tlabel\n").unwrap_err();
+        assert_eq!(error.to_string(), "symbol map line 3: expected 4 tab-separated fields, found 3");
+        let error = SymbolMap::parse("8\tstack\tlabel\tloop").unwrap_err();
+        assert_eq!(error.message, "unknown section `stack`");
+    }
+}
@@ -1,4 +1,54 @@
+use crate::disassembler::INSTRUCTION_SIZE;
 use crate::instruction::Opcode;
+use crate::symbol_map::SymbolMap;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Largest heap `ALLOC` or `resize_heap` can make, in bytes.
+pub const MAX_HEAP: usize = 16 * 1024 * 1024;
+
+/// Why `run`, `resume` or `run_once` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The program executed `HLT`.
+    Halted,
+    /// The pc ran off the end of the program.
+    EndOfProgram,
+    /// The pc reached a breakpoint. The instruction there has not run yet.
+    Breakpoint(usize),
+    /// The instruction at `address` could not be executed. The pc is left
+    /// pointing at it.
+    Fault { address: usize, fault: Fault },
+}
+
+/// Something a program asked for that the VM cannot do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    InvalidOpcode(u8),
+    /// A register operand above $31.
+    InvalidRegister(u8),
+    /// The instruction's operands run past the end of the program.
+    TruncatedInstruction,
+    DivisionByZero,
+    /// A jump to an address before the start of the program.
+    InvalidJump(i64),
+    /// A heap size below zero or above `MAX_HEAP`, or one that could not be
+    /// allocated.
+    InvalidHeapSize(i64),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::InvalidOpcode(opcode) => write!(f, "invalid opcode {opcode}"),
+            Fault::InvalidRegister(register) => write!(f, "invalid register ${register}"),
+            Fault::TruncatedInstruction => write!(f, "instruction runs past the end of the program"),
+            Fault::DivisionByZero => write!(f, "division by zero"),
+            Fault::InvalidJump(target) => write!(f, "jump to {target}, before the start of the program"),
+            Fault::InvalidHeapSize(size) => write!(f, "cannot make the heap {size} bytes, the limit is {MAX_HEAP}"),
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct VM {
@@ -8,6 +58,10 @@ pub struct VM {
     heap: Vec<u8>,
     remainder: u32,
     pub equal_flag: bool,
+    /// Symbols of the loaded program, used to name addresses.
+    pub symbols: SymbolMap,
+    /// Addresses `run` stops before.
+    breakpoints: BTreeSet<usize>,
 }
 
 impl VM {
@@ -19,6 +73,8 @@ impl VM {
             heap: vec![],
             remainder: 0,
             equal_flag: false,
+            symbols: SymbolMap::new(),
+            breakpoints: BTreeSet::new(),
         }
     }
 
@@ -30,149 +86,209 @@ impl VM {
         self.program.append(&mut bytes);
     }
 
-    pub fn run(&mut self) {
-        'running: loop {
-            if !self.execute_instruction() {
-                break 'running;
+    /// Memory reserved with `ALLOC`.
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut [u8] {
+        &mut self.heap
+    }
+
+    /// Grows the heap with zeros, or shrinks it, to `len` bytes.
+    pub fn resize_heap(&mut self, len: usize) -> Result<(), Fault> {
+        let additional = len.saturating_sub(self.heap.len());
+        if len > MAX_HEAP || self.heap.try_reserve(additional).is_err() {
+            return Err(Fault::InvalidHeapSize(i64::try_from(len).unwrap_or(i64::MAX)));
+        }
+        self.heap.resize(len, 0);
+        Ok(())
+    }
+
+    pub fn clear_heap(&mut self) {
+        self.heap.clear();
+    }
+
+    /// Clears the equal flag and the remainder of the last `DIV`.
+    pub fn reset_flags(&mut self) {
+        self.equal_flag = false;
+        self.remainder = 0;
+    }
+
+    /// The program counter relative to the closest label, e.g. `loop+4`.
+    pub fn describe_pc(&self) -> String {
+        self.symbols.describe_address(self.pc)
+    }
+
+    /// Sets a breakpoint at `address`. Returns false if there already is one.
+    pub fn add_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Removes the breakpoint at `address`. Returns false if there is none.
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn has_breakpoint(&self, address: usize) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    /// Addresses with a breakpoint, in order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Executes instructions until the program halts, runs off its end or the
+    /// pc reaches a breakpoint, including one at the pc it starts from.
+    pub fn run(&mut self) -> StopReason {
+        loop {
+            if self.breakpoints.contains(&self.pc) {
+                return StopReason::Breakpoint(self.pc);
+            }
+            if let Some(reason) = self.run_once() {
+                return reason;
             }
         }
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// Like `run`, but first executes the instruction at the pc even if it
+    /// has a breakpoint, so a stopped program can carry on.
+    pub fn resume(&mut self) -> StopReason {
+        match self.run_once() {
+            Some(reason) => reason,
+            None => self.run(),
+        }
     }
 
-    fn execute_instruction(&mut self) -> bool {
+    /// Executes one instruction. Returns why the VM cannot go on, if it can't.
+    pub fn run_once(&mut self) -> Option<StopReason> {
         if self.pc >= self.program.len() {
-            return false;
+            return Some(StopReason::EndOfProgram);
+        }
+        let address = self.pc;
+        match self.execute_instruction() {
+            Ok(true) => None,
+            Ok(false) => Some(StopReason::Halted),
+            Err(fault) => {
+                self.pc = address;
+                Some(StopReason::Fault { address, fault })
+            }
+        }
+    }
+
+    /// Executes the instruction at the pc. Returns false if it was `HLT`.
+    fn execute_instruction(&mut self) -> Result<bool, Fault> {
+        if self.program.len() - self.pc < INSTRUCTION_SIZE {
+            return Err(Fault::TruncatedInstruction);
         }
         let opcode = self.decode_opcode();
         match opcode {
-            Opcode::HLT => {
-                println!("Stopping VM...");
-                return false;
-            }
+            Opcode::HLT => return Ok(false),
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
+                let register = self.next_register()?;
                 let number = self.next_16_bits();
                 self.registers[register] = number as i32;
             }
             Opcode::ADD => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = lhs + rhs;
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = lhs.wrapping_add(rhs);
             }
             Opcode::SUB => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = lhs - rhs;
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = lhs.wrapping_sub(rhs);
             }
             Opcode::MUL => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = lhs * rhs;
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = lhs.wrapping_mul(rhs);
             }
             Opcode::DIV => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = lhs / rhs;
-                self.remainder = (lhs % rhs) as u32;
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                if rhs == 0 {
+                    return Err(Fault::DivisionByZero);
+                }
+                self.registers[self.next_register()?] = lhs.wrapping_div(rhs);
+                self.remainder = lhs.wrapping_rem(rhs) as u32;
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                let target = self.registers[self.next_register()?];
+                self.jump(target as i64)?;
             }
             Opcode::JMPF => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc += target as usize;
+                let target = self.registers[self.next_register()?];
+                self.jump(self.pc as i64 + target as i64)?;
             }
             Opcode::JMPB => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc -= target as usize;
+                let target = self.registers[self.next_register()?];
+                self.jump(self.pc as i64 - target as i64)?;
             }
             Opcode::EQ => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                if lhs == rhs {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                self.equal_flag = lhs == rhs;
                 self.next_8_bits();
             }
             Opcode::NEQ => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                if lhs != rhs {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                self.equal_flag = lhs != rhs;
                 self.next_8_bits();
             }
             Opcode::GT => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                if lhs > rhs {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                self.equal_flag = lhs > rhs;
                 self.next_8_bits();
             }
             Opcode::LT => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                if lhs < rhs {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                self.equal_flag = lhs < rhs;
                 self.next_8_bits();
             }
             Opcode::GE => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                if lhs >= rhs {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                self.equal_flag = lhs >= rhs;
                 self.next_8_bits();
             }
             Opcode::LE => {
-                let lhs = self.registers[self.next_8_bits() as usize];
-                let rhs = self.registers[self.next_8_bits() as usize];
-                if lhs <= rhs {
-                    self.equal_flag = true;
-                } else {
-                    self.equal_flag = false;
-                }
+                let lhs = self.registers[self.next_register()?];
+                let rhs = self.registers[self.next_register()?];
+                self.equal_flag = lhs <= rhs;
                 self.next_8_bits();
             }
             Opcode::JEQ => {
                 if self.equal_flag {
-                    let target = self.registers[self.next_8_bits() as usize];
-                    self.pc = target as usize;
+                    let target = self.registers[self.next_register()?];
+                    self.jump(target as i64)?;
                 } else {
                     self.next_16_bits();
                     self.next_8_bits();
                 }
             }
             Opcode::ALLOC => {
-                let register = self.next_8_bits() as usize;
+                let register = self.next_register()?;
                 let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
+                let new_end = self.heap.len() as i64 + bytes as i64;
+                self.resize_heap(usize::try_from(new_end).map_err(|_| Fault::InvalidHeapSize(new_end))?)?;
             }
             Opcode::INC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
+                let register = self.next_register()?;
+                self.registers[register] = self.registers[register].wrapping_add(1);
                 self.next_16_bits();
             }
             Opcode::DEC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
+                let register = self.next_register()?;
+                self.registers[register] = self.registers[register].wrapping_add(1);
                 self.next_16_bits();
             }
             Opcode::DJEQ => {
@@ -184,12 +300,21 @@ impl VM {
                     self.next_8_bits();
                 }
             }
-            opcode => {
-                println!("What are you trying to do? (Invalid Opcode: {opcode:?})");
-                return false;
-            }
+            Opcode::ILLEGAL => return Err(Fault::InvalidOpcode(self.program[self.pc - 1])),
+        }
+        Ok(true)
+    }
+
+    fn jump(&mut self, target: i64) -> Result<(), Fault> {
+        self.pc = usize::try_from(target).map_err(|_| Fault::InvalidJump(target))?;
+        Ok(())
+    }
+
+    fn next_register(&mut self) -> Result<usize, Fault> {
+        match self.next_8_bits() {
+            register if (register as usize) < self.registers.len() => Ok(register as usize),
+            register => Err(Fault::InvalidRegister(register)),
         }
-        true
     }
 
     fn decode_opcode(&mut self) -> Opcode {
@@ -226,7 +351,7 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![0, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        assert_eq!(test_vm.run(), StopReason::Halted);
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -238,6 +363,14 @@ mod tests {
         assert_eq!(test_vm.registers[0], 500);
     }
 
+    #[test]
+    fn test_opcode_load_zero_extends() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 255, 254];
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 65534);
+    }
+
     #[test]
     fn test_opcode_add() {
         let mut test_vm = VM::new();
@@ -353,14 +486,72 @@ mod tests {
         test_vm.program = vec![16, 0, 0, 0];
         test_vm.run_once();
         assert_eq!(test_vm.heap.len(), 1024);
+        test_vm.registers[0] = -2048;
+        test_vm.pc = 0;
+        let fault = StopReason::Fault { address: 0, fault: Fault::InvalidHeapSize(-1024) };
+        assert_eq!(test_vm.run_once(), Some(fault));
+        assert_eq!(test_vm.resize_heap(MAX_HEAP + 1), Err(Fault::InvalidHeapSize(MAX_HEAP as i64 + 1)));
+        assert_eq!(test_vm.heap.len(), 1024);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut test_vm = VM::new();
+        // LOAD 0 1, INC 0 three times, HLT
+        test_vm.program = vec![1, 0, 0, 1, 17, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0, 0, 0, 0, 0];
+        assert!(test_vm.add_breakpoint(8));
+        assert!(!test_vm.add_breakpoint(8));
+        test_vm.add_breakpoint(12);
+        assert_eq!(test_vm.run(), StopReason::Breakpoint(8));
+        assert_eq!((test_vm.pc, test_vm.registers[0]), (8, 2));
+        assert_eq!(test_vm.run(), StopReason::Breakpoint(8));
+        assert_eq!(test_vm.resume(), StopReason::Breakpoint(12));
+        assert!(test_vm.remove_breakpoint(8));
+        assert_eq!(test_vm.breakpoints().collect::<Vec<_>>(), [12]);
+        assert_eq!(test_vm.resume(), StopReason::Halted);
+        assert_eq!(test_vm.registers[0], 4);
+        test_vm.program.truncate(16);
+        test_vm.pc = 12;
+        assert_eq!(test_vm.resume(), StopReason::EndOfProgram);
+    }
+
+    #[test]
+    fn test_faults() {
+        let fault = |program: Vec<u8>, registers: &[(usize, i32)]| {
+            let mut test_vm = VM::new();
+            test_vm.program = program;
+            for &(register, value) in registers {
+                test_vm.registers[register] = value;
+            }
+            match test_vm.run() {
+                StopReason::Fault { address: 0, fault } => fault,
+                reason => panic!("expected a fault, got {reason:?}"),
+            }
+        };
+        assert_eq!(fault(vec![5, 1, 2, 3], &[(1, 1)]), Fault::DivisionByZero);
+        assert_eq!(fault(vec![8, 0, 0, 0], &[(0, 8)]), Fault::InvalidJump(-6));
+        assert_eq!(fault(vec![6, 0, 0, 0], &[(0, -1)]), Fault::InvalidJump(-1));
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = -4;
+        test_vm.program = vec![9, 0, 0, 0, 15, 1, 0, 0];
+        assert_eq!(test_vm.run(), StopReason::Fault { address: 4, fault: Fault::InvalidJump(-4) });
+        assert_eq!(fault(vec![2, 0, 40, 1], &[]), Fault::InvalidRegister(40));
+        assert_eq!(fault(vec![1, 0], &[]), Fault::TruncatedInstruction);
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.program = vec![2, 0, 0, 1, 4, 0, 0, 2];
+        test_vm.run();
+        assert_eq!((test_vm.registers[1], test_vm.registers[2]), (-2, 1));
     }
 
     #[test]
     fn test_opcode_igl() {
         let mut test_vm = VM::new();
-        let test_bytes = vec![200, 0, 0, 0];
+        let test_bytes = vec![0, 0, 0, 0, 200, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
-        assert_eq!(test_vm.pc, 1);
+        test_vm.pc = 4;
+        let fault = StopReason::Fault { address: 4, fault: Fault::InvalidOpcode(200) };
+        assert_eq!(test_vm.run(), fault);
+        assert_eq!(test_vm.pc, 4);
     }
 }